@@ -1,7 +1,8 @@
-use std::ptr;
+use std::collections::BTreeMap;
+use std::{cmp, ptr};
 
 use syscall::io::Dma;
-use syscall::error::Result;
+use syscall::error::{Error, Result, EIO, ENODEV};
 
 use super::hba::{HbaPort, HbaCmdTable, HbaCmdHeader};
 use super::Disk;
@@ -18,19 +19,49 @@ struct Request {
     running_opt: Option<(u32, usize)>,
 }
 
+/// A command queued with NCQ, in the slot of its tag.
+struct QueuedCommand {
+    /// The buffer that the data is copied through.
+    buf: Dma<[u8; 256 * 512]>,
+    /// The result of the command, once it has completed or was aborted.
+    result: Option<Result<()>>,
+}
+
+/// A request that is split up into queued commands, which run at the same time.
+struct QueuedRequest {
+    /// The number of sectors, from the start of the request, that commands were queued for.
+    submitted: usize,
+    /// The commands that haven't been finished, by their slot, with the first sector and the
+    /// number of sectors that each covers.
+    running: Vec<(u32, usize, usize)>,
+    /// The first error of a command, which is returned once no command is running anymore.
+    error: Option<Error>,
+}
+
 pub struct DiskATA {
-    id: usize,
     port: &'static mut HbaPort,
     size: u64,
     request_opt: Option<Request>,
     clb: Dma<[HbaCmdHeader; 32]>,
     ctbas: [Dma<HbaCmdTable>; 32],
     _fb: Dma<[u8; 256]>,
-    buf: Dma<[u8; 256 * 512]>
+    buf: Dma<[u8; 256 * 512]>,
+    /// The number of commands that are queued at once with NCQ, if both the HBA and the device
+    /// support it. Otherwise, requests are served one at a time, with a single DMA command.
+    queue_depth: Option<u32>,
+    /// The requests in progress with NCQ, by their direction, their first block, and the address
+    /// and length of their buffer, which stay the same every time a request is retried.
+    queued_requests: BTreeMap<(bool, u64, usize, usize), QueuedRequest>,
+    /// The queued commands that haven't been finished yet, by their slot.
+    queued_commands: BTreeMap<u32, QueuedCommand>,
+    /// The buffers of finished queued commands, kept for reuse.
+    free_bufs: Vec<Dma<[u8; 256 * 512]>>,
 }
 
 impl DiskATA {
-    pub fn new(id: usize, port: &'static mut HbaPort) -> Result<Self> {
+    /// Set up the disk on `port`. `ncq_slots` is the number of command slots if the HBA supports
+    /// NCQ.
+    pub fn new(port: &'static mut HbaPort, ncq_slots: Option<u32>) -> Result<Self> {
         let mut clb = Dma::zeroed()?;
         let mut ctbas = [
             Dma::zeroed()?, Dma::zeroed()?, Dma::zeroed()?, Dma::zeroed()?,
@@ -47,21 +78,141 @@ impl DiskATA {
 
         port.init(&mut clb, &mut ctbas, &mut fb);
 
-        let size = unsafe { port.identify(&mut clb, &mut ctbas).unwrap_or(0) };
+        let identity = unsafe { port.identify(&mut clb, &mut ctbas) };
+        let size = identity.as_ref().map_or(0, |identity| identity.size);
+
+        let queue_depth = match (ncq_slots, identity.and_then(|identity| identity.queue_depth)) {
+            (Some(slots), Some(depth)) => Some(cmp::min(slots, depth)),
+            _ => None,
+        };
+        if let Some(queue_depth) = queue_depth {
+            print!("{}", format!("   + NCQ queue depth: {}\n", queue_depth));
+            // Queued commands complete while the port keeps running.
+            port.start();
+        }
 
         Ok(DiskATA {
-            id: id,
             port: port,
             size: size,
             request_opt: None,
             clb: clb,
             ctbas: ctbas,
             _fb: fb,
-            buf: buf
+            buf: buf,
+            queue_depth,
+            queued_requests: BTreeMap::new(),
+            queued_commands: BTreeMap::new(),
+            free_bufs: Vec::new(),
         })
     }
 
+    /// Record the result of the queued commands that completed. If a command failed, every
+    /// queued command was aborted, and fails.
+    fn poll_queued(&mut self) {
+        let active = self.port.ncq_active();
+        for (&slot, command) in self.queued_commands.iter_mut() {
+            if command.result.is_none() && active & 1 << slot == 0 {
+                command.result = Some(Ok(()));
+            }
+        }
+
+        if self.queued_commands.values().any(|command| command.result.is_none()) && self.port.ncq_error() {
+            self.port.ncq_recover(&mut self.clb, &mut self.ctbas);
+            for command in self.queued_commands.values_mut().filter(|command| command.result.is_none()) {
+                command.result = Some(Err(Error::new(EIO)));
+            }
+        }
+    }
+
+    /// Serve a request with NCQ, keeping up to `queue_depth` commands queued, which may complete
+    /// in any order.
+    fn request_queued(&mut self, block: u64, mut buffer_kind: BufferKind, queue_depth: u32) -> Result<Option<usize>> {
+        let (write, address, len) = match buffer_kind {
+            BufferKind::Read(ref buffer) => (false, buffer.as_ptr() as usize, buffer.len()),
+            BufferKind::Write(ref buffer) => (true, buffer.as_ptr() as usize, buffer.len()),
+        };
+        let key = (write, block, address, len);
+        let total_sectors = len / 512;
+
+        self.poll_queued();
+
+        let mut request = self.queued_requests.remove(&key).unwrap_or(QueuedRequest {
+            submitted: 0,
+            running: Vec::new(),
+            error: None,
+        });
+
+        // Finish the commands that completed
+        let mut i = 0;
+        while i < request.running.len() {
+            let (slot, sector, sectors) = request.running[i];
+            if self.queued_commands.get(&slot).map_or(true, |command| command.result.is_none()) {
+                i += 1;
+                continue;
+            }
+            let command = self.queued_commands.remove(&slot).unwrap();
+            request.running.swap_remove(i);
+
+            match command.result.unwrap() {
+                Ok(()) => if let BufferKind::Read(ref mut buffer) = buffer_kind {
+                    buffer[sector * 512..(sector + sectors) * 512].copy_from_slice(&command.buf[..sectors * 512]);
+                },
+                Err(err) => {
+                    request.error.get_or_insert(err);
+                }
+            }
+            self.free_bufs.push(command.buf);
+        }
+
+        // Queue as many commands as there are free slots for, unless one has failed. A slot stays
+        // taken until its command is finished, even after the device completed it.
+        while request.error.is_none() && request.submitted < total_sectors {
+            let slot = match (0..queue_depth).find(|slot| !self.queued_commands.contains_key(slot)) {
+                Some(slot) => slot,
+                None => break,
+            };
+
+            let sector = request.submitted;
+            let sectors = cmp::min(total_sectors - sector, 255);
+
+            let mut buf = match self.free_bufs.pop() {
+                Some(buf) => buf,
+                None => Dma::zeroed()?,
+            };
+            if let BufferKind::Write(ref buffer) = buffer_kind {
+                buf[..sectors * 512].copy_from_slice(&buffer[sector * 512..(sector + sectors) * 512]);
+            }
+
+            self.port.ncq_dma(slot, block + sector as u64, sectors, write, &mut self.clb, &mut self.ctbas, &buf);
+            self.queued_commands.insert(slot, QueuedCommand {
+                buf,
+                result: None,
+            });
+            request.running.push((slot, sector, sectors));
+            request.submitted += sectors;
+        }
+
+        if request.running.is_empty() && (request.error.is_some() || request.submitted == total_sectors) {
+            match request.error {
+                Some(err) => Err(err),
+                None => Ok(Some(total_sectors * 512)),
+            }
+        } else {
+            self.queued_requests.insert(key, request);
+            Ok(None)
+        }
+    }
+
     fn request(&mut self, block: u64, mut buffer_kind: BufferKind) -> Result<Option<usize>> {
+        // The disk was detached, and commands would never complete.
+        if !self.port.present() {
+            return Err(Error::new(ENODEV));
+        }
+
+        if let Some(queue_depth) = self.queue_depth {
+            return self.request_queued(block, buffer_kind, queue_depth);
+        }
+
         let (write, address, total_sectors) = match buffer_kind {
             BufferKind::Read(ref buffer) => (false, buffer.as_ptr() as usize, buffer.len()/512),
             BufferKind::Write(ref buffer) => (true, buffer.as_ptr() as usize, buffer.len()/512),
@@ -145,11 +296,14 @@ impl DiskATA {
     }
 }
 
-impl Disk for DiskATA {
-    fn id(&self) -> usize {
-        self.id
+impl Drop for DiskATA {
+    fn drop(&mut self) {
+        // The HBA mustn't use the command list and the received FIS area once they are freed.
+        self.port.stop();
     }
+}
 
+impl Disk for DiskATA {
     fn size(&mut self) -> u64 {
         self.size
     }
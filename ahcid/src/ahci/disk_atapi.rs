@@ -14,7 +14,6 @@ const SCSI_READ_CAPACITY: u8 = 0x25;
 const SCSI_READ10: u8 = 0x28;
 
 pub struct DiskATAPI {
-    id: usize,
     port: &'static mut HbaPort,
     size: u64,
     clb: Dma<[HbaCmdHeader; 32]>,
@@ -26,7 +25,7 @@ pub struct DiskATAPI {
 }
 
 impl DiskATAPI {
-    pub fn new(id: usize, port: &'static mut HbaPort) -> Result<Self> {
+    pub fn new(port: &'static mut HbaPort) -> Result<Self> {
         let mut clb = Dma::zeroed()?;
         let mut ctbas = [
             Dma::zeroed()?, Dma::zeroed()?, Dma::zeroed()?, Dma::zeroed()?,
@@ -46,7 +45,6 @@ impl DiskATAPI {
         let size = unsafe { port.identify_packet(&mut clb, &mut ctbas).unwrap_or(0) };
 
         Ok(DiskATAPI {
-            id: id,
             port: port,
             size: size,
             clb: clb,
@@ -72,10 +70,6 @@ impl DiskATAPI {
 }
 
 impl Disk for DiskATAPI {
-    fn id(&self) -> usize {
-        self.id
-    }
-
     fn size(&mut self) -> u64 {
         match self.read_capacity() {
             Ok((blk_count, blk_size)) => (blk_count as u64) * (blk_size as u64),
@@ -106,7 +100,7 @@ impl Disk for DiskATAPI {
 
             unsafe { ptr::copy(self.buf.as_ptr(), buffer.as_mut_ptr().offset(sector as isize * blk_len as isize), buf_size as usize); }
 
-            sector += blk_len;
+            sector += buf_len;
         }
         if sector < sectors {
             let cmd = read10_cmd(block as u32 + sector, (sectors - sector) as u16);
@@ -127,4 +121,9 @@ impl Disk for DiskATAPI {
     fn block_length(&mut self) -> Result<u32> {
         Ok(self.read_capacity()?.1)
     }
+
+    fn max_transfer(&mut self) -> usize {
+        // A read has to fit in the bounce buffer.
+        self.buf.len()
+    }
 }
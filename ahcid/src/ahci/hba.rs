@@ -1,30 +1,45 @@
 use std::mem::size_of;
 use std::ops::DerefMut;
+use std::time::Duration;
 use std::{ptr, u32};
 
 use syscall::io::{Dma, Io, Mmio};
-use syscall::error::{Error, Result, EIO};
+use syscall::error::{Error, Result, EIO, ENODEV};
 
 use super::fis::{FisType, FisRegH2D};
 
 const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
 const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_CMD_READ_LOG_EXT: u8 = 0x2F;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
 const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
 const ATA_CMD_PACKET: u8 = 0xA0;
 const ATA_DEV_BUSY: u8 = 0x80;
 const ATA_DEV_DRQ: u8 = 0x08;
+const ATA_DEV_ERR: u8 = 0x01;
+const ATA_LOG_NCQ_ERROR: u8 = 0x10;
 
 const HBA_PORT_CMD_CR: u32 = 1 << 15;
 const HBA_PORT_CMD_FR: u32 = 1 << 14;
 const HBA_PORT_CMD_FRE: u32 = 1 << 4;
 const HBA_PORT_CMD_ST: u32 = 1;
 const HBA_PORT_IS_ERR: u32 = 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+const HBA_PORT_IS_TFES: u32 = 1 << 30;
+const HBA_PORT_IS_SDBS: u32 = 1 << 3;
+const HBA_PORT_IS_PCS: u32 = 1 << 6;
+const HBA_PORT_IS_PRCS: u32 = 1 << 22;
+const HBA_CAP_SNCQ: u32 = 1 << 30;
 const HBA_SSTS_PRESENT: u32 = 0x3;
 const HBA_SIG_ATA: u32 = 0x00000101;
 const HBA_SIG_ATAPI: u32 = 0xEB140101;
 const HBA_SIG_PM: u32 = 0x96690101;
 const HBA_SIG_SEMB: u32 = 0xC33C0101;
+/// The signature of a port until the device has sent one.
+const HBA_SIG_NONE: u32 = 0xFFFFFFFF;
+/// How long a device that was just attached may take to send its signature.
+pub const HBA_SIG_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub enum HbaPortType {
@@ -36,6 +51,14 @@ pub enum HbaPortType {
     SEMB,
 }
 
+/// What IDENTIFY DEVICE reports about a device.
+pub struct Identity {
+    /// The size of the device in bytes.
+    pub size: u64,
+    /// The number of commands that the device queues with NCQ, if it supports it.
+    pub queue_depth: Option<u32>,
+}
+
 #[repr(packed)]
 pub struct HbaPort {
     pub clb: [Mmio<u32>; 2], // 0x00, command list base address, 1K-byte aligned
@@ -58,8 +81,20 @@ pub struct HbaPort {
 }
 
 impl HbaPort {
+    /// Whether a device is attached, and the link to it is up.
+    pub fn present(&self) -> bool {
+        self.ssts.readf(HBA_SSTS_PRESENT)
+    }
+
+    /// Whether a device that was just attached has sent its signature, and is no longer busy, so
+    /// that it can be probed. The task file is busy until the signature is received, which tells
+    /// a new signature apart from that of a device that was attached before.
+    pub fn has_signature(&self) -> bool {
+        self.sig.read() != HBA_SIG_NONE && !self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32)
+    }
+
     pub fn probe(&self) -> HbaPortType {
-        if self.ssts.readf(HBA_SSTS_PRESENT) {
+        if self.present() {
             let sig = self.sig.read();
             match sig {
                 HBA_SIG_ATA => HbaPortType::SATA,
@@ -116,7 +151,8 @@ impl HbaPort {
         self.fb[1].write((fb.physical() >> 32) as u32);
         let is = self.is.read();
         self.is.write(is);
-        self.ie.write(0b10111);
+        // Set Device Bits completes queued commands, and a task file error aborts them.
+        self.ie.write(0b10111 | HBA_PORT_IS_SDBS | HBA_PORT_IS_TFES | HBA_PORT_IS_PCS | HBA_PORT_IS_PRCS);
         let serr = self.serr.read();
         self.serr.write(serr);
 
@@ -130,16 +166,25 @@ impl HbaPort {
         print!("{}", format!("   - AHCI init {:X}\n", self.cmd.read()));
     }
 
-    pub unsafe fn identify(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<u64> {
+    /// Interrupt when a device is attached to or detached from the port, which may have no device
+    /// yet. Connection changes that are already pending are cleared.
+    pub fn enable_hotplug(&mut self) {
+        let serr = self.serr.read();
+        self.serr.write(serr);
+        self.is.write(HBA_PORT_IS_PCS | HBA_PORT_IS_PRCS);
+        self.ie.writef(HBA_PORT_IS_PCS | HBA_PORT_IS_PRCS, true);
+    }
+
+    pub unsafe fn identify(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<Identity> {
         self.identify_inner(ATA_CMD_IDENTIFY, clb, ctbas)
     }
 
     pub unsafe fn identify_packet(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<u64> {
-        self.identify_inner(ATA_CMD_IDENTIFY_PACKET, clb, ctbas)
+        self.identify_inner(ATA_CMD_IDENTIFY_PACKET, clb, ctbas).map(|identity| identity.size)
     }
 
     // Shared between identify() and identify_packet()
-    unsafe fn identify_inner(&mut self, cmd: u8, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<u64> {
+    unsafe fn identify_inner(&mut self, cmd: u8, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Option<Identity> {
         let dest: Dma<[u16; 256]> = Dma::new([0; 256]).unwrap();
 
         let slot = self.ata_start(clb, ctbas, |cmdheader, cmdfis, prdt_entries, _acmd| {
@@ -208,10 +253,21 @@ impl HbaPort {
                 48
             };
 
+            // Word 76 bit 8 tells whether NCQ is supported, and word 75 has the queue depth,
+            // zero-based.
+            let queue_depth = if dest[76] != 0xFFFF && dest[76] & 1 << 8 != 0 {
+                Some(u32::from(dest[75] & 0x1F) + 1)
+            } else {
+                None
+            };
+
             print!("{}", format!("   + Serial: {} Firmware: {} Model: {} {}-bit LBA Size: {} MB\n",
                         serial.trim(), firmware.trim(), model.trim(), lba_bits, sectors / 2048));
 
-            Some(sectors * 512)
+            Some(Identity {
+                size: sectors * 512,
+                queue_depth,
+            })
         } else {
             None
         }
@@ -256,6 +312,108 @@ impl HbaPort {
         })
     }
 
+    /// Queue a READ or WRITE FPDMA QUEUED command in `slot`, which is also its NCQ tag. Unlike
+    /// the other commands, the port has to be started already, and stays started, so that more
+    /// commands can be queued while this one runs.
+    pub fn ncq_dma(&mut self, slot: u32, block: u64, sectors: usize, write: bool, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32], buf: &Dma<[u8; 256 * 512]>) {
+        assert!(sectors > 0 && sectors < 256);
+
+        Self::build_command(slot, clb, ctbas, |cmdheader, cmdfis, prdt_entries, _acmd| {
+            // Set W for writes, but not P, as queued commands must not be prefetchable.
+            if write {
+                let cfl = cmdheader.cfl.read();
+                cmdheader.cfl.write(cfl | 1 << 6)
+            }
+
+            cmdheader.prdtl.write(1);
+
+            let prdt_entry = &mut prdt_entries[0];
+            prdt_entry.dba.write(buf.physical() as u64);
+            prdt_entry.dbc.write(((sectors * 512) as u32) | 1);
+
+            cmdfis.pm.write(1 << 7);
+            if write {
+                cmdfis.command.write(ATA_CMD_WRITE_FPDMA_QUEUED);
+            } else {
+                cmdfis.command.write(ATA_CMD_READ_FPDMA_QUEUED);
+            }
+
+            // The sector count is in the feature register, and the tag in the count register.
+            cmdfis.featurel.write(sectors as u8);
+            cmdfis.featureh.write((sectors >> 8) as u8);
+
+            cmdfis.lba0.write(block as u8);
+            cmdfis.lba1.write((block >> 8) as u8);
+            cmdfis.lba2.write((block >> 16) as u8);
+
+            cmdfis.device.write(1 << 6);
+
+            cmdfis.lba3.write((block >> 24) as u8);
+            cmdfis.lba4.write((block >> 32) as u8);
+            cmdfis.lba5.write((block >> 40) as u8);
+
+            cmdfis.countl.write((slot << 3) as u8);
+            cmdfis.counth.write(0);
+        });
+
+        // Both registers only set the bits that are written as one.
+        self.sact.write(1 << slot);
+        self.ci.write(1 << slot);
+    }
+
+    /// The slots of the queued commands that the device hasn't completed yet.
+    pub fn ncq_active(&self) -> u32 {
+        self.sact.read() | self.ci.read()
+    }
+
+    /// Whether a queued command failed, which aborts every queued command.
+    pub fn ncq_error(&self) -> bool {
+        self.is.read() & HBA_PORT_IS_ERR != 0 || self.tfd.readf(ATA_DEV_ERR as u32)
+    }
+
+    /// Clear the error of a queued command by restarting the port, and reading the NCQ Command
+    /// Error log, which the device refuses every command until, so that commands can be queued
+    /// again. The commands that were queued are dropped.
+    pub fn ncq_recover(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) {
+        print!("{}", format!("NCQ ERROR IS {:X} TFD {:X} SERR {:X} SACT {:X} CI {:X}\n",
+                self.is.read(), self.tfd.read(), self.serr.read(), self.sact.read(), self.ci.read()));
+
+        // Stopping the port clears SACT and CI.
+        self.stop();
+
+        let serr = self.serr.read();
+        self.serr.write(serr);
+        self.is.write(u32::MAX);
+
+        let log: Dma<[u8; 512]> = Dma::new([0; 512]).unwrap();
+        let slot = self.ata_start(clb, ctbas, |cmdheader, cmdfis, prdt_entries, _acmd| {
+            cmdheader.prdtl.write(1);
+
+            let prdt_entry = &mut prdt_entries[0];
+            prdt_entry.dba.write(log.physical() as u64);
+            prdt_entry.dbc.write(512 | 1);
+
+            cmdfis.pm.write(1 << 7);
+            cmdfis.command.write(ATA_CMD_READ_LOG_EXT);
+            cmdfis.device.write(0);
+            cmdfis.lba0.write(ATA_LOG_NCQ_ERROR);
+            cmdfis.countl.write(1);
+            cmdfis.counth.write(0);
+        });
+
+        match slot.map(|slot| self.ata_stop(slot)) {
+            // Byte 0 has the tag of the failed command, unless NQ is set, and bytes 2 and 3 the
+            // status and error registers.
+            Some(Ok(())) if log[0] & 1 << 7 == 0 => {
+                print!("{}", format!("NCQ ERROR TAG {} STATUS {:X} ERROR {:X}\n", log[0] & 0x1F, log[2], log[3]));
+            }
+            Some(Ok(())) => (),
+            _ => println!("NCQ ERROR LOG FAILED"),
+        }
+
+        self.start();
+    }
+
     /// Send ATAPI packet
     pub fn atapi_dma(&mut self, cmd: &[u8; 16], size: u32, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32], buf: &mut Dma<[u8; 256 * 512]>) -> Result<()> {
         let slot = self.ata_start(clb, ctbas, |cmdheader, cmdfis, prdt_entries, acmd| {
@@ -288,21 +446,7 @@ impl HbaPort {
         self.is.write(u32::MAX);
 
         if let Some(slot) = self.slot() {
-            {
-                let cmdheader = &mut clb[slot as usize];
-                cmdheader.cfl.write((size_of::<FisRegH2D>() / size_of::<u32>()) as u8);
-
-                let cmdtbl = &mut ctbas[slot as usize];
-                unsafe { ptr::write_bytes(cmdtbl.deref_mut() as *mut HbaCmdTable as *mut u8, 0, size_of::<HbaCmdTable>()); }
-
-                let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_mut_ptr() as *mut FisRegH2D) };
-                cmdfis.fis_type.write(FisType::RegH2D as u8);
-
-                let prdt_entry = unsafe { &mut *(&mut cmdtbl.prdt_entry as *mut _) };
-                let acmd = unsafe { &mut *(&mut cmdtbl.acmd as *mut _) };
-
-                callback(cmdheader, cmdfis, prdt_entry, acmd)
-            }
+            Self::build_command(slot, clb, ctbas, callback);
 
             while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
                 unsafe { asm!("pause"); }
@@ -319,8 +463,26 @@ impl HbaPort {
         }
     }
 
+    /// Build a command in a slot, without issuing it.
+    fn build_command<F>(slot: u32, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32], callback: F)
+              where F: FnOnce(&mut HbaCmdHeader, &mut FisRegH2D, &mut [HbaPrdtEntry; 65536], &mut [Mmio<u8>; 16]) {
+        let cmdheader = &mut clb[slot as usize];
+        cmdheader.cfl.write((size_of::<FisRegH2D>() / size_of::<u32>()) as u8);
+
+        let cmdtbl = &mut ctbas[slot as usize];
+        unsafe { ptr::write_bytes(cmdtbl.deref_mut() as *mut HbaCmdTable as *mut u8, 0, size_of::<HbaCmdTable>()); }
+
+        let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_mut_ptr() as *mut FisRegH2D) };
+        cmdfis.fis_type.write(FisType::RegH2D as u8);
+
+        let prdt_entry = unsafe { &mut *(&mut cmdtbl.prdt_entry as *mut _) };
+        let acmd = unsafe { &mut *(&mut cmdtbl.acmd as *mut _) };
+
+        callback(cmdheader, cmdfis, prdt_entry, acmd)
+    }
+
     pub fn ata_running(&self, slot: u32) -> bool {
-        (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0
+        (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0 && self.present()
     }
 
     pub fn ata_stop(&mut self, slot: u32) -> Result<()> {
@@ -330,7 +492,9 @@ impl HbaPort {
 
         self.stop();
 
-        if self.is.read() & HBA_PORT_IS_ERR != 0 {
+        if !self.present() {
+            Err(Error::new(ENODEV))
+        } else if self.is.read() & HBA_PORT_IS_ERR != 0 {
             print!("{}", format!("ERROR IS {:X} IE {:X} CMD {:X} TFD {:X}\nSSTS {:X} SCTL {:X} SERR {:X} SACT {:X}\nCI {:X} SNTF {:X} FBS {:X}\n",
                     self.is.read(), self.ie.read(), self.cmd.read(), self.tfd.read(),
                     self.ssts.read(), self.sctl.read(), self.serr.read(), self.sact.read(),
@@ -375,6 +539,45 @@ impl HbaMem {
             self.cap.read(), self.ghc.read(), self.is.read(), self.pi.read(),
             self.vs.read(), self.cap2.read(), self.bohc.read()));
     }
+
+    /// The number of command slots of each port, if the HBA supports NCQ.
+    pub fn ncq_slots(&self) -> Option<u32> {
+        let cap = self.cap.read();
+        if cap & HBA_CAP_SNCQ == HBA_CAP_SNCQ {
+            Some(((cap >> 8) & 0x1F) + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Acknowledge the interrupts of every port, returning whether the HBA raised any, and a mask
+    /// of the ports that a device was attached to or detached from.
+    pub fn irq(&mut self) -> (bool, u32) {
+        let is = self.is.read();
+        if is > 0 {
+            let pi = self.pi.read();
+            let pi_is = pi & is;
+            let mut changed = 0;
+            for i in 0..self.ports.len() {
+                if pi_is & 1 << i > 0 {
+                    let port = &mut self.ports[i];
+                    let is = port.is.read();
+                    if is & (HBA_PORT_IS_PCS | HBA_PORT_IS_PRCS) != 0 {
+                        // Both are only cleared along with the diagnostics in SError.
+                        let serr = port.serr.read();
+                        port.serr.write(serr);
+                        changed |= 1 << i;
+                    }
+                    //TODO: Handle requests for only this port here
+                    port.is.write(is);
+                }
+            }
+            self.is.write(is);
+            (true, changed)
+        } else {
+            (false, 0)
+        }
+    }
 }
 
 #[repr(packed)]
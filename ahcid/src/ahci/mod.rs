@@ -1,5 +1,6 @@
+use std::collections::BTreeMap;
+
 use syscall::io::Io;
-use syscall::error::Result;
 
 use self::disk_ata::DiskATA;
 use self::disk_atapi::DiskATAPI;
@@ -10,50 +11,51 @@ pub mod disk_atapi;
 pub mod fis;
 pub mod hba;
 
-pub trait Disk {
-    fn id(&self) -> usize;
-    fn size(&mut self) -> u64;
-    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>>;
-    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>>;
-    fn block_length(&mut self) -> Result<u32>;
+pub use block_io_wrapper::Disk;
+
+/// Set up the disk attached to port `i`, if there is one of a supported type.
+pub fn attach(hba_mem: &mut HbaMem, i: usize, name: &str) -> Option<Box<dyn Disk>> {
+    let ncq_slots = hba_mem.ncq_slots();
+    let port = unsafe { &mut *hba_mem.ports.as_mut_ptr().add(i) };
+    let port_type = port.probe();
+    print!("{}", format!("{}-{}: {:?}\n", name, i, port_type));
+
+    match port_type {
+        HbaPortType::SATA => {
+            match DiskATA::new(port, ncq_slots) {
+                Ok(disk) => Some(Box::new(disk)),
+                Err(err) => {
+                    print!("{}", format!("{}: {}\n", i, err));
+                    None
+                }
+            }
+        }
+        HbaPortType::SATAPI => {
+            match DiskATAPI::new(port) {
+                Ok(disk) => Some(Box::new(disk)),
+                Err(err) => {
+                    print!("{}", format!("{}: {}\n", i, err));
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
 }
 
-pub fn disks(base: usize, name: &str) -> (&'static mut HbaMem, Vec<Box<dyn Disk>>) {
+/// Set up the disks attached to the HBA at `base`, by the port that each is attached to. Every
+/// implemented port interrupts when a device is attached or detached later.
+pub fn disks(base: usize, name: &str) -> (&'static mut HbaMem, BTreeMap<usize, Box<dyn Disk>>) {
     let hba_mem = unsafe { &mut *(base as *mut HbaMem) };
     hba_mem.init();
     let pi = hba_mem.pi.read();
-    let disks: Vec<Box<dyn Disk>> = (0..hba_mem.ports.len())
-          .filter(|&i| pi & 1 << i as i32 == 1 << i as i32)
-          .filter_map(|i| {
-              let port = unsafe { &mut *hba_mem.ports.as_mut_ptr().add(i) };
-              let port_type = port.probe();
-              print!("{}", format!("{}-{}: {:?}\n", name, i, port_type));
-
-              let disk: Option<Box<dyn Disk>> = match port_type {
-                  HbaPortType::SATA => {
-                      match DiskATA::new(i, port) {
-                          Ok(disk) => Some(Box::new(disk)),
-                          Err(err) => {
-                              print!("{}", format!("{}: {}\n", i, err));
-                              None
-                          }
-                      }
-                  }
-                  HbaPortType::SATAPI => {
-                      match DiskATAPI::new(i, port) {
-                          Ok(disk) => Some(Box::new(disk)),
-                          Err(err) => {
-                              print!("{}", format!("{}: {}\n", i, err));
-                              None
-                          }
-                      }
-                  }
-                  _ => None,
-              };
-
-              disk
-          })
-          .collect();
+    let mut disks = BTreeMap::new();
+    for i in (0..hba_mem.ports.len()).filter(|&i| pi & 1 << i as i32 == 1 << i as i32) {
+        hba_mem.ports[i].enable_hotplug();
+        if let Some(disk) = attach(hba_mem, i, name) {
+            disks.insert(i, disk);
+        }
+    }
 
     (hba_mem, disks)
 }
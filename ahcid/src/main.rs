@@ -3,16 +3,53 @@
 extern crate syscall;
 extern crate byteorder;
 
+use std::collections::BTreeMap;
 use std::{env, usize};
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
 use std::os::unix::io::{FromRawFd, RawFd};
-use syscall::{ENODEV, EVENT_READ, PHYSMAP_NO_CACHE, PHYSMAP_WRITE, Error, Event, Packet, SchemeBlockMut};
+use std::time::Instant;
+use syscall::{CLOCK_MONOTONIC, ENODEV, EVENT_READ, PHYSMAP_NO_CACHE, PHYSMAP_WRITE, Error, Event, Packet, SchemeBlockMut, TimeSpec};
 
-use crate::scheme::DiskScheme;
+use block_io_wrapper::DiskScheme;
+
+use self::ahci::hba::{HbaMem, HBA_SIG_TIMEOUT};
 
 pub mod ahci;
-pub mod scheme;
+
+/// How often ports that a device was just attached to are checked for its signature.
+const PROBE_INTERVAL_NSECS: i32 = 10_000_000;
+
+/// Make the timer fire again after `PROBE_INTERVAL_NSECS`.
+fn arm_timer(time_file: &mut File) {
+    let mut time = TimeSpec::default();
+    time_file.read(&mut time).expect("ahcid: failed to read time");
+    time.tv_nsec += PROBE_INTERVAL_NSECS;
+    if time.tv_nsec >= 1_000_000_000 {
+        time.tv_sec += 1;
+        time.tv_nsec -= 1_000_000_000;
+    }
+    time_file.write(&time).expect("ahcid: failed to write time");
+}
+
+/// Attach the disks of the ports in `pending` that have sent their signature, or have taken too
+/// long to, and forget the ports that were detached again. Ports whose device hasn't sent its
+/// signature yet are kept, to be checked on a later timer tick.
+fn probe_pending(hba_mem: &mut HbaMem, name: &str, pending: &mut BTreeMap<usize, Instant>, scheme: &mut DiskScheme<Box<dyn ahci::Disk>>, disk_nums: &mut BTreeMap<usize, u32>, next_disk_num: &mut u32) {
+    let ports = pending.keys().cloned().collect::<Vec<_>>();
+    for port in ports {
+        if !hba_mem.ports[port].present() {
+            pending.remove(&port);
+        } else if hba_mem.ports[port].has_signature() || pending[&port].elapsed() >= HBA_SIG_TIMEOUT {
+            pending.remove(&port);
+            if let Some(disk) = ahci::attach(hba_mem, port, name) {
+                scheme.add_disk(*next_disk_num, disk);
+                disk_nums.insert(port, *next_disk_num);
+                *next_disk_num += 1;
+            }
+        }
+    }
+}
 
 fn main() {
     let mut args = env::args().skip(1);
@@ -51,6 +88,12 @@ fn main() {
             ).expect("ahcid: failed to open irq file");
             let mut irq_file = unsafe { File::from_raw_fd(irq_fd as RawFd) };
 
+            let time_fd = syscall::open(
+                &format!("time:{}", CLOCK_MONOTONIC),
+                syscall::O_RDWR
+            ).expect("ahcid: failed to open timer");
+            let mut time_file = unsafe { File::from_raw_fd(time_fd as RawFd) };
+
             let mut event_file = File::open("event:").expect("ahcid: failed to open event file");
 
             syscall::setrens(0, 0).expect("ahcid: failed to enter null namespace");
@@ -67,8 +110,28 @@ fn main() {
                 data: 0
             }).expect("ahcid: failed to event irq scheme");
 
-            let (hba_mem, disks) = ahci::disks(address, &name);
-            let mut scheme = DiskScheme::new(scheme_name, hba_mem, disks);
+            event_file.write(&Event {
+                id: time_fd,
+                flags: EVENT_READ,
+                data: 0
+            }).expect("ahcid: failed to event timer");
+
+            let (hba_mem, port_disks) = ahci::disks(address, &name);
+            // Disks are numbered in the order of their ports at first. Disks that are attached
+            // later get the next number, so that a number is never reused for another disk.
+            let mut disk_nums = BTreeMap::new();
+            let mut disks = BTreeMap::new();
+            for (disk_num, (port, disk)) in port_disks.into_iter().enumerate() {
+                disk_nums.insert(port, disk_num as u32);
+                disks.insert(disk_num as u32, disk);
+            }
+            let mut next_disk_num = disks.len() as u32;
+            let mut scheme = DiskScheme::new(scheme_name, disks);
+
+            // Ports that a device was attached to, by when, until it sends its signature. The
+            // timer only runs while there are any.
+            let mut pending = BTreeMap::new();
+            let mut timer_armed = false;
 
             let mut mounted = true;
             let mut todo = Vec::new();
@@ -103,9 +166,31 @@ fn main() {
                 } else if event.id == irq_fd {
                     let mut irq = [0; 8];
                     if irq_file.read(&mut irq).expect("ahcid: failed to read irq file") >= irq.len() {
-                        if scheme.irq() {
+                        let (raised, changed) = hba_mem.irq();
+                        if raised {
                             irq_file.write(&irq).expect("ahcid: failed to write irq file");
 
+                            // Remove the disks of the ports whose connection changed, whose
+                            // requests then fail with ENODEV. A device may have been detached and
+                            // another attached since the last interrupt, so a port that has a
+                            // device now is probed again, once it has sent its signature.
+                            for port in (0..hba_mem.ports.len()).filter(|&port| changed & 1 << port != 0) {
+                                if let Some(disk_num) = disk_nums.remove(&port) {
+                                    print!("{}", format!("{}-{}: detached\n", name, port));
+                                    scheme.remove_disk(disk_num);
+                                }
+                                if hba_mem.ports[port].present() {
+                                    pending.insert(port, Instant::now());
+                                } else {
+                                    pending.remove(&port);
+                                }
+                            }
+                            probe_pending(hba_mem, &name, &mut pending, &mut scheme, &mut disk_nums, &mut next_disk_num);
+                            if !pending.is_empty() && !timer_armed {
+                                arm_timer(&mut time_file);
+                                timer_armed = true;
+                            }
+
                             // Handle todos in order to finish previous packets if possible
                             let mut i = 0;
                             while i < todo.len() {
@@ -119,6 +204,12 @@ fn main() {
                             }
                         }
                     }
+                } else if event.id == time_fd {
+                    probe_pending(hba_mem, &name, &mut pending, &mut scheme, &mut disk_nums, &mut next_disk_num);
+                    timer_armed = !pending.is_empty();
+                    if timer_armed {
+                        arm_timer(&mut time_file);
+                    }
                 } else {
                     println!("Unknown event {}", event.id);
                 }
@@ -0,0 +1,75 @@
+use syscall::error::{Error, Result, ENOENT, EOPNOTSUPP};
+
+/// A block device served by a `DiskScheme`.
+///
+/// Reads and writes are of whole blocks. A driver that completes requests asynchronously returns
+/// `Ok(None)` until the request is done, and is then called again with the same arguments.
+pub trait Disk {
+    /// The size of the disk in bytes.
+    fn size(&mut self) -> u64;
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>>;
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>>;
+    fn block_length(&mut self) -> Result<u32>;
+    /// The most bytes that a single call to `read` or `write` may transfer. Longer requests are
+    /// split up.
+    fn max_transfer(&mut self) -> usize {
+        usize::max_value()
+    }
+    /// Make the data of completed writes durable, for disks with a volatile write cache.
+    fn flush(&mut self) -> Result<Option<usize>> {
+        Ok(Some(0))
+    }
+    /// Tell the disk that `count` blocks from `block` on are unused, so that their contents may
+    /// be dropped.
+    fn discard(&mut self, _block: u64, _count: u64) -> Result<Option<usize>> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+    /// Zero `count` blocks from `block` on, without transferring any data.
+    fn write_zeroes(&mut self, _block: u64, _count: u64) -> Result<Option<usize>> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+    /// The names of the files about the disk that the driver serves besides its data, such as
+    /// health information. Each file `name` of disk `N` is available as `N/name`.
+    fn files(&mut self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Handle a request written to one of `files`, returning the response that is read from it
+    /// next. Opening a file makes an empty request, so files that are only read return their
+    /// contents for it.
+    fn file_request(&mut self, _name: &str, _request: &[u8]) -> Result<Vec<u8>> {
+        Err(Error::new(ENOENT))
+    }
+}
+
+impl<D: Disk + ?Sized> Disk for Box<D> {
+    fn size(&mut self) -> u64 {
+        (**self).size()
+    }
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>> {
+        (**self).read(block, buffer)
+    }
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>> {
+        (**self).write(block, buffer)
+    }
+    fn block_length(&mut self) -> Result<u32> {
+        (**self).block_length()
+    }
+    fn max_transfer(&mut self) -> usize {
+        (**self).max_transfer()
+    }
+    fn flush(&mut self) -> Result<Option<usize>> {
+        (**self).flush()
+    }
+    fn discard(&mut self, block: u64, count: u64) -> Result<Option<usize>> {
+        (**self).discard(block, count)
+    }
+    fn write_zeroes(&mut self, block: u64, count: u64) -> Result<Option<usize>> {
+        (**self).write_zeroes(block, count)
+    }
+    fn files(&mut self) -> &'static [&'static str] {
+        (**self).files()
+    }
+    fn file_request(&mut self, name: &str, request: &[u8]) -> Result<Vec<u8>> {
+        (**self).file_request(name, request)
+    }
+}
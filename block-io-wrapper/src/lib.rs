@@ -1,47 +1,234 @@
-pub fn read<E>(offset: u64, blksize: u32, mut buf: &mut [u8], block_bytes: &mut [u8], mut read: impl FnMut(u64, &mut [u8]) -> Result<(), E>) -> Result<usize, E> {
+//! The disk scheme shared by the storage drivers, and helpers for doing byte-granular I/O on
+//! block devices.
+
+pub use syscall;
+
+pub use self::disk::Disk;
+pub use self::scheme::DiskScheme;
+
+mod disk;
+mod scheme;
+
+/// The largest run of whole blocks that fits in `max_transfer` bytes, which is at least one block.
+fn max_run(blksize: u64, max_transfer: usize) -> usize {
+    std::cmp::max(max_transfer - max_transfer % blksize as usize, blksize as usize)
+}
+
+/// Read `buf.len()` bytes starting at the byte `offset`, which need not be aligned to blocks.
+///
+/// Runs of whole blocks are read straight into `buf`, by calling `read` with the first block and
+/// a slice of at most `max_transfer` bytes. Blocks that are only partially covered by `buf` are
+/// read into `block_bytes` instead, and copied from there.
+pub fn read<E>(offset: u64, blksize: u32, max_transfer: usize, mut buf: &mut [u8], block_bytes: &mut [u8], mut read: impl FnMut(u64, &mut [u8]) -> Result<(), E>) -> Result<usize, E> {
     // TODO: Yield sometimes, perhaps after a few blocks or something.
-    use std::ops::{Add, Div, Rem};
-
-    fn div_round_up<T>(a: T, b: T) -> T
-    where
-        T: Add<Output = T> + Div<Output = T> + Rem<Output = T> + PartialEq + From<u8> + Copy
-    {
-        if a % b != T::from(0u8) {
-            a / b + T::from(1u8)
-        } else {
-            a / b
-        }
-    }
+    let blksize = u64::from(blksize);
+    let block_bytes = &mut block_bytes[..blksize as usize];
+    let max_run = max_run(blksize, max_transfer);
 
     let orig_buf_len = buf.len();
+    let mut offset = offset;
 
-    let start_block = offset / u64::from(blksize);
-    let end_block = div_round_up(offset + buf.len() as u64, u64::from(blksize)); // The first block not in the range
+    while !buf.is_empty() {
+        let block = offset / blksize;
+        let offset_in_block = (offset % blksize) as usize;
 
-    let offset_from_start_block: u64 = offset % u64::from(blksize);
-    let offset_to_end_block: u64 = u64::from(blksize) - (offset + buf.len() as u64) % u64::from(blksize);
+        let count = if offset_in_block == 0 && buf.len() as u64 >= blksize {
+            let count = std::cmp::min(buf.len() - buf.len() % blksize as usize, max_run);
+            read(block, &mut buf[..count])?;
+            count
+        } else {
+            let count = std::cmp::min(blksize as usize - offset_in_block, buf.len());
+            read(block, block_bytes)?;
+            buf[..count].copy_from_slice(&block_bytes[offset_in_block..offset_in_block + count]);
+            count
+        };
 
-    let first_whole_block = start_block + if offset_from_start_block > 0 { 1 } else { 0 };
-    let last_whole_block = end_block - if offset_to_end_block > 0 { 1 } else { 0 } - 1;
+        buf = &mut buf[count..];
+        offset += count as u64;
+    }
 
-    let whole_blocks_to_read = last_whole_block - first_whole_block + 1;
+    Ok(orig_buf_len)
+}
 
-    for block in start_block..=end_block {
-        // TODO: Async/await? I mean, shouldn't AHCI be async?
+/// Write `buf` starting at the byte `offset`. Blocks that are only partially covered by `buf` are
+/// read into `block_bytes` and written back with the new bytes, while runs of whole blocks are
+/// passed straight to `write`, at most `max_transfer` bytes at a time.
+pub fn write<E>(offset: u64, blksize: u32, max_transfer: usize, mut buf: &[u8], block_bytes: &mut [u8], mut read: impl FnMut(u64, &mut [u8]) -> Result<(), E>, mut write: impl FnMut(u64, &[u8]) -> Result<(), E>) -> Result<usize, E> {
+    let blksize = u64::from(blksize);
+    let block_bytes = &mut block_bytes[..blksize as usize];
+    let max_run = max_run(blksize, max_transfer);
 
-        read(block, block_bytes)?;
+    let orig_buf_len = buf.len();
+    let mut offset = offset;
 
-        let (bytes_to_read, src_buf): (u64, &[u8]) = if block == start_block {
-            (u64::from(blksize) - offset_from_start_block, &block_bytes[offset_from_start_block as usize..])
-        } else if block == end_block {
-            (u64::from(blksize) - offset_to_end_block, &block_bytes[..offset_to_end_block as usize])
+    while !buf.is_empty() {
+        let block = offset / blksize;
+        let offset_in_block = (offset % blksize) as usize;
+
+        let count = if offset_in_block == 0 && buf.len() as u64 >= blksize {
+            let count = std::cmp::min(buf.len() - buf.len() % blksize as usize, max_run);
+            write(block, &buf[..count])?;
+            count
         } else {
-            (blksize.into(), &block_bytes[..])
+            let count = std::cmp::min(blksize as usize - offset_in_block, buf.len());
+            read(block, block_bytes)?;
+            block_bytes[offset_in_block..offset_in_block + count].copy_from_slice(&buf[..count]);
+            write(block, block_bytes)?;
+            count
         };
-        let bytes_to_read = std::cmp::min(bytes_to_read as usize, buf.len());
-        buf[..bytes_to_read].copy_from_slice(&src_buf[..bytes_to_read]);
-        buf = &mut buf[bytes_to_read..];
+
+        buf = &buf[count..];
+        offset += count as u64;
+    }
+
+    Ok(orig_buf_len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::cmp;
+
+    const BLKSIZE: u32 = 8;
+    const BLOCKS: usize = 6;
+
+    /// The maximum transfer sizes to test with: a single block, a size that isn't a multiple of
+    /// the block size, and no limit.
+    const MAX_TRANSFERS: [usize; 4] = [1, 2 * BLKSIZE as usize + 3, 4 * BLKSIZE as usize, usize::max_value()];
+
+    /// A disk of `BLOCKS` blocks, where each byte initially holds its own offset, and that checks
+    /// that it's only ever asked for whole blocks.
+    struct MemDisk {
+        data: Vec<u8>,
+        reads: Vec<(u64, usize)>,
+        writes: Vec<(u64, usize)>,
+    }
+
+    impl MemDisk {
+        fn new() -> Self {
+            Self {
+                data: (0..BLKSIZE as usize * BLOCKS).map(|i| i as u8).collect(),
+                reads: Vec::new(),
+                writes: Vec::new(),
+            }
+        }
+        fn range(block: u64, len: usize) -> std::ops::Range<usize> {
+            assert!(len > 0 && len % BLKSIZE as usize == 0, "partial block");
+            let start = block as usize * BLKSIZE as usize;
+            assert!(start + len <= BLKSIZE as usize * BLOCKS, "past the end of the disk");
+            start..start + len
+        }
+        fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), ()> {
+            self.reads.push((block, buf.len()));
+            buf.copy_from_slice(&self.data[Self::range(block, buf.len())]);
+            Ok(())
+        }
+        fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), ()> {
+            self.writes.push((block, buf.len()));
+            self.data[Self::range(block, buf.len())].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// The blocks that the bytes from `offset` to `offset + len` are in.
+    fn blocks(offset: usize, len: usize) -> std::ops::Range<u64> {
+        let blksize = BLKSIZE as usize;
+        if len == 0 {
+            return 0..0;
+        }
+        (offset / blksize) as u64..((offset + len + blksize - 1) / blksize) as u64
+    }
+    /// The blocks that are only partially covered by the bytes from `offset` to `offset + len`,
+    /// which can only be the first and the last.
+    fn partial_blocks(offset: usize, len: usize) -> Vec<u64> {
+        let blksize = BLKSIZE as usize;
+
+        blocks(offset, len).filter(|&block| {
+            let start = cmp::max(offset, block as usize * blksize);
+            let end = cmp::min(offset + len, (block as usize + 1) * blksize);
+            end - start < blksize
+        }).collect()
     }
+    /// Check that `calls` cover the blocks from `offset` to `offset + len` exactly once, with each
+    /// partial block on its own, and the whole blocks in as few calls as `max_transfer` allows.
+    fn check_calls(calls: &[(u64, usize)], offset: usize, len: usize, max_transfer: usize) {
+        let blksize = BLKSIZE as usize;
+        let max_run = cmp::max(max_transfer - max_transfer % blksize, blksize);
+
+        let partial = partial_blocks(offset, len);
+        let whole = blocks(offset, len).count() - partial.len();
+        let runs = if whole > 0 { (whole * blksize - 1) / max_run + 1 } else { 0 };
+        assert_eq!(calls.len(), partial.len() + runs, "max_transfer {} offset {} len {}", max_transfer, offset, len);
+
+        for &(block, len) in calls {
+            assert!(len <= max_run, "max_transfer {} block {} len {}", max_transfer, block, len);
+            assert!(!partial.contains(&block) || len == blksize, "max_transfer {} block {} len {}", max_transfer, block, len);
+        }
+        let mut covered = calls.iter().flat_map(|&(block, len)| block..block + (len / blksize) as u64).collect::<Vec<_>>();
+        covered.sort();
+        assert_eq!(covered, blocks(offset, len).collect::<Vec<_>>(), "max_transfer {} offset {} len {}", max_transfer, offset, len);
+    }
+
+    #[test]
+    fn test_read() {
+        let size = BLKSIZE as usize * BLOCKS;
+
+        for &max_transfer in MAX_TRANSFERS.iter() {
+            for offset in 0..=size {
+                for len in 0..=size - offset {
+                    let mut disk = MemDisk::new();
+                    // Larger than a block, like the buffers drivers pass.
+                    let mut block_bytes = [0u8; 4 * BLKSIZE as usize];
+                    let mut buf = vec![0xFF; len];
+
+                    let count = super::read(offset as u64, BLKSIZE, max_transfer, &mut buf, &mut block_bytes, |block, bytes| disk.read(block, bytes)).unwrap();
 
-    Ok(std::cmp::min(orig_buf_len, whole_blocks_to_read as usize * blksize as usize + offset_from_start_block as usize + offset_to_end_block as usize))
+                    assert_eq!(count, len, "max_transfer {} offset {} len {}", max_transfer, offset, len);
+                    assert_eq!(buf, &disk.data[offset..offset + len], "max_transfer {} offset {} len {}", max_transfer, offset, len);
+                    check_calls(&disk.reads, offset, len, max_transfer);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let size = BLKSIZE as usize * BLOCKS;
+
+        for &max_transfer in MAX_TRANSFERS.iter() {
+            for offset in 0..=size {
+                for len in 0..=size - offset {
+                    let mut disk = RefCell::new(MemDisk::new());
+                    let mut block_bytes = [0u8; 4 * BLKSIZE as usize];
+                    let buf = (0..len).map(|i| 0x80 | i as u8).collect::<Vec<_>>();
+
+                    let mut expected = disk.get_mut().data.clone();
+                    expected[offset..offset + len].copy_from_slice(&buf);
+
+                    let count = super::write(offset as u64, BLKSIZE, max_transfer, &buf, &mut block_bytes, |block, bytes| disk.borrow_mut().read(block, bytes), |block, bytes| disk.borrow_mut().write(block, bytes)).unwrap();
+                    let disk = disk.get_mut();
+
+                    assert_eq!(count, len, "max_transfer {} offset {} len {}", max_transfer, offset, len);
+                    assert_eq!(disk.data, expected, "max_transfer {} offset {} len {}", max_transfer, offset, len);
+
+                    // Only the partial blocks are read back.
+                    let partial = partial_blocks(offset, len);
+                    assert_eq!(disk.reads, partial.iter().map(|&block| (block, BLKSIZE as usize)).collect::<Vec<_>>(), "max_transfer {} offset {} len {}", max_transfer, offset, len);
+                    check_calls(&disk.writes, offset, len, max_transfer);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let mut disk = MemDisk::new();
+        let mut block_bytes = [0u8; BLKSIZE as usize];
+        let mut buf = [0u8; 3 * BLKSIZE as usize];
+
+        // An error from the callback stops the transfer.
+        let result = super::read(4, BLKSIZE, BLKSIZE as usize, &mut buf, &mut block_bytes, |block, bytes| if block == 2 { Err(()) } else { disk.read(block, bytes) });
+        assert_eq!(result, Err(()));
+        assert_eq!(disk.reads, [(0, BLKSIZE as usize), (1, BLKSIZE as usize)]);
+    }
 }
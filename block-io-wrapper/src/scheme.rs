@@ -0,0 +1,699 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::{cmp, io, str, thread};
+
+use syscall::{
+    Error, EACCES, EBADF, EINVAL, EISDIR, ENODEV, ENOENT, ENOTDIR, EOVERFLOW, Result,
+    SchemeBlockMut, Stat, MODE_DIR, MODE_FILE, O_DIRECTORY, O_STAT, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use partitionlib::{LogicalBlockSize, PartitionTable};
+
+use crate::Disk;
+
+/// The file type of block devices, which isn't defined by `syscall` yet.
+const MODE_BLK: u16 = 0x6000;
+
+/// An operation on a range of blocks, without transferring data.
+#[derive(Clone, Copy)]
+enum RangeOp {
+    Discard,
+    WriteZeroes,
+}
+
+impl RangeOp {
+    fn name(self) -> &'static str {
+        match self {
+            RangeOp::Discard => "discard",
+            RangeOp::WriteZeroes => "zero",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Handle {
+    List(Vec<u8>, usize), // entries, offset
+    Disk(u32, usize), // disk num, offset
+    Partition(u32, u32, usize), // disk num, part num, offset
+    File(u32, &'static str, Vec<u8>, usize), // disk num, name, response, offset
+    Range(RangeOp, u32, Option<u32>), // operation, disk num, part num
+}
+
+/// What a path opened on the scheme refers to: the list of disks, a disk `N`, a partition `NpP`
+/// or a file `N/name` about a disk.
+#[derive(Debug, PartialEq)]
+enum Path<'a> {
+    List,
+    Disk(u32),
+    Partition(u32, u32),
+    File(u32, &'a str),
+}
+
+fn parse_path(path: &str) -> Option<Path> {
+    let path = path.trim_matches('/');
+
+    if path.is_empty() {
+        return Some(Path::List);
+    }
+    let mut components = path.splitn(2, '/');
+    let mut parts = components.next()?.splitn(2, 'p');
+    let disk_num = parts.next()?.parse::<u32>().ok()?;
+
+    match (parts.next(), components.next()) {
+        (Some(part_num), None) => Some(Path::Partition(disk_num, part_num.parse::<u32>().ok()?)),
+        (None, Some(name)) if !name.contains('/') => Some(Path::File(disk_num, name)),
+        (None, None) => Some(Path::Disk(disk_num)),
+        _ => None,
+    }
+}
+
+/// Retry a request until the disk has completed it. This is only done while reading the
+/// partition table of a disk that was just added, when no other request can be keeping the disk
+/// busy; scheme calls return `Ok(None)` instead, so that the driver can make progress meanwhile.
+fn wait<T>(mut request: impl FnMut() -> Result<Option<T>>) -> Result<T> {
+    loop {
+        if let Some(value) = request()? {
+            return Ok(value);
+        }
+        thread::yield_now();
+    }
+}
+
+/// A read or write that the disk can't serve with a single request, because it isn't aligned to
+/// blocks or is longer than the disk can transfer at once. It is split up the same way as by
+/// `block_io_wrapper::read` and `write`, but is done one disk request at a time, so that it can
+/// pick up where it left off when the scheme retries it.
+struct Transfer {
+    offset: u64,
+    blksize: u64,
+    max_run: usize,
+    /// The number of bytes of the buffer that are done.
+    done: usize,
+    /// Whether the partial block being written has been read into `block_bytes`, and modified.
+    block_read: bool,
+    /// The partial block at either end, which keeps its address while the disk works on it.
+    block_bytes: Vec<u8>,
+}
+
+impl Transfer {
+    fn new(offset: u64, blksize: u32, max_transfer: usize) -> Self {
+        Self {
+            offset,
+            blksize: u64::from(blksize),
+            max_run: crate::max_run(u64::from(blksize), max_transfer),
+            done: 0,
+            block_read: false,
+            block_bytes: vec![0; blksize as usize],
+        }
+    }
+    /// The block that the next request is for, the offset into it, the number of bytes of a
+    /// buffer of `len` bytes that it covers, and whether it is of whole blocks.
+    fn next(&self, len: usize) -> (u64, usize, usize, bool) {
+        let offset = self.offset + self.done as u64;
+        let block = offset / self.blksize;
+        let offset_in_block = (offset % self.blksize) as usize;
+        let remaining = len - self.done;
+
+        if offset_in_block == 0 && remaining as u64 >= self.blksize {
+            (block, 0, cmp::min(remaining - remaining % self.blksize as usize, self.max_run), true)
+        } else {
+            (block, offset_in_block, cmp::min(self.blksize as usize - offset_in_block, remaining), false)
+        }
+    }
+    fn read<D: Disk>(&mut self, disk: &mut D, buf: &mut [u8]) -> Result<Option<usize>> {
+        while self.done < buf.len() {
+            let (block, offset_in_block, count, whole) = self.next(buf.len());
+
+            if whole {
+                if disk.read(block, &mut buf[self.done..self.done + count])?.is_none() {
+                    return Ok(None);
+                }
+            } else {
+                if disk.read(block, &mut self.block_bytes)?.is_none() {
+                    return Ok(None);
+                }
+                buf[self.done..self.done + count].copy_from_slice(&self.block_bytes[offset_in_block..offset_in_block + count]);
+            }
+            self.done += count;
+        }
+        Ok(Some(buf.len()))
+    }
+    fn write<D: Disk>(&mut self, disk: &mut D, buf: &[u8]) -> Result<Option<usize>> {
+        while self.done < buf.len() {
+            let (block, offset_in_block, count, whole) = self.next(buf.len());
+
+            if whole {
+                if disk.write(block, &buf[self.done..self.done + count])?.is_none() {
+                    return Ok(None);
+                }
+            } else {
+                // The block mustn't be read again once its write has been started.
+                if !self.block_read {
+                    if disk.read(block, &mut self.block_bytes)?.is_none() {
+                        return Ok(None);
+                    }
+                    self.block_bytes[offset_in_block..offset_in_block + count].copy_from_slice(&buf[self.done..self.done + count]);
+                    self.block_read = true;
+                }
+                if disk.write(block, &self.block_bytes)?.is_none() {
+                    return Ok(None);
+                }
+                self.block_read = false;
+            }
+            self.done += count;
+        }
+        Ok(Some(buf.len()))
+    }
+}
+
+struct DiskWrapper<D> {
+    disk: D,
+    pt: Option<PartitionTable>,
+    /// The transfers that are waiting for the disk, by whether they write, their byte offset on
+    /// the disk, and the address and length of their buffer, which stay the same every time the
+    /// request is retried.
+    transfers: BTreeMap<(bool, u64, usize, usize), Transfer>,
+}
+
+impl<D: Disk> DiskWrapper<D> {
+    fn pt(disk: &mut D) -> Option<PartitionTable> {
+        let bs = match disk.block_length() {
+            Ok(512) => LogicalBlockSize::Lb512,
+            Ok(4096) => LogicalBlockSize::Lb4096,
+            _ => return None,
+        };
+        struct Device<'a, 'b, D> { disk: &'a mut D, offset: u64, block_bytes: &'b mut [u8] }
+
+        impl<'a, 'b, D: Disk> Seek for Device<'a, 'b, D> {
+            fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+                let size = i64::try_from(self.disk.size()).or(Err(io::Error::new(io::ErrorKind::Other, "Disk larger than 2^63 - 1 bytes")))?;
+
+                self.offset = match from {
+                    SeekFrom::Start(new_pos) => cmp::min(self.disk.size(), new_pos),
+                    SeekFrom::Current(new_pos) => cmp::max(0, cmp::min(size, self.offset as i64 + new_pos)) as u64,
+                    SeekFrom::End(new_pos) => cmp::max(0, cmp::min(size + new_pos, size)) as u64,
+                };
+
+                Ok(self.offset)
+            }
+        }
+
+        impl<'a, 'b, D: Disk> Read for Device<'a, 'b, D> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let blksize = self.disk.block_length().map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                let size_in_blocks = self.disk.size() / u64::from(blksize);
+                let max_transfer = self.disk.max_transfer();
+
+                let disk = &mut self.disk;
+
+                let read_blocks = |block: u64, bytes: &mut [u8]| {
+                    if block + bytes.len() as u64 / u64::from(blksize) > size_in_blocks {
+                        return Err(io::Error::from_raw_os_error(syscall::EOVERFLOW));
+                    }
+                    let count = wait(|| disk.read(block, bytes)).map_err(|err| io::Error::from_raw_os_error(err.errno))?;
+                    assert_eq!(count, bytes.len());
+                    Ok(())
+                };
+                let bytes_read = crate::read(self.offset, blksize, max_transfer, buf, self.block_bytes, read_blocks)?;
+
+                self.offset += bytes_read as u64;
+                Ok(bytes_read)
+            }
+        }
+
+        let mut block_bytes = [0u8; 4096];
+
+        partitionlib::get_partitions(&mut Device { disk, offset: 0, block_bytes: &mut block_bytes[..bs.into()] }, bs).ok().and_then(|pt| pt)
+    }
+    fn new(mut disk: D) -> Self {
+        Self {
+            pt: Self::pt(&mut disk),
+            disk,
+            transfers: BTreeMap::new(),
+        }
+    }
+    /// The first block and the number of blocks of either the whole disk, or a partition.
+    fn extent(&mut self, part_num: Option<u32>) -> Result<(u64, u64)> {
+        match part_num {
+            None => Ok((0, self.disk.size() / u64::from(self.disk.block_length()?))),
+            Some(part_num) => {
+                let part = self.pt.as_ref().and_then(|pt| pt.partitions.get(part_num as usize)).ok_or(Error::new(EBADF))?;
+                Ok((part.start_lba, part.size))
+            }
+        }
+    }
+    /// The byte offset on the disk that I/O at `offset` into the disk or a partition starts at,
+    /// and how many of `len` bytes fit before the end, so that I/O never crosses into the next
+    /// partition.
+    fn locate(&mut self, part_num: Option<u32>, offset: usize, len: usize) -> Result<(u64, usize)> {
+        let blksize = u64::from(self.disk.block_length()?);
+        let (start, blocks) = self.extent(part_num)?;
+
+        let remaining = (blocks * blksize).saturating_sub(offset as u64);
+
+        Ok((start * blksize + offset as u64, cmp::min(len as u64, remaining) as usize))
+    }
+    /// Read from a byte offset on the disk. Requests of whole blocks that the disk can transfer at
+    /// once go straight to it, while others are split up into a `Transfer`, which returns
+    /// `Ok(None)` whenever the disk does, to be resumed when the request is retried.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<Option<usize>> {
+        let blksize = self.disk.block_length()?;
+        let max_transfer = self.disk.max_transfer();
+
+        if offset % u64::from(blksize) == 0 && buf.len() % blksize as usize == 0 && buf.len() <= max_transfer {
+            return self.disk.read(offset / u64::from(blksize), buf);
+        }
+
+        let key = (false, offset, buf.as_ptr() as usize, buf.len());
+        let mut transfer = self.transfers.remove(&key).unwrap_or_else(|| Transfer::new(offset, blksize, max_transfer));
+        let result = transfer.read(&mut self.disk, buf);
+        if let Ok(None) = result {
+            self.transfers.insert(key, transfer);
+        }
+        result
+    }
+    /// Write to a byte offset on the disk, doing read-modify-write for partial blocks, like `read`.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<Option<usize>> {
+        let blksize = self.disk.block_length()?;
+        let max_transfer = self.disk.max_transfer();
+
+        if offset % u64::from(blksize) == 0 && buf.len() % blksize as usize == 0 && buf.len() <= max_transfer {
+            return self.disk.write(offset / u64::from(blksize), buf);
+        }
+
+        let key = (true, offset, buf.as_ptr() as usize, buf.len());
+        let mut transfer = self.transfers.remove(&key).unwrap_or_else(|| Transfer::new(offset, blksize, max_transfer));
+        let result = transfer.write(&mut self.disk, buf);
+        if let Ok(None) = result {
+            self.transfers.insert(key, transfer);
+        }
+        result
+    }
+}
+
+/// Serves the disks of a storage driver. Each disk `N` is available as `N`, and each of its
+/// partitions `P` as `NpP`. Listing the root of the scheme returns all of them. The files that a
+/// disk serves besides its data are available as `N/name`, but aren't listed.
+///
+/// Duplicating a disk or partition handle with `discard` or `zero` returns a handle that discards
+/// or zeroes ranges of blocks. Each write to it is a little-endian `u64` byte offset into the disk
+/// or partition followed by a `u64` byte length, both multiples of the block size.
+///
+/// Disks may be added and removed while the scheme runs. Handles to a removed disk fail with
+/// `ENODEV`, so disk numbers shouldn't be reused.
+pub struct DiskScheme<D> {
+    scheme_name: String,
+    disks: BTreeMap<u32, DiskWrapper<D>>,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize,
+}
+
+impl<D: Disk> DiskScheme<D> {
+    /// Create the scheme, reading the partition table of every disk.
+    pub fn new(scheme_name: String, disks: BTreeMap<u32, D>) -> Self {
+        Self {
+            scheme_name,
+            disks: disks.into_iter().map(|(num, disk)| (num, DiskWrapper::new(disk))).collect(),
+            handles: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+    /// Serve another disk as `num`, reading its partition table.
+    pub fn add_disk(&mut self, num: u32, disk: D) {
+        self.disks.insert(num, DiskWrapper::new(disk));
+    }
+    /// Stop serving disk `num`, returning it.
+    pub fn remove_disk(&mut self, num: u32) -> Option<D> {
+        self.disks.remove(&num).map(|wrapper| wrapper.disk)
+    }
+    fn list(&self) -> Vec<u8> {
+        let mut list = String::new();
+
+        for (disk_num, disk) in self.disks.iter() {
+            writeln!(list, "{}", disk_num).unwrap();
+
+            if let Some(ref pt) = disk.pt {
+                for part_num in 0..pt.partitions.len() {
+                    writeln!(list, "{}p{}", disk_num, part_num).unwrap();
+                }
+            }
+        }
+        list.into_bytes()
+    }
+}
+
+fn seek_offset(offset: usize, len: usize, pos: usize, whence: usize) -> Result<usize> {
+    Ok(match whence {
+        SEEK_SET => cmp::min(len, pos),
+        SEEK_CUR => cmp::max(0, cmp::min(len as isize, offset as isize + pos as isize)) as usize,
+        SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
+        _ => return Err(Error::new(EINVAL)),
+    })
+}
+
+/// Discard or zero the range of a disk or partition in a request written to a `Handle::Range`,
+/// returning the length of the request once the disk has completed it.
+fn range_request<D: Disk>(disk: &mut DiskWrapper<D>, op: RangeOp, part_num: Option<u32>, buf: &[u8]) -> Result<Option<usize>> {
+    if buf.len() != 16 {
+        return Err(Error::new(EINVAL));
+    }
+    let offset = u64::from_le_bytes(<[u8; 8]>::try_from(&buf[..8]).unwrap());
+    let len = u64::from_le_bytes(<[u8; 8]>::try_from(&buf[8..]).unwrap());
+
+    let blksize = u64::from(disk.disk.block_length()?);
+    if offset % blksize != 0 || len % blksize != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let (start, blocks) = disk.extent(part_num)?;
+    let (block, count) = (offset / blksize, len / blksize);
+    if block.checked_add(count).map_or(true, |end| end > blocks) {
+        return Err(Error::new(EOVERFLOW));
+    }
+    if count == 0 {
+        return Ok(Some(buf.len()));
+    }
+
+    let result = match op {
+        RangeOp::Discard => disk.disk.discard(start + block, count)?,
+        RangeOp::WriteZeroes => disk.disk.write_zeroes(start + block, count)?,
+    };
+    Ok(result.map(|_| buf.len()))
+}
+
+impl<D: Disk> SchemeBlockMut for DiskScheme<D> {
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<Option<usize>> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+        let path = str::from_utf8(path).ok().and_then(parse_path).ok_or(Error::new(ENOENT))?;
+        let directory = flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT;
+
+        let handle = match path {
+            Path::List => {
+                if flags & O_DIRECTORY != O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EISDIR));
+                }
+                Handle::List(self.list(), 0)
+            }
+            Path::Disk(disk_num) => {
+                if !self.disks.contains_key(&disk_num) {
+                    return Err(Error::new(ENOENT));
+                }
+                if directory {
+                    return Err(Error::new(ENOTDIR));
+                }
+                Handle::Disk(disk_num, 0)
+            }
+            Path::Partition(disk_num, part_num) => {
+                let disk = self.disks.get(&disk_num).ok_or(Error::new(ENOENT))?;
+                if disk.pt.as_ref().and_then(|pt| pt.partitions.get(part_num as usize)).is_none() {
+                    return Err(Error::new(ENOENT));
+                }
+                if directory {
+                    return Err(Error::new(ENOTDIR));
+                }
+                Handle::Partition(disk_num, part_num, 0)
+            }
+            Path::File(disk_num, name) => {
+                let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENOENT))?;
+                let name = *disk.disk.files().iter().find(|&&file| file == name).ok_or(Error::new(ENOENT))?;
+                if directory {
+                    return Err(Error::new(ENOTDIR));
+                }
+                Handle::File(disk_num, name, disk.disk.file_request(name, &[])?, 0)
+            }
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(Some(id))
+    }
+
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<Option<usize>> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let new_handle = match buf {
+            b"" => handle.clone(),
+            b"discard" | b"zero" => {
+                let op = if buf == b"discard" { RangeOp::Discard } else { RangeOp::WriteZeroes };
+                match *handle {
+                    Handle::Disk(disk_num, _) => Handle::Range(op, disk_num, None),
+                    Handle::Partition(disk_num, part_num, _) => Handle::Range(op, disk_num, Some(part_num)),
+                    _ => return Err(Error::new(EBADF)),
+                }
+            }
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(new_id, new_handle);
+        Ok(Some(new_id))
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<Option<usize>> {
+        let (disk_num, part_num) = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::List(ref data, _) => {
+                stat.st_mode = MODE_DIR;
+                stat.st_size = data.len() as u64;
+                return Ok(Some(0));
+            }
+            Handle::File(_, _, ref data, _) => {
+                stat.st_mode = MODE_FILE;
+                stat.st_size = data.len() as u64;
+                return Ok(Some(0));
+            }
+            Handle::Range(_, _, _) => {
+                stat.st_mode = MODE_FILE;
+                stat.st_size = 0;
+                return Ok(Some(0));
+            }
+            Handle::Disk(disk_num, _) => (disk_num, None),
+            Handle::Partition(disk_num, part_num, _) => (disk_num, Some(part_num)),
+        };
+        let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+        let blksize = disk.disk.block_length()?;
+        let (_, blocks) = disk.extent(part_num)?;
+
+        stat.st_mode = MODE_BLK;
+        stat.st_size = blocks * u64::from(blksize);
+        stat.st_blksize = blksize;
+        stat.st_blocks = blocks;
+        Ok(Some(0))
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let path = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::List(_, _) => format!("{}:", self.scheme_name),
+            Handle::Disk(disk_num, _) => format!("{}:{}", self.scheme_name, disk_num),
+            Handle::Partition(disk_num, part_num, _) => format!("{}:{}p{}", self.scheme_name, disk_num, part_num),
+            Handle::File(disk_num, name, _, _) => format!("{}:{}/{}", self.scheme_name, disk_num, name),
+            Handle::Range(op, disk_num, None) => format!("{}:{}/{}", self.scheme_name, disk_num, op.name()),
+            Handle::Range(op, disk_num, Some(part_num)) => format!("{}:{}p{}/{}", self.scheme_name, disk_num, part_num, op.name()),
+        };
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[..count].copy_from_slice(&path.as_bytes()[..count]);
+        Ok(Some(count))
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<Option<usize>> {
+        let (disk_num, part_num, offset) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::List(ref data, ref mut offset) | Handle::File(_, _, ref data, ref mut offset) => {
+                let count = (&data[cmp::min(*offset, data.len())..]).read(buf).unwrap();
+                *offset += count;
+                return Ok(Some(count));
+            }
+            Handle::Range(_, _, _) => return Err(Error::new(EBADF)),
+            Handle::Disk(disk_num, ref mut offset) => (disk_num, None, offset),
+            Handle::Partition(disk_num, part_num, ref mut offset) => (disk_num, Some(part_num), offset),
+        };
+        let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+
+        let (disk_offset, len) = disk.locate(part_num, *offset, buf.len())?;
+        if len == 0 {
+            return Ok(Some(0));
+        }
+        if let Some(count) = disk.read(disk_offset, &mut buf[..len])? {
+            *offset += count;
+            Ok(Some(count))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<Option<usize>> {
+        let (disk_num, part_num, offset) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::List(_, _) => return Err(Error::new(EBADF)),
+            Handle::File(disk_num, name, ref mut data, ref mut offset) => {
+                let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+                *data = disk.disk.file_request(name, buf)?;
+                *offset = 0;
+                return Ok(Some(buf.len()));
+            }
+            Handle::Range(op, disk_num, part_num) => {
+                let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+                return range_request(disk, op, part_num, buf);
+            }
+            Handle::Disk(disk_num, ref mut offset) => (disk_num, None, offset),
+            Handle::Partition(disk_num, part_num, ref mut offset) => (disk_num, Some(part_num), offset),
+        };
+        let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+
+        let (disk_offset, len) = disk.locate(part_num, *offset, buf.len())?;
+        if len == 0 && !buf.is_empty() {
+            return Err(Error::new(EOVERFLOW));
+        }
+        if let Some(count) = disk.write(disk_offset, &buf[..len])? {
+            *offset += count;
+            Ok(Some(count))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<Option<usize>> {
+        let (disk_num, part_num, offset) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::List(ref data, ref mut offset) | Handle::File(_, _, ref data, ref mut offset) => {
+                *offset = seek_offset(*offset, data.len(), pos, whence)?;
+                return Ok(Some(*offset));
+            }
+            Handle::Range(_, _, _) => return Err(Error::new(EBADF)),
+            Handle::Disk(disk_num, ref mut offset) => (disk_num, None, offset),
+            Handle::Partition(disk_num, part_num, ref mut offset) => (disk_num, Some(part_num), offset),
+        };
+        let disk = self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?;
+
+        let (_, blocks) = disk.extent(part_num)?;
+        let len = blocks * u64::from(disk.disk.block_length()?);
+
+        *offset = seek_offset(*offset, len as usize, pos, whence)?;
+        Ok(Some(*offset))
+    }
+
+    fn fsync(&mut self, id: usize) -> Result<Option<usize>> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Disk(disk_num, _) | Handle::Partition(disk_num, _, _) => {
+                self.disks.get_mut(&disk_num).ok_or(Error::new(ENODEV))?.disk.flush()
+            }
+            _ => Ok(Some(0)),
+        }
+    }
+
+    fn close(&mut self, id: usize) -> Result<Option<usize>> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(Some(0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use syscall::Result;
+
+    use crate::Disk;
+    use super::{parse_path, DiskWrapper, Path};
+
+    const BLKSIZE: usize = 16;
+
+    /// A disk that works on one request at a time, like ahcid without NCQ: a request is started by
+    /// the first call for it, and done once it is called again with the same arguments. Calls for
+    /// other requests meanwhile return `Ok(None)` without starting them.
+    struct OneAtATimeDisk {
+        data: Vec<u8>,
+        running: Option<(bool, u64, usize, usize)>,
+    }
+
+    impl OneAtATimeDisk {
+        fn request(&mut self, request: (bool, u64, usize, usize)) -> bool {
+            match self.running {
+                Some(running) if running == request => {
+                    self.running = None;
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    self.running = Some(request);
+                    false
+                }
+            }
+        }
+    }
+
+    impl Disk for OneAtATimeDisk {
+        fn size(&mut self) -> u64 {
+            self.data.len() as u64
+        }
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>> {
+            if !self.request((false, block, buffer.as_ptr() as usize, buffer.len())) {
+                return Ok(None);
+            }
+            let start = block as usize * BLKSIZE;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(Some(buffer.len()))
+        }
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>> {
+            if !self.request((true, block, buffer.as_ptr() as usize, buffer.len())) {
+                return Ok(None);
+            }
+            let start = block as usize * BLKSIZE;
+            self.data[start..start + buffer.len()].copy_from_slice(buffer);
+            Ok(Some(buffer.len()))
+        }
+        fn block_length(&mut self) -> Result<u32> {
+            Ok(BLKSIZE as u32)
+        }
+    }
+
+    #[test]
+    fn test_split_requests_wait_for_others() {
+        let data = (0..8 * BLKSIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut wrapper = DiskWrapper::new(OneAtATimeDisk { data: data.clone(), running: None });
+
+        // An aligned read, which the disk starts first, and an unaligned read and write that can
+        // only make progress once it is done, as the scheme retries them in turn.
+        let mut aligned = vec![0; 2 * BLKSIZE];
+        let mut unaligned = vec![0; 2 * BLKSIZE];
+        let written = vec![0xFF; BLKSIZE + 3];
+        let mut done = [false; 3];
+        for _ in 0..100 {
+            if !done[0] {
+                done[0] = wrapper.read(0, &mut aligned).unwrap().is_some();
+            }
+            if !done[1] {
+                done[1] = wrapper.read(3, &mut unaligned).unwrap().is_some();
+            }
+            if !done[2] {
+                done[2] = wrapper.write(4 * BLKSIZE as u64 + 5, &written).unwrap().is_some();
+            }
+        }
+
+        assert_eq!(done, [true; 3]);
+        assert_eq!(aligned, &data[..2 * BLKSIZE]);
+        assert_eq!(unaligned, &data[3..3 + 2 * BLKSIZE]);
+
+        let mut expected = data.clone();
+        expected[4 * BLKSIZE + 5..5 * BLKSIZE + 8].copy_from_slice(&written);
+        assert_eq!(wrapper.disk.data, expected);
+        assert!(wrapper.transfers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(parse_path(""), Some(Path::List));
+        assert_eq!(parse_path("/"), Some(Path::List));
+        assert_eq!(parse_path("1"), Some(Path::Disk(1)));
+        assert_eq!(parse_path("/12/"), Some(Path::Disk(12)));
+        assert_eq!(parse_path("0p3"), Some(Path::Partition(0, 3)));
+        assert_eq!(parse_path("0/health"), Some(Path::File(0, "health")));
+        assert_eq!(parse_path("/2/passthrough/"), Some(Path::File(2, "passthrough")));
+
+        assert_eq!(parse_path("p3"), None);
+        assert_eq!(parse_path("0p"), None);
+        assert_eq!(parse_path("0p1p2"), None);
+        assert_eq!(parse_path("disk"), None);
+        assert_eq!(parse_path("0p1/health"), None);
+        assert_eq!(parse_path("0/a/b"), None);
+    }
+}
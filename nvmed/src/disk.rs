@@ -0,0 +1,228 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use block_io_wrapper::Disk;
+use syscall::error::{Error, Result, EINVAL, ENOENT, EOPNOTSUPP};
+
+use crate::log;
+use crate::nvme::{self, Nvme, NvmeCommandId, NvmeNamespace, FEATURE_TEMPERATURE_THRESHOLD, NSID_GLOBAL, SETTABLE_FEATURES};
+
+enum Operation<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+    /// Write zeroes to a number of blocks.
+    WriteZeroes(u64),
+    /// Deallocate a number of blocks.
+    Deallocate(u64),
+    Flush,
+}
+
+/// A request to the namespace, split up into commands that run at the same time.
+struct Request {
+    /// The number of units, from the start of the request, that commands were submitted for. A
+    /// unit is a byte of the buffer for reads and writes, a block for other operations on blocks,
+    /// or the whole request for a flush.
+    submitted: usize,
+    /// The commands that haven't been finished, with the range of units that each covers. The
+    /// controller may access that range of a buffer until the command is finished.
+    running: Vec<(NvmeCommandId, usize, usize)>,
+    /// The first error of a command, which is returned once no command is running anymore.
+    error: Option<Error>,
+}
+
+/// A namespace of a controller, served as a disk.
+pub struct NvmeDisk {
+    nvme: Arc<Mutex<Nvme>>,
+    ns: NvmeNamespace,
+    /// The requests in progress, by their kind, their first block, and the address and length of
+    /// their buffer or their number of blocks, which stay the same every time a request is retried.
+    requests: BTreeMap<(u8, u64, usize, usize), Request>,
+}
+
+impl NvmeDisk {
+    pub fn new(nvme: Arc<Mutex<Nvme>>, ns: NvmeNamespace) -> Self {
+        Self {
+            nvme,
+            ns,
+            requests: BTreeMap::new(),
+        }
+    }
+
+    fn request(&mut self, block: u64, mut operation: Operation) -> Result<Option<usize>> {
+        let (kind, address, len) = match operation {
+            Operation::Read(ref buffer) => (0, buffer.as_ptr() as usize, buffer.len()),
+            Operation::Write(ref buffer) => (1, buffer.as_ptr() as usize, buffer.len()),
+            Operation::WriteZeroes(blocks) => (2, 0, blocks.try_into().or(Err(Error::new(EINVAL)))?),
+            Operation::Deallocate(blocks) => (3, 0, blocks.try_into().or(Err(Error::new(EINVAL)))?),
+            Operation::Flush => (4, 0, 1),
+        };
+        let key = (kind, block, address, len);
+
+        // The controller transfers whole blocks, directly to or from the buffer.
+        let block_size = self.ns.block_size as usize;
+        if kind <= 1 && len % block_size != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut nvme = self.nvme.lock().unwrap();
+        // The request may be retried without waiting for an interrupt, when the scheme has to
+        // wait for it to complete.
+        nvme.poll();
+
+        let mut request = self.requests.remove(&key).unwrap_or(Request {
+            submitted: 0,
+            running: Vec::new(),
+            error: None,
+        });
+
+        let mut i = 0;
+        while i < request.running.len() {
+            let (id, start, end) = request.running[i];
+            let read = match operation {
+                Operation::Read(ref mut buffer) => Some(&mut buffer[start..end]),
+                _ => None,
+            };
+            match nvme.finish(id, read) {
+                Some(result) => {
+                    request.running.swap_remove(i);
+                    if let Err(err) = result {
+                        request.error.get_or_insert(err);
+                    }
+                }
+                None => i += 1,
+            }
+        }
+
+        // Keep as many commands running as the queues have room for, unless one has failed.
+        let max_units = match operation {
+            Operation::Read(_) | Operation::Write(_) => nvme.max_transfer() / block_size * block_size,
+            Operation::WriteZeroes(_) => 0x1_0000,
+            Operation::Deallocate(_) => u32::max_value() as usize,
+            Operation::Flush => 1,
+        };
+        while request.error.is_none() && request.submitted < len {
+            let start = request.submitted;
+            let end = start + cmp::min(len - start, max_units);
+
+            let submitted = unsafe {
+                match operation {
+                    Operation::Read(ref mut buffer) => {
+                        let lba = block + (start / block_size) as u64;
+                        nvme.submit_read(self.ns.id, lba, (end - start) / block_size, &mut buffer[start..end])
+                    }
+                    Operation::Write(ref buffer) => {
+                        let lba = block + (start / block_size) as u64;
+                        nvme.submit_write(self.ns.id, lba, (end - start) / block_size, &buffer[start..end])
+                    }
+                    Operation::WriteZeroes(_) => nvme.submit_write_zeroes(self.ns.id, block + start as u64, end - start),
+                    Operation::Deallocate(_) => nvme.submit_deallocate(self.ns.id, block + start as u64, (end - start) as u32),
+                    Operation::Flush => nvme.submit_flush(self.ns.id),
+                }
+            };
+            match submitted {
+                Ok(Some(id)) => {
+                    request.running.push((id, start, end));
+                    request.submitted = end;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    request.error = Some(err);
+                }
+            }
+        }
+
+        if request.running.is_empty() && (request.error.is_some() || request.submitted == len) {
+            match request.error {
+                Some(err) => Err(err),
+                None => Ok(Some(len)),
+            }
+        } else {
+            self.requests.insert(key, request);
+            Ok(None)
+        }
+    }
+}
+
+impl Disk for NvmeDisk {
+    fn size(&mut self) -> u64 {
+        self.ns.blocks * self.ns.block_size
+    }
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>> {
+        self.request(block, Operation::Read(buffer))
+    }
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>> {
+        self.request(block, Operation::Write(buffer))
+    }
+    fn block_length(&mut self) -> Result<u32> {
+        Ok(self.ns.block_size.try_into().expect("Unreasonable block size of over 2^32 bytes"))
+    }
+    fn flush(&mut self) -> Result<Option<usize>> {
+        // Without a volatile write cache, writes are durable once they have completed.
+        if !self.nvme.lock().unwrap().has_volatile_write_cache() {
+            return Ok(Some(0));
+        }
+        self.request(0, Operation::Flush)
+    }
+    fn discard(&mut self, block: u64, count: u64) -> Result<Option<usize>> {
+        if !self.nvme.lock().unwrap().supports_dataset_management() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        self.request(block, Operation::Deallocate(count))
+    }
+    fn write_zeroes(&mut self, block: u64, count: u64) -> Result<Option<usize>> {
+        if !self.nvme.lock().unwrap().supports_write_zeroes() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        self.request(block, Operation::WriteZeroes(count))
+    }
+    /// The log pages of the controller, the features that may be changed, and a passthrough for
+    /// admin commands, in the format of `Nvme::admin_passthrough`. The features are read as
+    /// `name: value` lines, and one of them is set by writing such a line.
+    fn files(&mut self) -> &'static [&'static str] {
+        &["health", "errors", "firmware", "features", "admin"]
+    }
+    fn file_request(&mut self, name: &str, request: &[u8]) -> Result<Vec<u8>> {
+        let mut nvme = self.nvme.lock().unwrap();
+
+        match name {
+            "admin" if request.is_empty() => Ok(Vec::new()),
+            "admin" => unsafe { nvme.admin_passthrough(request) },
+            "features" => {
+                if !request.is_empty() {
+                    let (fid, value) = nvme::parse_feature_request(request)?;
+                    unsafe { nvme.set_features(fid, value)?; }
+                }
+
+                let mut features = String::new();
+                for &(name, fid, mask) in SETTABLE_FEATURES.iter() {
+                    let value = unsafe { nvme.get_features(fid, 0)? } & mask;
+                    writeln!(features, "{}: {}", name, value).unwrap();
+                }
+                Ok(features.into_bytes())
+            }
+            _ if !request.is_empty() => Err(Error::new(EINVAL)),
+            "health" => {
+                let mut page = [0; log::PAGE_SIZE];
+                let temperature_threshold = unsafe {
+                    nvme.get_log_page(NSID_GLOBAL, log::LOG_SMART_HEALTH, &mut page)?;
+                    nvme.get_features(FEATURE_TEMPERATURE_THRESHOLD, 0)? as u16
+                };
+                Ok(log::format_health(&page, temperature_threshold).into_bytes())
+            }
+            "errors" => {
+                let mut page = vec![0; nvme.error_log_entries() * log::ERROR_ENTRY_SIZE];
+                unsafe { nvme.get_log_page(NSID_GLOBAL, log::LOG_ERROR_INFORMATION, &mut page)?; }
+                Ok(log::format_errors(&page).into_bytes())
+            }
+            "firmware" => {
+                let mut page = [0; log::PAGE_SIZE];
+                unsafe { nvme.get_log_page(NSID_GLOBAL, log::LOG_FIRMWARE_SLOT, &mut page)?; }
+                Ok(log::format_firmware_slots(&page).into_bytes())
+            }
+            _ => Err(Error::new(ENOENT)),
+        }
+    }
+}
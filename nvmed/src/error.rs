@@ -0,0 +1,146 @@
+use std::fmt;
+
+use syscall::error::{Error, EIO};
+
+/// The type of a status code, which tells how the code is to be read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatusCodeType {
+    Generic,
+    CommandSpecific,
+    MediaAndDataIntegrity,
+    PathRelated,
+    VendorSpecific,
+    Reserved(u8),
+}
+
+/// The status of a command that the controller completed with an error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandStatus {
+    pub kind: StatusCodeType,
+    pub code: u8,
+    /// Whether the controller expects the command to fail again if it is retried.
+    pub do_not_retry: bool,
+}
+
+impl CommandStatus {
+    /// Decode the status field of a completion queue entry, which still includes the phase tag.
+    /// Returns `None` if the command succeeded.
+    pub fn parse(status: u16) -> Option<Self> {
+        let status = status >> 1;
+        if status & 0x7FF == 0 {
+            return None;
+        }
+
+        let kind = match (status >> 8) & 0b111 {
+            0 => StatusCodeType::Generic,
+            1 => StatusCodeType::CommandSpecific,
+            2 => StatusCodeType::MediaAndDataIntegrity,
+            3 => StatusCodeType::PathRelated,
+            7 => StatusCodeType::VendorSpecific,
+            sct => StatusCodeType::Reserved(sct as u8),
+        };
+        Some(Self {
+            kind,
+            code: status as u8,
+            do_not_retry: status & 1 << 14 != 0,
+        })
+    }
+
+    /// The name of the status in the specification, for the codes that an I/O or admin command
+    /// commonly fails with.
+    fn description(&self) -> Option<&'static str> {
+        Some(match (self.kind, self.code) {
+            (StatusCodeType::Generic, 0x01) => "Invalid Command Opcode",
+            (StatusCodeType::Generic, 0x02) => "Invalid Field in Command",
+            (StatusCodeType::Generic, 0x04) => "Data Transfer Error",
+            (StatusCodeType::Generic, 0x05) => "Commands Aborted due to Power Loss Notification",
+            (StatusCodeType::Generic, 0x06) => "Internal Error",
+            (StatusCodeType::Generic, 0x07) => "Command Abort Requested",
+            (StatusCodeType::Generic, 0x0B) => "Invalid Namespace or Format",
+            (StatusCodeType::Generic, 0x80) => "LBA Out of Range",
+            (StatusCodeType::Generic, 0x81) => "Capacity Exceeded",
+            (StatusCodeType::Generic, 0x82) => "Namespace Not Ready",
+            (StatusCodeType::CommandSpecific, 0x01) => "Invalid Queue Identifier",
+            (StatusCodeType::CommandSpecific, 0x02) => "Invalid Queue Size",
+            (StatusCodeType::CommandSpecific, 0x08) => "Invalid Interrupt Vector",
+            (StatusCodeType::CommandSpecific, 0x09) => "Invalid Log Page",
+            (StatusCodeType::CommandSpecific, 0x0D) => "Feature Identifier Not Saveable",
+            (StatusCodeType::MediaAndDataIntegrity, 0x80) => "Write Fault",
+            (StatusCodeType::MediaAndDataIntegrity, 0x81) => "Unrecovered Read Error",
+            (StatusCodeType::MediaAndDataIntegrity, 0x85) => "Compare Failure",
+            (StatusCodeType::MediaAndDataIntegrity, 0x86) => "Access Denied",
+            (StatusCodeType::MediaAndDataIntegrity, 0x87) => "Deallocated or Unwritten Logical Block",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.description() {
+            Some(description) => write!(f, "{} ({:?} {:#04X})", description, self.kind, self.code)?,
+            None => write!(f, "{:?} {:#04X}", self.kind, self.code)?,
+        }
+        if self.do_not_retry {
+            write!(f, ", do not retry")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a command failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NvmeError {
+    /// The controller completed the command with an error status.
+    Status(CommandStatus),
+    /// The command didn't complete in time, so the controller was reset.
+    Timeout,
+    /// The controller was reset while the command was running, and dropped it.
+    Reset,
+    /// The controller failed, and couldn't be brought back up.
+    ControllerFailed,
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NvmeError::Status(ref status) => write!(f, "{}", status),
+            NvmeError::Timeout => write!(f, "timed out"),
+            NvmeError::Reset => write!(f, "dropped by a controller reset"),
+            NvmeError::ControllerFailed => write!(f, "controller failed"),
+        }
+    }
+}
+
+/// Every failed command is an I/O error to the scheme.
+impl From<NvmeError> for Error {
+    fn from(_err: NvmeError) -> Self {
+        Error::new(EIO)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandStatus, StatusCodeType};
+
+    #[test]
+    fn test_parse_status() {
+        // Success, with either phase.
+        assert_eq!(CommandStatus::parse(0), None);
+        assert_eq!(CommandStatus::parse(1), None);
+
+        // LBA Out of Range, with the phase tag set.
+        let status = CommandStatus::parse((0x80 << 1) | 1).unwrap();
+        assert_eq!(status, CommandStatus { kind: StatusCodeType::Generic, code: 0x80, do_not_retry: false });
+        assert_eq!(status.to_string(), "LBA Out of Range (Generic 0x80)");
+
+        // Unrecovered Read Error, with Do Not Retry.
+        let status = CommandStatus::parse(((1 << 14) | (2 << 8) | 0x81) << 1).unwrap();
+        assert_eq!(status, CommandStatus { kind: StatusCodeType::MediaAndDataIntegrity, code: 0x81, do_not_retry: true });
+        assert_eq!(status.to_string(), "Unrecovered Read Error (MediaAndDataIntegrity 0x81), do not retry");
+
+        let status = CommandStatus::parse((5 << 8 | 0x42) << 1).unwrap();
+        assert_eq!(status.kind, StatusCodeType::Reserved(5));
+        assert_eq!(status.to_string(), "Reserved(5) 0x42");
+    }
+}
@@ -0,0 +1,160 @@
+use std::convert::TryInto;
+use std::fmt::Write;
+
+/// The log identifier of the Error Information log page, with one 64 byte entry per error.
+pub const LOG_ERROR_INFORMATION: u8 = 0x01;
+/// The log identifier of the SMART / Health Information log page.
+pub const LOG_SMART_HEALTH: u8 = 0x02;
+/// The log identifier of the Firmware Slot Information log page.
+pub const LOG_FIRMWARE_SLOT: u8 = 0x03;
+
+/// The size of an entry of the Error Information log page.
+pub const ERROR_ENTRY_SIZE: usize = 64;
+/// The size of the SMART / Health Information and Firmware Slot Information log pages.
+pub const PAGE_SIZE: usize = 512;
+
+fn u16_at(page: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(page[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(page: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap())
+}
+
+fn u128_at(page: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(page[offset..offset + 16].try_into().unwrap())
+}
+
+/// Format the SMART / Health Information log page as `key: value` lines, along with the current
+/// temperature threshold, in Kelvin.
+pub fn format_health(page: &[u8], temperature_threshold: u16) -> String {
+    let mut health = String::new();
+
+    writeln!(health, "critical_warning: {:#04X}", page[0]).unwrap();
+    writeln!(health, "temperature_kelvin: {}", u16_at(page, 1)).unwrap();
+    writeln!(health, "temperature_threshold_kelvin: {}", temperature_threshold).unwrap();
+    writeln!(health, "available_spare_percent: {}", page[3]).unwrap();
+    writeln!(health, "available_spare_threshold_percent: {}", page[4]).unwrap();
+    writeln!(health, "percentage_used: {}", page[5]).unwrap();
+
+    let counters = [
+        ("data_units_read", 32),
+        ("data_units_written", 48),
+        ("host_read_commands", 64),
+        ("host_write_commands", 80),
+        ("controller_busy_time_minutes", 96),
+        ("power_cycles", 112),
+        ("power_on_hours", 128),
+        ("unsafe_shutdowns", 144),
+        ("media_errors", 160),
+        ("error_log_entries", 176),
+    ];
+    for &(name, offset) in counters.iter() {
+        writeln!(health, "{}: {}", name, u128_at(page, offset)).unwrap();
+    }
+
+    health
+}
+
+/// Format the valid entries of the Error Information log page, one per line.
+pub fn format_errors(page: &[u8]) -> String {
+    let mut errors = String::new();
+
+    for entry in page.chunks(ERROR_ENTRY_SIZE) {
+        // Entries with an error count of zero are unused.
+        let error_count = u64_at(entry, 0);
+        if error_count == 0 {
+            continue;
+        }
+        writeln!(
+            errors,
+            "error_count={} sqid={} cid={:#X} status={:#X} lba={} nsid={}",
+            error_count,
+            u16_at(entry, 8),
+            u16_at(entry, 10),
+            // The status field follows the phase tag.
+            u16_at(entry, 12) >> 1,
+            u64_at(entry, 16),
+            u32_at(entry, 24),
+        ).unwrap();
+    }
+
+    errors
+}
+
+/// Format the Firmware Slot Information log page, with the revision in each slot that isn't
+/// empty.
+pub fn format_firmware_slots(page: &[u8]) -> String {
+    let mut firmware = String::new();
+
+    // Active firmware info: the active slot in bits 2:0, and the slot that is activated at the
+    // next reset in bits 6:4, if any.
+    let afi = page[0];
+    writeln!(firmware, "active_slot: {}", afi & 0x7).unwrap();
+    match (afi >> 4) & 0x7 {
+        0 => writeln!(firmware, "next_slot: none").unwrap(),
+        slot => writeln!(firmware, "next_slot: {}", slot).unwrap(),
+    }
+
+    for slot in 1..=7 {
+        let revision = &page[8 * slot..8 * slot + 8];
+        if revision.iter().all(|&b| b == 0) {
+            continue;
+        }
+        let revision = revision.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect::<String>();
+        writeln!(firmware, "slot{}: {}", slot, revision.trim()).unwrap();
+    }
+
+    firmware
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_errors, format_firmware_slots, format_health, ERROR_ENTRY_SIZE, PAGE_SIZE};
+
+    #[test]
+    fn test_format_health() {
+        let mut page = [0; PAGE_SIZE];
+        page[0] = 0x02;
+        page[1..3].copy_from_slice(&310u16.to_le_bytes());
+        page[3] = 100;
+        page[4] = 10;
+        page[5] = 3;
+        page[160] = 7;
+
+        let health = format_health(&page, 343);
+        assert!(health.starts_with("critical_warning: 0x02\ntemperature_kelvin: 310\ntemperature_threshold_kelvin: 343\n"));
+        assert!(health.contains("\npercentage_used: 3\n"));
+        assert!(health.contains("\nmedia_errors: 7\n"));
+        assert!(health.ends_with("\nerror_log_entries: 0\n"));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let mut page = [0; 4 * ERROR_ENTRY_SIZE];
+        let entry = &mut page[2 * ERROR_ENTRY_SIZE..3 * ERROR_ENTRY_SIZE];
+        entry[0] = 5;
+        entry[8] = 1;
+        entry[10] = 0x2A;
+        // LBA Out of Range, with the phase tag set.
+        entry[12..14].copy_from_slice(&((0x80 << 1) | 1u16).to_le_bytes());
+        entry[16] = 0x10;
+        entry[24] = 1;
+
+        assert_eq!(format_errors(&page), "error_count=5 sqid=1 cid=0x2A status=0x80 lba=16 nsid=1\n");
+    }
+
+    #[test]
+    fn test_format_firmware_slots() {
+        let mut page = [0; PAGE_SIZE];
+        page[0] = 0x21;
+        page[8..12].copy_from_slice(b"1.0 ");
+        page[16..24].copy_from_slice(b"2.0.1-rc");
+
+        assert_eq!(format_firmware_slots(&page), "active_slot: 1\nnext_slot: 2\nslot1: 1.0\nslot2: 2.0.1-rc\n");
+    }
+}
@@ -4,18 +4,72 @@ extern crate bitflags;
 extern crate spin;
 extern crate syscall;
 
-use std::{env, usize};
+use std::{cmp, env, usize};
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
-use std::os::unix::io::{RawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, RawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
 
-use syscall::{EVENT_READ, PHYSMAP_NO_CACHE, PHYSMAP_WRITE, Event, Packet, Result, SchemeBlockMut};
+use syscall::{CLOCK_MONOTONIC, EVENT_READ, PHYSMAP_NO_CACHE, PHYSMAP_WRITE, Event, Packet, Result, SchemeBlockMut, TimeSpec};
 
-use self::nvme::Nvme;
-use self::scheme::DiskScheme;
+use block_io_wrapper::DiskScheme;
+use pcid_interface::{InterruptKind, PcidServerHandle, PciFeature, PciFeatureInfo};
 
+use self::disk::NvmeDisk;
+use self::nvme::{InterruptMethod, Nvme, MAX_IO_QUEUES};
+
+mod disk;
+mod error;
+mod log;
 mod nvme;
-mod scheme;
+
+/// How often the controller is polled, to notice commands that timed out even when no interrupt
+/// arrives.
+const POLL_INTERVAL_SECS: i64 = 1;
+
+/// Make the timer fire again after `POLL_INTERVAL_SECS`.
+fn arm_timer(time_file: &mut File) {
+    let mut time = TimeSpec::default();
+    time_file.read(&mut time).expect("nvmed: failed to read time");
+    time.tv_sec += POLL_INTERVAL_SECS;
+    time_file.write(&time).expect("nvmed: failed to write time");
+}
+
+/// Allocate an MSI-X vector for the admin queue and each I/O queue pair that may be created, or
+/// else one MSI vector, through pcid. Returns `None` if neither is available, and the legacy
+/// interrupt has to be used.
+fn allocate_interrupts(pcid_handle: &mut PcidServerHandle) -> Option<(Vec<File>, InterruptMethod)> {
+    let features = match pcid_handle.fetch_all_features() {
+        Ok(features) => features,
+        Err(err) => {
+            println!("nvmed: failed to fetch PCI features: {}", err);
+            return None;
+        }
+    };
+
+    if features.iter().any(|(feature, _)| feature.is_msix()) {
+        match pcid_handle.feature_info(PciFeature::MsiX) {
+            Ok(PciFeatureInfo::MsiX(capability)) => {
+                let count = cmp::min(capability.table_size(), MAX_IO_QUEUES + 1);
+                match pcid_handle.allocate_interrupts(count, InterruptKind::MsiX) {
+                    Ok(irq_files) => return Some((irq_files, InterruptMethod::MsiX(count))),
+                    Err(err) => println!("nvmed: failed to allocate MSI-X vectors: {}", err),
+                }
+            }
+            Ok(other) => println!("nvmed: unexpected MSI-X capability {:?}", other),
+            Err(err) => println!("nvmed: failed to fetch the MSI-X capability: {}", err),
+        }
+    }
+
+    if features.iter().any(|(feature, _)| feature.is_msi()) {
+        match pcid_handle.allocate_interrupts(1, InterruptKind::Msi) {
+            Ok(irq_files) => return Some((irq_files, InterruptMethod::Msi)),
+            Err(err) => println!("nvmed: failed to allocate an MSI vector: {}", err),
+        }
+    }
+
+    None
+}
 
 fn main() {
     let mut args = env::args().skip(1);
@@ -45,16 +99,32 @@ fn main() {
                 .expect("nvmed: failed to open event queue");
             let mut event_file = unsafe { File::from_raw_fd(event_fd as RawFd) };
 
-            let irq_fd = syscall::open(
-                &format!("irq:{}", irq),
-                syscall::O_RDWR | syscall::O_NONBLOCK | syscall::O_CLOEXEC
-            ).expect("nvmed: failed to open irq file");
-            syscall::write(event_fd, &syscall::Event {
-                id: irq_fd,
-                flags: syscall::EVENT_READ,
-                data: 0,
-            }).expect("nvmed: failed to watch irq file events");
-            let mut irq_file = unsafe { File::from_raw_fd(irq_fd as RawFd) };
+            // The channel to pcid is kept open for as long as the driver runs.
+            let mut pcid_handle = match PcidServerHandle::connect_default() {
+                Ok(pcid_handle) => Some(pcid_handle),
+                Err(err) => {
+                    println!("nvmed: failed to connect to pcid, using the legacy interrupt: {}", err);
+                    None
+                }
+            };
+            let (mut irq_files, interrupt_method) = match pcid_handle.as_mut().and_then(allocate_interrupts) {
+                Some(interrupts) => interrupts,
+                None => {
+                    let irq_fd = syscall::open(
+                        &format!("irq:{}", irq),
+                        syscall::O_RDWR | syscall::O_NONBLOCK | syscall::O_CLOEXEC
+                    ).expect("nvmed: failed to open irq file");
+                    (vec![unsafe { File::from_raw_fd(irq_fd as RawFd) }], InterruptMethod::Intx)
+                }
+            };
+            // The event data of an interrupt is one more than its vector.
+            for (vector, irq_file) in irq_files.iter().enumerate() {
+                syscall::write(event_fd, &syscall::Event {
+                    id: irq_file.as_raw_fd() as usize,
+                    flags: syscall::EVENT_READ,
+                    data: vector + 1,
+                }).expect("nvmed: failed to watch irq file events");
+            }
 
             let scheme_name = format!("disk/{}", name);
             let socket_fd = syscall::open(
@@ -64,16 +134,30 @@ fn main() {
             syscall::write(event_fd, &syscall::Event {
                 id: socket_fd,
                 flags: syscall::EVENT_READ,
-                data: 1,
+                data: 0,
             }).expect("nvmed: failed to watch disk scheme events");
             let mut socket_file = unsafe { File::from_raw_fd(socket_fd as RawFd) };
 
+            // The event data of the timer follows those of the interrupts.
+            let timer_data = irq_files.len() + 1;
+            let time_fd = syscall::open(
+                &format!("time:{}", CLOCK_MONOTONIC),
+                syscall::O_RDWR | syscall::O_CLOEXEC
+            ).expect("nvmed: failed to open timer");
+            syscall::write(event_fd, &syscall::Event {
+                id: time_fd,
+                flags: syscall::EVENT_READ,
+                data: timer_data,
+            }).expect("nvmed: failed to watch timer events");
+            let mut time_file = unsafe { File::from_raw_fd(time_fd as RawFd) };
+
             syscall::setrens(0, 0).expect("nvmed: failed to enter null namespace");
 
-            let mut nvme = Nvme::new(address).expect("nvmed: failed to allocate driver data");
-            let namespaces = unsafe { nvme.init() };
-            let mut scheme = DiskScheme::new(scheme_name, nvme, namespaces);
+            let nvme = Arc::new(Mutex::new(Nvme::new(address, interrupt_method).expect("nvmed: failed to allocate driver data")));
+            let namespaces = unsafe { nvme.lock().unwrap().init() }.expect("nvmed: failed to initialize controller");
+            let mut scheme = DiskScheme::new(scheme_name, namespaces.into_iter().map(|(nsid, ns)| (nsid, NvmeDisk::new(Arc::clone(&nvme), ns))).collect());
             let mut todo = Vec::new();
+            arm_timer(&mut time_file);
             'events: loop {
                 let mut event = Event::default();
                 if event_file.read(&mut event).expect("nvmed: failed to read event queue") == 0 {
@@ -81,15 +165,7 @@ fn main() {
                 }
 
                 match event.data {
-                    0 => {
-                        let mut irq = [0; 8];
-                        if irq_file.read(&mut irq).expect("nvmed: failed to read irq file") >= irq.len() {
-                            if scheme.irq() {
-                                irq_file.write(&irq).expect("nvmed: failed to write irq file");
-                            }
-                        }
-                    },
-                    1 => loop {
+                    0 => loop {
                         let mut packet = Packet::default();
                         match socket_file.read(&mut packet) {
                             Ok(0) => break 'events,
@@ -101,6 +177,22 @@ fn main() {
                         }
                         todo.push(packet);
                     },
+                    data if data <= irq_files.len() => {
+                        let vector = data - 1;
+                        let irq_file = &mut irq_files[vector];
+                        let mut irq = [0; 8];
+                        if irq_file.read(&mut irq).expect("nvmed: failed to read irq file") >= irq.len() {
+                            // A legacy interrupt may be shared with other functions, but an MSI or
+                            // MSI-X vector is only ever ours.
+                            if nvme.lock().unwrap().irq(vector as u16) || interrupt_method != InterruptMethod::Intx {
+                                irq_file.write(&irq).expect("nvmed: failed to write irq file");
+                            }
+                        }
+                    },
+                    data if data == timer_data => {
+                        nvme.lock().unwrap().poll();
+                        arm_timer(&mut time_file);
+                    },
                     unknown => {
                         panic!("nvmed: unknown event data {}", unknown);
                     },
@@ -1,7 +1,11 @@
-use std::{ptr, thread};
+use std::{cmp, mem, ptr, slice, str};
 use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
 use syscall::io::{Dma, Io, Mmio};
-use syscall::error::{Error, Result, EINVAL};
+use syscall::error::{Error, Result, EINVAL, EIO};
+
+use crate::error::{CommandStatus, NvmeError};
 
 #[derive(Clone, Copy)]
 #[repr(packed)]
@@ -35,7 +39,7 @@ pub struct NvmeCmd {
 }
 
 impl NvmeCmd {
-    pub fn create_io_completion_queue(cid: u16, qid: u16, ptr: usize, size: u16) -> Self {
+    pub fn create_io_completion_queue(cid: u16, qid: u16, ptr: usize, size: u16, vector: u16) -> Self {
         Self {
             opcode: 5,
             flags: 0,
@@ -45,7 +49,7 @@ impl NvmeCmd {
             mptr: 0,
             dptr: [ptr as u64, 0],
             cdw10: ((size as u32) << 16) | (qid as u32),
-            cdw11: 1 /* Physically Contiguous */, //TODO: IV, IEN
+            cdw11: ((vector as u32) << 16) | 1 << 1 /* Interrupts Enabled */ | 1 /* Physically Contiguous */,
             cdw12: 0,
             cdw13: 0,
             cdw14: 0,
@@ -125,10 +129,118 @@ impl NvmeCmd {
         }
     }
 
-    pub fn io_read(cid: u16, nsid: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64) -> Self {
+    pub fn get_log_page(cid: u16, nsid: u32, lid: u8, numd: u32, ptr0: u64, ptr1: u64) -> Self {
+        Self {
+            opcode: 2,
+            flags: 0,
+            cid: cid,
+            nsid: nsid,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [ptr0, ptr1],
+            cdw10: ((numd & 0xFFFF) << 16) | lid as u32,
+            cdw11: numd >> 16,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn get_features(cid: u16, fid: u8, cdw11: u32) -> Self {
+        Self {
+            opcode: 0xA,
+            flags: 0,
+            cid: cid,
+            nsid: 0,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [0, 0],
+            cdw10: fid as u32,
+            cdw11: cdw11,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn set_features(cid: u16, fid: u8, cdw11: u32) -> Self {
+        Self {
+            opcode: 9,
+            flags: 0,
+            cid: cid,
+            nsid: 0,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [0, 0],
+            cdw10: fid as u32,
+            cdw11: cdw11,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn io_flush(cid: u16, nsid: u32) -> Self {
+        Self {
+            opcode: 0,
+            flags: 0,
+            cid: cid,
+            nsid: nsid,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [0, 0],
+            cdw10: 0,
+            cdw11: 0,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn io_write_zeroes(cid: u16, nsid: u32, lba: u64, blocks_1: u16) -> Self {
+        Self {
+            opcode: 8,
+            flags: 0,
+            cid: cid,
+            nsid: nsid,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [0, 0],
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: blocks_1 as u32,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn io_dataset_management(cid: u16, nsid: u32, ranges_1: u8, ptr0: u64) -> Self {
+        Self {
+            opcode: 9,
+            flags: 0,
+            cid: cid,
+            nsid: nsid,
+            _rsvd: 0,
+            mptr: 0,
+            dptr: [ptr0, 0],
+            cdw10: ranges_1 as u32,
+            cdw11: 1 << 2 /* Attribute - Deallocate */,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+
+    pub fn io_read(cid: u16, nsid: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64, sgl: bool) -> Self {
         Self {
             opcode: 2,
-            flags: 1 << 6,
+            flags: if sgl { 1 << 6 /* SGL, with a contiguous metadata buffer */ } else { 0 /* PRP */ },
             cid: cid,
             nsid: nsid,
             _rsvd: 0,
@@ -143,10 +255,10 @@ impl NvmeCmd {
         }
     }
 
-    pub fn io_write(cid: u16, nsid: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64) -> Self {
+    pub fn io_write(cid: u16, nsid: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64, sgl: bool) -> Self {
         Self {
             opcode: 1,
-            flags: 1 << 6,
+            flags: if sgl { 1 << 6 /* SGL, with a contiguous metadata buffer */ } else { 0 /* PRP */ },
             cid: cid,
             nsid: nsid,
             _rsvd: 0,
@@ -221,6 +333,11 @@ impl NvmeCmdQueue {
         self.i = (self.i + 1) % self.data.len();
         self.i
     }
+
+    /// Start from the first entry again, as the controller does after a reset.
+    fn reset(&mut self) {
+        self.i = 0;
+    }
 }
 
 pub struct NvmeCompQueue {
@@ -254,15 +371,272 @@ impl NvmeCompQueue {
         }
     }
 
-    fn complete_spin(&mut self) -> (usize, NvmeComp) {
+    /// Spin until an entry completes, or return `None` once `timeout` has passed.
+    fn complete_spin(&mut self, timeout: Duration) -> Option<(usize, NvmeComp)> {
+        let start = Instant::now();
         loop {
             if let Some(some) = self.complete() {
-                return some;
+                return Some(some);
+            } else if start.elapsed() >= timeout {
+                return None;
             } else {
                 unsafe { asm!("pause"); }
             }
         }
     }
+
+    /// Clear the entries and start from the first one again, as the controller does after a
+    /// reset.
+    fn reset(&mut self) {
+        unsafe {
+            ptr::write_bytes(self.data.as_mut_ptr(), 0, self.data.len());
+        }
+        self.i = 0;
+        self.phase = true;
+    }
+}
+
+/// The largest number of I/O queue pairs that are created, if the controller and the interrupt
+/// vectors allow that many.
+pub const MAX_IO_QUEUES: u16 = 8;
+
+/// The number of bytes that one I/O command transfers at most, unless the controller has a lower
+/// limit.
+pub const MAX_TRANSFER: usize = 128 * PAGE_SIZE;
+
+/// The memory page size, which is also the granularity of PRP entries.
+const PAGE_SIZE: usize = 4096;
+
+/// The SGL descriptor type of a last segment descriptor, in the upper byte of its second half.
+const SGL_LAST_SEGMENT: u64 = 0x30 << 56;
+
+/// How long an I/O command may run before the controller is reset.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long an admin command may run before the controller is reset.
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The Ready bit of the Controller Status register.
+const CSTS_RDY: u32 = 1 << 0;
+
+/// The Controller Fatal Status bit of the Controller Status register.
+const CSTS_CFS: u32 = 1 << 1;
+
+/// The feature identifier of the Power Management feature.
+const FEATURE_POWER_MANAGEMENT: u8 = 0x02;
+
+/// The feature identifier of the Temperature Threshold feature.
+pub const FEATURE_TEMPERATURE_THRESHOLD: u8 = 0x04;
+
+/// The feature identifier of the Error Recovery feature.
+const FEATURE_ERROR_RECOVERY: u8 = 0x05;
+
+/// The features that may be set through the features file, by name, along with the bits of
+/// dword 11 that hold their value. None of them changes how the driver uses the controller; the
+/// other bits are written as zero, which selects the composite over temperature threshold.
+pub const SETTABLE_FEATURES: [(&str, u8, u32); 3] = [
+    ("power_state", FEATURE_POWER_MANAGEMENT, 0x1F),
+    ("temperature_threshold_kelvin", FEATURE_TEMPERATURE_THRESHOLD, 0xFFFF),
+    ("error_recovery_time_100ms", FEATURE_ERROR_RECOVERY, 0xFFFF),
+];
+
+/// The feature identifier of the Number of Queues feature.
+const FEATURE_NUMBER_OF_QUEUES: u8 = 0x07;
+
+/// The namespace identifier that refers to the controller as a whole, in log pages and features.
+pub const NSID_GLOBAL: u32 = 0xFFFF_FFFF;
+
+/// The number of bytes that one admin command transfers at most.
+const ADMIN_BUFFER_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The size of the submission queue entry and the length of the data transferred to the host, which
+/// start a request to `Nvme::admin_passthrough`.
+const ADMIN_REQUEST_HEADER_SIZE: usize = 64 + 4;
+
+/// How the controller signals completions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptMethod {
+    /// A legacy INTx# pin, which may be shared with other functions.
+    Intx,
+    /// A single MSI message.
+    Msi,
+    /// MSI-X, with the number of vectors that were allocated.
+    MsiX(u16),
+}
+
+/// The data pointer of a command transferring `len` bytes at the virtual `address`, as PRP entries.
+/// When the data touches more than two pages, the second entry points to a PRP list, which is
+/// built in `list`, at `list_physical`. `translate` finds the physical address of a virtual one.
+fn build_prps<T>(address: usize, len: usize, list: &mut [u64], list_physical: usize, mut translate: T) -> Result<[u64; 2]>
+    where T: FnMut(usize) -> Result<usize>
+{
+    let first = translate(address)? as u64;
+    let next_page = (address & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    let end = address + len;
+    if end <= next_page {
+        return Ok([first, 0]);
+    }
+
+    let pages = (end - next_page + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages == 1 {
+        return Ok([first, translate(next_page)? as u64]);
+    }
+
+    assert!(pages <= list.len());
+    for (i, entry) in list.iter_mut().take(pages).enumerate() {
+        *entry = translate(next_page + i * PAGE_SIZE)? as u64;
+    }
+    Ok([first, list_physical as u64])
+}
+
+/// The data pointer of a command transferring `len` bytes at the virtual `address`, as an SGL
+/// descriptor. That is a data block descriptor when the data is physically contiguous, or else a
+/// last segment descriptor of the data block descriptors built in `list`, two entries each.
+fn build_sgl<T>(address: usize, len: usize, list: &mut [u64], list_physical: usize, mut translate: T) -> Result<[u64; 2]>
+    where T: FnMut(usize) -> Result<usize>
+{
+    let mut count = 0;
+    let mut offset = 0;
+    while offset < len {
+        let virt = address + offset;
+        let chunk = cmp::min(len - offset, PAGE_SIZE - virt % PAGE_SIZE);
+        let phys = translate(virt)? as u64;
+
+        // Pages that are physically contiguous share a descriptor.
+        if count > 0 && list[2 * count - 2] + list[2 * count - 1] == phys {
+            list[2 * count - 1] += chunk as u64;
+        } else {
+            assert!(2 * count + 1 < list.len());
+            list[2 * count] = phys;
+            list[2 * count + 1] = chunk as u64;
+            count += 1;
+        }
+        offset += chunk;
+    }
+
+    if count == 1 {
+        Ok([list[0], list[1]])
+    } else {
+        Ok([list_physical as u64, (count * 16) as u64 | SGL_LAST_SEGMENT])
+    }
+}
+
+/// Whether an admin command may be run through the admin passthrough file.
+fn passthrough_allowed(opcode: u8) -> bool {
+    match opcode {
+        // Get Log Page, Identify, Get Features
+        0x02 | 0x06 | 0x0A => true,
+        // Vendor specific commands that transfer data to the host
+        0xC0..=0xFF => opcode & 0b11 == 0b10,
+        _ => false,
+    }
+}
+
+/// Parse a `name: value` line written to the features file, into the feature identifier and
+/// dword 11 to set it with.
+pub fn parse_feature_request(request: &[u8]) -> Result<(u8, u32)> {
+    let request = str::from_utf8(request).or(Err(Error::new(EINVAL)))?;
+    let mut parts = request.trim().splitn(2, ':');
+    let (name, value) = match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) => (name.trim(), value.trim()),
+        _ => return Err(Error::new(EINVAL)),
+    };
+
+    let &(_, fid, mask) = SETTABLE_FEATURES.iter().find(|&&(feature, _, _)| feature == name).ok_or(Error::new(EINVAL))?;
+    match value.parse::<u32>() {
+        Ok(value) if value & !mask == 0 => Ok((fid, value)),
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+/// Dword 0 of the completion of an admin command, if it succeeded.
+fn admin_result(entry: &NvmeComp) -> Result<u32> {
+    match CommandStatus::parse(entry.status) {
+        None => Ok(entry.command_specific),
+        Some(status) => {
+            println!("nvmed: admin command {} failed: {}", { entry.cid }, status);
+            Err(NvmeError::Status(status).into())
+        }
+    }
+}
+
+/// A command that was submitted to an I/O queue pair, and hasn't been finished yet.
+struct IoCommand {
+    /// The PRP list or the SGL segment of the command.
+    list: Dma<[u64; 512]>,
+    /// The buffer that the data is copied through, if the caller's buffer isn't aligned as the
+    /// controller requires.
+    bounce: Option<Dma<[u8; MAX_TRANSFER]>>,
+    /// When the command is considered to have timed out.
+    deadline: Instant,
+    /// The result of the command, once it has completed, or was dropped by a reset.
+    status: Option<::std::result::Result<(), NvmeError>>,
+}
+
+/// An I/O submission queue and the completion queue that it completes to, with the commands
+/// submitted to it by command ID.
+struct IoQueuePair {
+    qid: u16,
+    /// The interrupt vector of the completion queue.
+    vector: u16,
+    submission_queue: NvmeCmdQueue,
+    completion_queue: NvmeCompQueue,
+    commands: BTreeMap<u16, IoCommand>,
+    /// The number of commands that haven't completed. Completed commands don't take up space in
+    /// either queue, so they may be kept until they are finished.
+    running: usize,
+    next_cid: u16,
+    /// Whether completions were consumed by polling, since the last interrupt was handled.
+    polled: bool,
+}
+
+impl IoQueuePair {
+    fn new(qid: u16, vector: u16) -> Result<Self> {
+        Ok(Self {
+            qid,
+            vector,
+            submission_queue: NvmeCmdQueue::new()?,
+            completion_queue: NvmeCompQueue::new()?,
+            commands: BTreeMap::new(),
+            running: 0,
+            next_cid: 0,
+            polled: false,
+        })
+    }
+
+    /// Whether another command can be submitted. One submission queue entry is always left empty,
+    /// so that a full queue can be told apart from an empty one.
+    fn is_full(&self) -> bool {
+        self.running >= self.submission_queue.data.len() - 1
+    }
+
+    fn allocate_cid(&mut self) -> u16 {
+        while self.commands.contains_key(&self.next_cid) {
+            self.next_cid = self.next_cid.wrapping_add(1);
+        }
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        cid
+    }
+}
+
+/// An I/O command, by the index of its queue pair and its command ID.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct NvmeCommandId {
+    queue: usize,
+    cid: u16,
+}
+
+/// Why a namespace isn't served.
+#[derive(Debug, PartialEq)]
+pub enum UnsupportedFormat {
+    /// FLBAS selects an LBA format beyond the number of formats.
+    InvalidFormat(u8),
+    /// The LBA format has metadata, of a size in bytes, which would either be interleaved with
+    /// the data, or be written to a metadata pointer that the driver doesn't set.
+    Metadata(u16),
+    /// The data size of the LBA format, as a power of two, is too small or too large.
+    BlockSize(u8),
 }
 
 pub struct NvmeNamespace {
@@ -271,22 +645,94 @@ pub struct NvmeNamespace {
     pub block_size: u64,
 }
 
+impl NvmeNamespace {
+    /// Parse the Identify Namespace data structure, refusing LBA formats that can't be served
+    /// without corrupting data.
+    pub fn parse(id: u32, data: &[u8]) -> ::std::result::Result<Self, UnsupportedFormat> {
+        let size = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        // Number of LBA formats, zero-based
+        let nlbaf = data[25];
+        // Formatted LBA size: the lower bits of the format index are in bits 3:0, and the upper
+        // bits in 6:5.
+        let flbas = data[26];
+        let format = (flbas & 0xF) | ((flbas >> 5) & 0x3) << 4;
+        if format > nlbaf {
+            return Err(UnsupportedFormat::InvalidFormat(format));
+        }
+
+        let offset = 128 + 4 * format as usize;
+        let lbaf = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let metadata_size = lbaf as u16;
+        let lbads = (lbaf >> 16) as u8;
+
+        if metadata_size != 0 {
+            return Err(UnsupportedFormat::Metadata(metadata_size));
+        }
+        if lbads < 9 || u32::from(lbads) > MAX_TRANSFER.trailing_zeros() {
+            return Err(UnsupportedFormat::BlockSize(lbads));
+        }
+
+        Ok(Self {
+            id,
+            blocks: size,
+            block_size: 1 << lbads,
+        })
+    }
+}
+
 pub struct Nvme {
     regs: &'static mut NvmeRegs,
-    submission_queues: [NvmeCmdQueue; 2],
-    pub (crate) completion_queues: [NvmeCompQueue; 2],
-    buffer: Dma<[u8; 512 * 4096]>, // 2MB of buffer
-    buffer_prp: Dma<[u64; 512]>, // 4KB of PRP for the buffer
+    interrupt_method: InterruptMethod,
+    admin_submission_queue: NvmeCmdQueue,
+    admin_completion_queue: NvmeCompQueue,
+    io_queues: Vec<IoQueuePair>,
+    /// The number of bytes that one I/O command transfers at most.
+    max_transfer: usize,
+    /// The alignment of the data that SGLs require, if the controller supports them.
+    sgl_alignment: Option<usize>,
+    /// The PRP lists and SGL segments of finished commands, kept for reuse.
+    free_lists: Vec<Dma<[u64; 512]>>,
+    /// The bounce buffers of finished commands, kept for reuse.
+    free_bounce_buffers: Vec<Dma<[u8; MAX_TRANSFER]>>,
+    /// The data of admin commands, with a PRP list of its pages.
+    admin_buffer: Dma<[u8; ADMIN_BUFFER_SIZE]>,
+    admin_list: Dma<[u64; 512]>,
+    /// Whether admin completions were consumed while spinning, since the last interrupt was
+    /// handled.
+    admin_polled: bool,
+    /// The number of entries of the Error Information log page.
+    error_log_entries: usize,
+    /// Whether the controller is being reset, so that a failing admin command doesn't reset it
+    /// again.
+    resetting: bool,
+    /// Whether the controller failed, and couldn't be reset. Every command fails from then on.
+    failed: bool,
+    /// Whether the controller has a volatile write cache, which Flush writes back.
+    volatile_write_cache: bool,
+    /// Optional NVM command support
+    oncs: u16,
 }
 
 impl Nvme {
-    pub fn new(address: usize) -> Result<Self> {
+    pub fn new(address: usize, interrupt_method: InterruptMethod) -> Result<Self> {
         Ok(Nvme {
             regs: unsafe { &mut *(address as *mut NvmeRegs) },
-            submission_queues: [NvmeCmdQueue::new()?, NvmeCmdQueue::new()?],
-            completion_queues: [NvmeCompQueue::new()?, NvmeCompQueue::new()?],
-            buffer: Dma::zeroed()?,
-            buffer_prp: Dma::zeroed()?,
+            interrupt_method,
+            admin_submission_queue: NvmeCmdQueue::new()?,
+            admin_completion_queue: NvmeCompQueue::new()?,
+            io_queues: Vec::new(),
+            max_transfer: MAX_TRANSFER,
+            sgl_alignment: None,
+            free_lists: Vec::new(),
+            free_bounce_buffers: Vec::new(),
+            admin_buffer: Dma::zeroed()?,
+            admin_list: Dma::zeroed()?,
+            admin_polled: false,
+            error_log_entries: 1,
+            resetting: false,
+            failed: false,
+            volatile_write_cache: false,
+            oncs: 0,
         })
     }
 
@@ -306,46 +752,585 @@ impl Nvme {
         self.doorbell(2 * (qid as usize) + 1).write(head as u32)
     }
 
-    pub unsafe fn init(&mut self) -> BTreeMap<u32, NvmeNamespace> {
-        for i in 0..self.buffer_prp.len() {
-            self.buffer_prp[i] = (self.buffer.physical() + i * 4096) as u64;
+    /// Submit an admin command built from its command ID, and spin until it completes. If it
+    /// doesn't complete in time, the controller is reset.
+    unsafe fn admin_command_spin<F: FnOnce(u16) -> NvmeCmd>(&mut self, f: F) -> Result<NvmeComp> {
+        if self.failed {
+            return Err(NvmeError::ControllerFailed.into());
         }
 
-        // println!("  - CAPS: {:X}", self.regs.cap.read());
-        // println!("  - VS: {:X}", self.regs.vs.read());
-        // println!("  - CC: {:X}", self.regs.cc.read());
-        // println!("  - CSTS: {:X}", self.regs.csts.read());
+        let cid = self.admin_submission_queue.i as u16;
+        let tail = self.admin_submission_queue.submit(f(cid));
+        self.submission_queue_tail(0, tail as u16);
 
-        // println!("  - Disable");
-        self.regs.cc.writef(1, false);
+        match self.admin_completion_queue.complete_spin(ADMIN_TIMEOUT) {
+            Some((head, entry)) => {
+                self.completion_queue_head(0, head as u16);
+                self.admin_polled = true;
+                Ok(entry)
+            }
+            None => {
+                println!("nvmed: admin command {} timed out", cid);
+                // A reset that times out fails instead.
+                if !self.resetting {
+                    self.reset();
+                }
+                Err(NvmeError::Timeout.into())
+            }
+        }
+    }
 
-        // println!("  - Waiting for not ready");
+    /// Submit an admin command built from its command ID and data pointer, and spin until it
+    /// completes. The command transfers `data_out` to the controller, or up to `data_in.len()`
+    /// bytes from it.
+    unsafe fn admin_data_command<F>(&mut self, data_out: &[u8], data_in: &mut [u8], f: F) -> Result<NvmeComp>
+        where F: FnOnce(u16, u64, u64) -> NvmeCmd
+    {
+        let len = cmp::max(data_out.len(), data_in.len());
+        if len > ADMIN_BUFFER_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        self.admin_buffer[..data_out.len()].copy_from_slice(data_out);
+
+        let [ptr0, ptr1] = if len == 0 {
+            [0, 0]
+        } else {
+            // The admin buffer is physically contiguous.
+            let (virt, phys) = (self.admin_buffer.as_ptr() as usize, self.admin_buffer.physical());
+            let list_physical = self.admin_list.physical();
+            build_prps(virt, len, &mut self.admin_list[..], list_physical, |address| Ok(phys + (address - virt)))?
+        };
+        let entry = self.admin_command_spin(|cid| f(cid, ptr0, ptr1))?;
+
+        data_in.copy_from_slice(&self.admin_buffer[..data_in.len()]);
+        Ok(entry)
+    }
+
+    /// Read a log page into `data`, which is a whole number of dwords.
+    pub unsafe fn get_log_page(&mut self, nsid: u32, lid: u8, data: &mut [u8]) -> Result<()> {
+        if data.is_empty() || data.len() % 4 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let numd = (data.len() / 4 - 1) as u32;
+        let entry = self.admin_data_command(&[], data, |cid, ptr0, ptr1| NvmeCmd::get_log_page(
+            cid, nsid, lid, numd, ptr0, ptr1
+        ))?;
+        admin_result(&entry).map(|_| ())
+    }
+
+    /// Get the current value of a feature, returning dword 0 of the completion.
+    pub unsafe fn get_features(&mut self, fid: u8, cdw11: u32) -> Result<u32> {
+        let entry = self.admin_command_spin(|cid| NvmeCmd::get_features(cid, fid, cdw11))?;
+        admin_result(&entry)
+    }
+
+    /// Set a feature, returning dword 0 of the completion.
+    pub unsafe fn set_features(&mut self, fid: u8, cdw11: u32) -> Result<u32> {
+        let entry = self.admin_command_spin(|cid| NvmeCmd::set_features(cid, fid, cdw11))?;
+        admin_result(&entry)
+    }
+
+    /// The number of entries of the Error Information log page.
+    pub fn error_log_entries(&self) -> usize {
+        self.error_log_entries
+    }
+
+    /// Run an admin command on behalf of the admin passthrough file. The `request` starts with a
+    /// 64 byte submission queue entry, and the number of bytes that the command transfers to the
+    /// host, as a 32-bit little endian integer. It ends with the data that the command transfers
+    /// to the controller. The command ID and the data pointer of the entry are filled in. Only
+    /// commands that read from the controller are allowed: Identify, Get Log Page, Get Features,
+    /// and vendor specific commands that transfer data to the host. Commands that never complete,
+    /// or that change the queues, namespaces or formats under the driver, are refused. The features
+    /// that are safe to change are set through the features file instead.
+    ///
+    /// The response is the 16 byte completion queue entry, followed by the data that the command
+    /// transferred to the host.
+    pub unsafe fn admin_passthrough(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if request.len() < ADMIN_REQUEST_HEADER_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let mut command = ptr::read_unaligned(request.as_ptr() as *const NvmeCmd);
+        let data_in_len = u32::from_le_bytes(request[64..68].try_into().unwrap()) as usize;
+        let data_out = &request[ADMIN_REQUEST_HEADER_SIZE..];
+
+        let opcode = command.opcode;
+        if !passthrough_allowed(opcode) || command.mptr != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        // The data transfer direction is in the lowest bits of the opcode.
+        match opcode & 0b11 {
+            0b00 if data_out.is_empty() && data_in_len == 0 => (),
+            0b01 if data_in_len == 0 => (),
+            0b10 if data_out.is_empty() => (),
+            _ => return Err(Error::new(EINVAL)),
+        }
+
+        let mut data_in = vec![0; data_in_len];
+        let entry = self.admin_data_command(data_out, &mut data_in, |cid, ptr0, ptr1| {
+            command.cid = cid;
+            command.flags = 0;
+            command.dptr = [ptr0, ptr1];
+            command
+        })?;
+
+        let mut response = mem::transmute::<NvmeComp, [u8; 16]>(entry).to_vec();
+        response.extend_from_slice(&data_in);
+        Ok(response)
+    }
+
+    /// Consume the completions of an I/O queue pair, returning whether there were any.
+    fn reap(&mut self, queue: usize) -> bool {
+        let mut head_opt = None;
+        {
+            let pair = &mut self.io_queues[queue];
+            while let Some((head, entry)) = pair.completion_queue.complete() {
+                head_opt = Some(head);
+                let cid = entry.cid;
+                match pair.commands.get_mut(&cid) {
+                    Some(command) if command.status.is_none() => {
+                        command.status = Some(match CommandStatus::parse(entry.status) {
+                            None => Ok(()),
+                            Some(status) => Err(NvmeError::Status(status)),
+                        });
+                        pair.running -= 1;
+                    }
+                    _ => println!("nvmed: unexpected completion {:?}", entry),
+                }
+            }
+        }
+
+        match head_opt {
+            Some(head) => {
+                let qid = self.io_queues[queue].qid;
+                unsafe { self.completion_queue_head(qid, head as u16); }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Consume the pending completions of every I/O queue, for requests that can't wait for an
+    /// interrupt. This is also called periodically, to notice commands that timed out.
+    pub fn poll(&mut self) {
+        for queue in 0..self.io_queues.len() {
+            if self.reap(queue) {
+                self.io_queues[queue].polled = true;
+            }
+        }
+        self.check_health();
+    }
+
+    /// Reset the controller if it reports a fatal error, or a command has timed out.
+    fn check_health(&mut self) {
+        if self.failed {
+            return;
+        }
+
+        let csts = self.regs.csts.read();
+        let now = Instant::now();
+        if csts == 0xFFFF_FFFF {
+            // Reads return all ones once the device is gone.
+            println!("nvmed: controller is not responding");
+            self.failed = true;
+            self.drop_commands();
+        } else if csts & CSTS_CFS == CSTS_CFS {
+            println!("nvmed: controller fatal status, resetting");
+            unsafe { self.reset(); }
+        } else if self.io_queues.iter().any(|pair| pair.commands.values().any(|command| command.status.is_none() && now >= command.deadline)) {
+            println!("nvmed: command timed out, resetting");
+            unsafe { self.reset(); }
+        }
+    }
+
+    /// Fail every command that is running, once the controller has dropped them, and empty the
+    /// queues. Commands that ran out of time fail with a timeout.
+    fn drop_commands(&mut self) {
+        let now = Instant::now();
+        for pair in self.io_queues.iter_mut() {
+            for command in pair.commands.values_mut().filter(|command| command.status.is_none()) {
+                command.status = Some(Err(if now >= command.deadline { NvmeError::Timeout } else { NvmeError::Reset }));
+            }
+            pair.running = 0;
+            pair.submission_queue.reset();
+            pair.completion_queue.reset();
+        }
+    }
+
+    /// Reset the controller after a fatal error or a timeout, and bring it back up with the same
+    /// I/O queue pairs. The commands that were running are failed. If the controller doesn't come
+    /// back, it is considered failed.
+    unsafe fn reset(&mut self) {
+        self.resetting = true;
+
+        let result = self.disable().and_then(|()| {
+            self.drop_commands();
+            self.enable()
+        }).and_then(|()| self.recreate_io_queues());
+
+        self.resetting = false;
+        match result {
+            Ok(()) => {
+                println!("nvmed: controller was reset");
+                if !self.uses_msix() {
+                    self.regs.intmc.write(1);
+                }
+            }
+            Err(_) => {
+                println!("nvmed: failed to reset the controller");
+                self.failed = true;
+                self.drop_commands();
+            }
+        }
+    }
+
+    /// Consume the pending completions of the queues that interrupt with `vector`, returning
+    /// whether the interrupt was caused by this controller. That includes completions that were
+    /// already consumed by `poll`, since their interrupt may have arrived in the meantime.
+    pub fn irq(&mut self, vector: u16) -> bool {
+        // Admin completions are always consumed while spinning, and interrupt with the first
+        // vector.
+        let mut found_completion = vector == 0 && mem::replace(&mut self.admin_polled, false);
+
+        for queue in 0..self.io_queues.len() {
+            if self.io_queues[queue].vector == vector {
+                let polled = mem::replace(&mut self.io_queues[queue].polled, false);
+                found_completion |= self.reap(queue) || polled;
+            }
+        }
+        self.check_health();
+
+        found_completion
+    }
+
+    /// The number of bytes that one I/O command transfers at most.
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    /// Submit a command reading `blocks` blocks into `data`, or return `None` if every I/O queue
+    /// is full. The controller may write to `data` until the command is finished, so it must stay
+    /// mapped until then.
+    pub unsafe fn submit_read(&mut self, nsid: u32, lba: u64, blocks: usize, data: &mut [u8]) -> Result<Option<NvmeCommandId>> {
+        self.submit_rw(nsid, lba, blocks, data.as_mut_ptr() as usize, data.len(), false)
+    }
+
+    /// Submit a command writing `blocks` blocks from `data`, or return `None` if every I/O queue
+    /// is full. The controller may read `data` until the command is finished, so it must stay
+    /// mapped and unchanged until then.
+    pub unsafe fn submit_write(&mut self, nsid: u32, lba: u64, blocks: usize, data: &[u8]) -> Result<Option<NvmeCommandId>> {
+        self.submit_rw(nsid, lba, blocks, data.as_ptr() as usize, data.len(), true)
+    }
+
+    /// Whether the controller has a volatile write cache, so that writes are only durable after a
+    /// Flush.
+    pub fn has_volatile_write_cache(&self) -> bool {
+        self.volatile_write_cache
+    }
+
+    /// Whether the controller supports Dataset Management, for deallocating blocks.
+    pub fn supports_dataset_management(&self) -> bool {
+        self.oncs & 1 << 2 != 0
+    }
+
+    /// Whether the controller supports Write Zeroes.
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.oncs & 1 << 3 != 0
+    }
+
+    /// Submit a command flushing the volatile write cache, or return `None` if every I/O queue is
+    /// full.
+    pub unsafe fn submit_flush(&mut self, nsid: u32) -> Result<Option<NvmeCommandId>> {
+        let queue = match self.queue_with_room()? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        let list = self.take_list()?;
+        Ok(Some(self.push_command(queue, list, None, |cid| NvmeCmd::io_flush(cid, nsid))))
+    }
+
+    /// Submit a command writing zeroes to `blocks` blocks, or return `None` if every I/O queue is
+    /// full.
+    pub unsafe fn submit_write_zeroes(&mut self, nsid: u32, lba: u64, blocks: usize) -> Result<Option<NvmeCommandId>> {
+        assert!(blocks > 0);
+        assert!(blocks <= 0x1_0000);
+
+        let queue = match self.queue_with_room()? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        let list = self.take_list()?;
+        let blocks_1 = (blocks - 1) as u16;
+        Ok(Some(self.push_command(queue, list, None, |cid| NvmeCmd::io_write_zeroes(cid, nsid, lba, blocks_1))))
+    }
+
+    /// Submit a Dataset Management command deallocating `blocks` blocks, or return `None` if every
+    /// I/O queue is full.
+    pub unsafe fn submit_deallocate(&mut self, nsid: u32, lba: u64, blocks: u32) -> Result<Option<NvmeCommandId>> {
+        assert!(blocks > 0);
+
+        let queue = match self.queue_with_room()? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        // The range is built in the list of the command: the context attributes and the number of
+        // blocks, followed by the starting LBA.
+        let mut list = self.take_list()?;
+        list[0] = u64::from(blocks) << 32;
+        list[1] = lba;
+        let ptr0 = list.physical() as u64;
+        Ok(Some(self.push_command(queue, list, None, |cid| NvmeCmd::io_dataset_management(cid, nsid, 0, ptr0))))
+    }
+
+    unsafe fn submit_rw(&mut self, nsid: u32, lba: u64, blocks: usize, address: usize, len: usize, write: bool) -> Result<Option<NvmeCommandId>> {
+        assert!(blocks > 0);
+        assert!(blocks <= 0x1_0000);
+        assert!(len <= self.max_transfer);
+
+        let queue = match self.queue_with_room()? {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        let mut list = self.take_list()?;
+
+        // The data is transferred directly to or from the caller's buffer, through an SGL if the
+        // controller supports them, or else through PRPs, which need the data to be dword
+        // aligned. Only if neither is possible, the data is copied through a bounce buffer.
+        let sgl = self.sgl_alignment.map_or(false, |alignment| address % alignment == 0);
+        let (address, bounce) = if sgl || address % 4 == 0 {
+            (address, None)
+        } else {
+            let mut bounce = match self.free_bounce_buffers.pop() {
+                Some(bounce) => bounce,
+                None => Dma::zeroed()?,
+            };
+            if write {
+                bounce[..len].copy_from_slice(slice::from_raw_parts(address as *const u8, len));
+            }
+            (bounce.as_ptr() as usize, Some(bounce))
+        };
+
+        let list_physical = list.physical();
+        let translate = |virt: usize| syscall::virttophys(virt);
+        let [ptr0, ptr1] = if sgl {
+            build_sgl(address, len, &mut list[..], list_physical, translate)?
+        } else {
+            build_prps(address, len, &mut list[..], list_physical, translate)?
+        };
+
+        let blocks_1 = (blocks - 1) as u16;
+        Ok(Some(self.push_command(queue, list, bounce, |cid| if write {
+            NvmeCmd::io_write(
+                cid, nsid, lba, blocks_1, ptr0, ptr1, sgl
+            )
+        } else {
+            NvmeCmd::io_read(
+                cid, nsid, lba, blocks_1, ptr0, ptr1, sgl
+            )
+        })))
+    }
+
+    /// The I/O queue pair to submit a command to, or `None` if every one is full. Commands are
+    /// spread over the queue pairs, by submitting to the least busy one.
+    fn queue_with_room(&self) -> Result<Option<usize>> {
+        if self.failed {
+            return Err(NvmeError::ControllerFailed.into());
+        }
+        Ok((0..self.io_queues.len())
+            .filter(|&queue| !self.io_queues[queue].is_full())
+            .min_by_key(|&queue| self.io_queues[queue].running))
+    }
+
+    fn take_list(&mut self) -> Result<Dma<[u64; 512]>> {
+        match self.free_lists.pop() {
+            Some(list) => Ok(list),
+            None => Dma::zeroed(),
+        }
+    }
+
+    /// Submit a command built from its command ID to an I/O queue pair with room for it.
+    unsafe fn push_command<F>(&mut self, queue: usize, list: Dma<[u64; 512]>, bounce: Option<Dma<[u8; MAX_TRANSFER]>>, f: F) -> NvmeCommandId
+        where F: FnOnce(u16) -> NvmeCmd
+    {
+        let (qid, tail, cid) = {
+            let pair = &mut self.io_queues[queue];
+            let cid = pair.allocate_cid();
+            let entry = f(cid);
+            pair.commands.insert(cid, IoCommand {
+                list,
+                bounce,
+                deadline: Instant::now() + IO_TIMEOUT,
+                status: None,
+            });
+            pair.running += 1;
+            (pair.qid, pair.submission_queue.submit(entry), cid)
+        };
+        self.submission_queue_tail(qid, tail as u16);
+
+        NvmeCommandId { queue, cid }
+    }
+
+    /// Finish a command once it has completed. If the data of a read went through a bounce
+    /// buffer, it is copied to `read`. Returns `None` while the command is still running.
+    pub fn finish(&mut self, id: NvmeCommandId, read: Option<&mut [u8]>) -> Option<Result<()>> {
+        let pair = &mut self.io_queues[id.queue];
+        let status = pair.commands.get(&id.cid)?.status?;
+        let command = pair.commands.remove(&id.cid).unwrap();
+
+        let result = match status {
+            Ok(()) => {
+                if let (Some(read), Some(bounce)) = (read, command.bounce.as_ref()) {
+                    read.copy_from_slice(&bounce[..read.len()]);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                println!("nvmed: command {} on queue {} failed: {}", id.cid, pair.qid, err);
+                Err(err.into())
+            }
+        };
+
+        self.free_lists.push(command.list);
+        if let Some(bounce) = command.bounce {
+            self.free_bounce_buffers.push(bounce);
+        }
+        Some(result)
+    }
+
+    /// Ask for `count` I/O queue pairs, returning how many the controller allocated.
+    unsafe fn set_number_of_queues(&mut self, count: u16) -> Result<u16> {
+        let dw0 = self.set_features(FEATURE_NUMBER_OF_QUEUES, (u32::from(count - 1) << 16) | u32::from(count - 1))?;
+        // Both counts are zero-based, and may be more than requested.
+        let submission_queues = (dw0 & 0xFFFF) as u16 + 1;
+        let completion_queues = (dw0 >> 16) as u16 + 1;
+        Ok(cmp::min(count, cmp::min(submission_queues, completion_queues)))
+    }
+
+    /// Create the completion and submission queue of an I/O queue pair on the controller.
+    unsafe fn register_io_queue_pair(&mut self, queue: usize) -> Result<()> {
+        let (qid, vector) = (self.io_queues[queue].qid, self.io_queues[queue].vector);
+
+        let completion_queue = &self.io_queues[queue].completion_queue;
+        let (ptr, len) = (completion_queue.data.physical(), completion_queue.data.len());
+        // println!("  - Attempting to create I/O completion queue {}", qid);
+        let entry = self.admin_command_spin(|cid| NvmeCmd::create_io_completion_queue(
+            cid, qid, ptr, (len - 1) as u16, vector
+        ))?;
+        if let Some(status) = CommandStatus::parse(entry.status) {
+            println!("nvmed: failed to create I/O completion queue {}: {}", qid, status);
+            return Err(NvmeError::Status(status).into());
+        }
+
+        let submission_queue = &self.io_queues[queue].submission_queue;
+        let (ptr, len) = (submission_queue.data.physical(), submission_queue.data.len());
+        // println!("  - Attempting to create I/O submission queue {}", qid);
+        let entry = self.admin_command_spin(|cid| NvmeCmd::create_io_submission_queue(
+            cid, qid, ptr, (len - 1) as u16, qid
+        ))?;
+        if let Some(status) = CommandStatus::parse(entry.status) {
+            println!("nvmed: failed to create I/O submission queue {}: {}", qid, status);
+            return Err(NvmeError::Status(status).into());
+        }
+
+        Ok(())
+    }
+
+    /// Create the I/O queue pairs: as many as the controller and the interrupt vectors allow, up
+    /// to `MAX_IO_QUEUES`.
+    unsafe fn create_io_queues(&mut self) {
+        // With MSI-X, the admin queue keeps the first vector, and every I/O queue pair gets one of
+        // its own. Otherwise, every queue shares the one interrupt.
+        let vectors = match self.interrupt_method {
+            InterruptMethod::MsiX(vectors) if vectors > 1 => vectors - 1,
+            _ => 1,
+        };
+        let requested = cmp::min(MAX_IO_QUEUES, vectors);
+
+        let allocated = match self.set_number_of_queues(requested) {
+            Ok(allocated) => allocated,
+            Err(_) => {
+                println!("nvmed: failed to set the number of queues");
+                1
+            }
+        };
+
+        for i in 0..allocated {
+            let qid = i + 1;
+            let vector = match self.interrupt_method {
+                InterruptMethod::MsiX(vectors) if vectors > 1 => qid,
+                _ => 0,
+            };
+            let pair = IoQueuePair::new(qid, vector).expect("nvmed: failed to allocate I/O queues");
+
+            self.io_queues.push(pair);
+            if self.register_io_queue_pair(self.io_queues.len() - 1).is_err() {
+                self.io_queues.pop();
+                break;
+            }
+        }
+
+        assert!(!self.io_queues.is_empty(), "nvmed: failed to create any I/O queue");
+        println!("  - I/O queues: {} Interrupts: {:?}", self.io_queues.len(), self.interrupt_method);
+    }
+
+    /// Create the I/O queue pairs again after a reset, which deleted them. The commands that were
+    /// submitted to them have to be dropped already.
+    unsafe fn recreate_io_queues(&mut self) -> Result<()> {
+        let count = self.io_queues.len() as u16;
+        if self.set_number_of_queues(count)? < count {
+            println!("nvmed: fewer I/O queues are available after the reset");
+            return Err(Error::new(EIO));
+        }
+        for queue in 0..self.io_queues.len() {
+            self.register_io_queue_pair(queue)?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the Ready bit of the controller is `ready`, for as long as the controller
+    /// allows for that in CAP.TO.
+    unsafe fn wait_ready(&mut self, ready: bool) -> Result<()> {
+        // The timeout is in units of 500 milliseconds.
+        let timeout = Duration::from_millis(((self.regs.cap.read() >> 24) & 0xFF) * 500);
+        let start = Instant::now();
         loop {
             let csts = self.regs.csts.read();
             // println!("CSTS: {:X}", csts);
-            if csts & 1 == 1 {
-                asm!("pause");
+            if (csts & CSTS_RDY == CSTS_RDY) == ready {
+                return Ok(());
+            } else if csts == 0xFFFF_FFFF || start.elapsed() > timeout {
+                println!("nvmed: controller didn't become {} in time, CSTS {:#X}", if ready { "ready" } else { "disabled" }, csts);
+                return Err(NvmeError::Timeout.into());
             } else {
-                break;
+                asm!("pause");
             }
         }
+    }
 
-        // println!("  - Mask all interrupts");
-        self.regs.intms.write(0xFFFFFFFF);
+    /// Disable the controller, which resets it and deletes the I/O queues.
+    unsafe fn disable(&mut self) -> Result<()> {
+        // println!("  - Disable");
+        self.regs.cc.writef(1, false);
 
-        for (qid, queue) in self.completion_queues.iter().enumerate() {
-            let data = &queue.data;
-            // println!("    - completion queue {}: {:X}, {}", qid, data.physical(), data.len());
-        }
+        // println!("  - Waiting for not ready");
+        self.wait_ready(false)
+    }
 
-        for (qid, queue) in self.submission_queues.iter().enumerate() {
-            let data = &queue.data;
-            // println!("    - submission queue {}: {:X}, {}", qid, data.physical(), data.len());
+    /// Set up the admin queues of a disabled controller, and enable it.
+    unsafe fn enable(&mut self) -> Result<()> {
+        // The interrupt mask registers must not be used with MSI-X, where each vector is masked
+        // in the MSI-X table instead.
+        if !self.uses_msix() {
+            // println!("  - Mask all interrupts");
+            self.regs.intms.write(0xFFFFFFFF);
         }
 
         {
-            let asq = &self.submission_queues[0];
-            let acq = &self.completion_queues[0];
+            self.admin_submission_queue.reset();
+            self.admin_completion_queue.reset();
+
+            let asq = &self.admin_submission_queue;
+            let acq = &self.admin_completion_queue;
             self.regs.aqa.write(((acq.data.len() as u32 - 1) << 16) | (asq.data.len() as u32 - 1));
             self.regs.asq.write(asq.data.physical() as u64);
             self.regs.acq.write(acq.data.physical() as u64);
@@ -361,39 +1346,26 @@ impl Nvme {
         self.regs.cc.writef(1, true);
 
         // println!("  - Waiting for ready");
-        loop {
-            let csts = self.regs.csts.read();
-            // println!("CSTS: {:X}", csts);
-            if csts & 1 == 0 {
-                asm!("pause");
-            } else {
-                break;
-            }
-        }
+        self.wait_ready(true)
+    }
+
+    pub unsafe fn init(&mut self) -> Result<BTreeMap<u32, NvmeNamespace>> {
+        // println!("  - CAPS: {:X}", self.regs.cap.read());
+        // println!("  - VS: {:X}", self.regs.vs.read());
+        // println!("  - CC: {:X}", self.regs.cc.read());
+        // println!("  - CSTS: {:X}", self.regs.csts.read());
+
+        self.disable()?;
+        self.enable()?;
 
         {
             //TODO: Use buffer
             let data: Dma<[u8; 4096]> = Dma::zeroed().unwrap();
 
             // println!("  - Attempting to identify controller");
-            {
-                let qid = 0;
-                let queue = &mut self.submission_queues[qid];
-                let cid = queue.i as u16;
-                let entry = NvmeCmd::identify_controller(
-                    cid, data.physical()
-                );
-                let tail = queue.submit(entry);
-                self.submission_queue_tail(qid as u16, tail as u16);
-            }
-
-            // println!("  - Waiting to identify controller");
-            {
-                let qid = 0;
-                let queue = &mut self.completion_queues[qid];
-                let (head, entry) = queue.complete_spin();
-                self.completion_queue_head(qid as u16, head as u16);
-            }
+            admin_result(&self.admin_command_spin(|cid| NvmeCmd::identify_controller(
+                cid, data.physical()
+            ))?)?;
 
             // println!("  - Dumping identify controller");
 
@@ -427,6 +1399,28 @@ impl Nvme {
                 serial.trim(),
                 firmware.trim()
             );
+
+            // Maximum data transfer size, in units of the minimum memory page size
+            let mdts = u32::from(data[77]);
+            let mpsmin = ((self.regs.cap.read() >> 48) & 0xF) as u32;
+            if mdts != 0 && mdts + mpsmin + 12 < 32 {
+                self.max_transfer = cmp::min(MAX_TRANSFER, 1 << (mdts + mpsmin + 12));
+            }
+
+            // Error log page entries, zero-based
+            self.error_log_entries = usize::from(data[262]) + 1;
+
+            // Optional NVM command support, and volatile write cache presence
+            self.oncs = *(data.as_ptr().offset(520) as *const u16);
+            self.volatile_write_cache = data[525] & 1 == 1;
+
+            // SGL support, for the NVM command set
+            let sgls = *(data.as_ptr().offset(536) as *const u32);
+            self.sgl_alignment = match sgls & 0b11 {
+                0b01 => Some(1),
+                0b10 => Some(4),
+                _ => None,
+            };
         }
 
         let mut nsids = Vec::new();
@@ -435,24 +1429,9 @@ impl Nvme {
             let data: Dma<[u32; 1024]> = Dma::zeroed().unwrap();
 
             // println!("  - Attempting to retrieve namespace ID list");
-            {
-                let qid = 0;
-                let queue = &mut self.submission_queues[qid];
-                let cid = queue.i as u16;
-                let entry = NvmeCmd::identify_namespace_list(
-                    cid, data.physical(), 0
-                );
-                let tail = queue.submit(entry);
-                self.submission_queue_tail(qid as u16, tail as u16);
-            }
-
-            // println!("  - Waiting to retrieve namespace ID list");
-            {
-                let qid = 0;
-                let queue = &mut self.completion_queues[qid];
-                let (head, entry) = queue.complete_spin();
-                self.completion_queue_head(qid as u16, head as u16);
-            }
+            admin_result(&self.admin_command_spin(|cid| NvmeCmd::identify_namespace_list(
+                cid, data.physical(), 0
+            ))?)?;
 
             // println!("  - Dumping namespace ID list");
             for &nsid in data.iter() {
@@ -468,188 +1447,145 @@ impl Nvme {
             let data: Dma<[u8; 4096]> = Dma::zeroed().unwrap();
 
             // println!("  - Attempting to identify namespace {}", nsid);
-            {
-                let qid = 0;
-                let queue = &mut self.submission_queues[qid];
-                let cid = queue.i as u16;
-                let entry = NvmeCmd::identify_namespace(
-                    cid, data.physical(), nsid
-                );
-                let tail = queue.submit(entry);
-                self.submission_queue_tail(qid as u16, tail as u16);
-            }
-
-            // println!("  - Waiting to identify namespace {}", nsid);
-            {
-                let qid = 0;
-                let queue = &mut self.completion_queues[qid];
-                let (head, entry) = queue.complete_spin();
-                self.completion_queue_head(qid as u16, head as u16);
+            if admin_result(&self.admin_command_spin(|cid| NvmeCmd::identify_namespace(
+                cid, data.physical(), nsid
+            ))?).is_err() {
+                println!("    - ID: {} couldn't be identified", nsid);
+                continue;
             }
 
             // println!("  - Dumping identify namespace");
 
 
-            let size = *(data.as_ptr().offset(0) as *const u64);
             let capacity = *(data.as_ptr().offset(8) as *const u64);
-            println!(
-                "    - ID: {} Size: {} Capacity: {}",
-                nsid,
-                size,
-                capacity
-            );
-
-            //TODO: Read block size
-
-            namespaces.insert(nsid, NvmeNamespace {
-                id: nsid,
-                blocks: size,
-                block_size: 512, // TODO
-            });
-        }
-
-        for io_qid in 1..self.completion_queues.len() {
-            let (ptr, len) = {
-                let queue = &self.completion_queues[io_qid];
-                (queue.data.physical(), queue.data.len())
-            };
-
-            // println!("  - Attempting to create I/O completion queue {}", io_qid);
-            {
-                let qid = 0;
-                let queue = &mut self.submission_queues[qid];
-                let cid = queue.i as u16;
-                let entry = NvmeCmd::create_io_completion_queue(
-                    cid, io_qid as u16, ptr, (len - 1) as u16
-                );
-                let tail = queue.submit(entry);
-                self.submission_queue_tail(qid as u16, tail as u16);
-            }
-
-            // println!("  - Waiting to create I/O completion queue {}", io_qid);
-            {
-                let qid = 0;
-                let queue = &mut self.completion_queues[qid];
-                let (head, entry) = queue.complete_spin();
-                self.completion_queue_head(qid as u16, head as u16);
+            match NvmeNamespace::parse(nsid, &data[..]) {
+                Ok(ref namespace) if namespace.block_size as usize > self.max_transfer => {
+                    println!("    - ID: {} has blocks of {} bytes, larger than a transfer", nsid, namespace.block_size);
+                }
+                Ok(namespace) => {
+                    println!(
+                        "    - ID: {} Size: {} Capacity: {} Block size: {}",
+                        nsid,
+                        namespace.blocks,
+                        capacity,
+                        namespace.block_size
+                    );
+                    namespaces.insert(nsid, namespace);
+                }
+                Err(err) => println!("    - ID: {} has an unsupported format: {:?}", nsid, err),
             }
         }
 
-        for io_qid in 1..self.submission_queues.len() {
-            let (ptr, len) = {
-                let queue = &self.submission_queues[io_qid];
-                (queue.data.physical(), queue.data.len())
-            };
-
-            // println!("  - Attempting to create I/O submission queue {}", io_qid);
-            {
-                let qid = 0;
-                let queue = &mut self.submission_queues[qid];
-                let cid = queue.i as u16;
-                //TODO: Get completion queue ID through smarter mechanism
-                let entry = NvmeCmd::create_io_submission_queue(
-                    cid, io_qid as u16, ptr, (len - 1) as u16, io_qid as u16
-                );
-                let tail = queue.submit(entry);
-                self.submission_queue_tail(qid as u16, tail as u16);
-            }
+        self.create_io_queues();
 
-            // println!("  - Waiting to create I/O submission queue {}", io_qid);
-            {
-                let qid = 0;
-                let queue = &mut self.completion_queues[qid];
-                let (head, entry) = queue.complete_spin();
-                self.completion_queue_head(qid as u16, head as u16);
-            }
+        if !self.uses_msix() {
+            // println!("  - Unmask the interrupt");
+            self.regs.intmc.write(1);
         }
 
         // println!("  - Complete");
 
-        namespaces
+        Ok(namespaces)
     }
 
-    unsafe fn namespace_rw(&mut self, nsid: u32, lba: u64, blocks_1: u16, write: bool) -> Result<()> {
-        //TODO: Get real block size
-        let block_size = 512;
+    fn uses_msix(&self) -> bool {
+        match self.interrupt_method {
+            InterruptMethod::MsiX(_) => true,
+            _ => false,
+        }
+    }
+}
 
-        let bytes = ((blocks_1 as u64) + 1) * block_size;
-        let (ptr0, ptr1) = if bytes <= 4096 {
-            (self.buffer_prp[0], 0)
-        } else if bytes <= 8192 {
-            (self.buffer_prp[0], self.buffer_prp[1])
-        } else {
-            (self.buffer_prp[0], (self.buffer_prp.physical() + 8) as u64)
-        };
+#[cfg(test)]
+mod test {
+    use syscall::error::Result;
 
-        {
-            let qid = 1;
-            let queue = &mut self.submission_queues[qid];
-            let cid = queue.i as u16;
-            let entry = if write {
-                NvmeCmd::io_write(
-                    cid, nsid, lba, blocks_1, ptr0, ptr1
-                )
-            } else {
-                NvmeCmd::io_read(
-                    cid, nsid, lba, blocks_1, ptr0, ptr1
-                )
-            };
-            let tail = queue.submit(entry);
-            self.submission_queue_tail(qid as u16, tail as u16);
-        }
+    use super::{build_prps, build_sgl, parse_feature_request, passthrough_allowed, NvmeNamespace, UnsupportedFormat, SGL_LAST_SEGMENT};
 
-        {
-            let qid = 1;
-            let queue = &mut self.completion_queues[qid];
-            let (head, entry) = queue.complete_spin();
-            //TODO: Handle errors
-            self.completion_queue_head(qid as u16, head as u16);
+    fn identify_namespace(nlbaf: u8, flbas: u8, lbafs: &[u32]) -> [u8; 4096] {
+        let mut data = [0; 4096];
+        data[0..8].copy_from_slice(&0x1000u64.to_le_bytes());
+        data[25] = nlbaf;
+        data[26] = flbas;
+        for (i, lbaf) in lbafs.iter().enumerate() {
+            data[128 + 4 * i..132 + 4 * i].copy_from_slice(&lbaf.to_le_bytes());
         }
-
-        Ok(())
+        data
     }
 
-    pub unsafe fn namespace_read(&mut self, nsid: u32, mut lba: u64, buf: &mut [u8]) -> Result<Option<usize>> {
-        //TODO: Use interrupts
-
-        //TODO: Get real block size
-        let block_size = 512;
+    #[test]
+    fn test_parse_namespace() {
+        // 512 byte blocks, 4096 byte blocks, and 4096 byte blocks with 8 bytes of metadata.
+        let lbafs = [9 << 16, 12 << 16, (12 << 16) | 8];
 
-        for chunk in buf.chunks_mut(self.buffer.len()) {
-            let blocks = (chunk.len() + block_size - 1) / block_size;
+        let namespace = NvmeNamespace::parse(1, &identify_namespace(2, 0, &lbafs)).unwrap();
+        assert_eq!((namespace.id, namespace.blocks, namespace.block_size), (1, 0x1000, 512));
 
-            assert!(blocks > 0);
-            assert!(blocks <= 0x1_0000);
+        let namespace = NvmeNamespace::parse(1, &identify_namespace(2, 1, &lbafs)).unwrap();
+        assert_eq!(namespace.block_size, 4096);
 
-            self.namespace_rw(nsid, lba, (blocks - 1) as u16, false)?;
+        // Whether the metadata is extended (bit 4) or in a separate buffer, it's refused.
+        assert_eq!(NvmeNamespace::parse(1, &identify_namespace(2, 2, &lbafs)).err(), Some(UnsupportedFormat::Metadata(8)));
+        assert_eq!(NvmeNamespace::parse(1, &identify_namespace(2, 0x12, &lbafs)).err(), Some(UnsupportedFormat::Metadata(8)));
 
-            chunk.copy_from_slice(&self.buffer[..chunk.len()]);
+        assert_eq!(NvmeNamespace::parse(1, &identify_namespace(2, 3, &lbafs)).err(), Some(UnsupportedFormat::InvalidFormat(3)));
+        assert_eq!(NvmeNamespace::parse(1, &identify_namespace(0, 0, &[8 << 16])).err(), Some(UnsupportedFormat::BlockSize(8)));
 
-            lba += blocks as u64;
-        }
-
-        Ok(Some(buf.len()))
+        // The upper bits of the format index.
+        let mut lbafs = [9 << 16; 18];
+        lbafs[17] = 12 << 16;
+        let namespace = NvmeNamespace::parse(1, &identify_namespace(17, 0x21, &lbafs)).unwrap();
+        assert_eq!(namespace.block_size, 4096);
     }
 
-    pub unsafe fn namespace_write(&mut self, nsid: u32, mut lba: u64, buf: &[u8]) -> Result<Option<usize>> {
-        //TODO: Use interrupts
-
-        //TODO: Get real block size
-        let block_size = 512;
+    /// Map virtual pages to physical pages in reverse order, so that no two are contiguous, apart
+    /// from the pages at 0x10000 and 0x11000.
+    fn translate(virt: usize) -> Result<usize> {
+        let page = match virt / 0x1000 {
+            0x10 => 0x100,
+            0x11 => 0x101,
+            page => 0x200 - page,
+        };
+        Ok(page * 0x1000 + virt % 0x1000)
+    }
 
-        for chunk in buf.chunks(self.buffer.len()) {
-            let blocks = (chunk.len() + block_size - 1) / block_size;
+    #[test]
+    fn test_build_prps() {
+        let mut list = [0; 512];
 
-            assert!(blocks > 0);
-            assert!(blocks <= 0x1_0000);
+        assert_eq!(build_prps(0x1200, 0xE00, &mut list, 0x8000, translate).unwrap(), [0x1FF200, 0]);
+        assert_eq!(build_prps(0x1200, 0x1000, &mut list, 0x8000, translate).unwrap(), [0x1FF200, 0x1FE000]);
+        assert_eq!(build_prps(0x1000, 0x3000, &mut list, 0x8000, translate).unwrap(), [0x1FF000, 0x8000]);
+        assert_eq!(&list[..2], &[0x1FE000, 0x1FD000]);
+    }
 
-            self.buffer[..chunk.len()].copy_from_slice(chunk);
+    #[test]
+    fn test_build_sgl() {
+        let mut list = [0; 512];
 
-            self.namespace_rw(nsid, lba, (blocks - 1) as u16, true)?;
+        // Physically contiguous data fits in a single data block descriptor.
+        assert_eq!(build_sgl(0x10200, 0x1C00, &mut list, 0x8000, translate).unwrap(), [0x100200, 0x1C00]);
+        assert_eq!(build_sgl(0x1001, 0x2000, &mut list, 0x8000, translate).unwrap(), [0x8000, 48 | SGL_LAST_SEGMENT]);
+        assert_eq!(&list[..6], &[0x1FF001, 0xFFF, 0x1FE000, 0x1000, 0x1FD000, 1]);
+    }
 
-            lba += blocks as u64;
-        }
+    #[test]
+    fn test_passthrough_allowed() {
+        assert!(passthrough_allowed(0x06));
+        assert!(passthrough_allowed(0xC2));
+        // Asynchronous Event Request, Format NVM, and a vendor specific write
+        assert!(!passthrough_allowed(0x0C));
+        assert!(!passthrough_allowed(0x80));
+        assert!(!passthrough_allowed(0xC1));
+    }
 
-        Ok(Some(buf.len()))
+    #[test]
+    fn test_parse_feature_request() {
+        assert_eq!(parse_feature_request(b"temperature_threshold_kelvin: 350\n").unwrap(), (0x04, 350));
+        assert_eq!(parse_feature_request(b"power_state:2").unwrap(), (0x02, 2));
+        // Out of range, unknown, and malformed
+        assert!(parse_feature_request(b"power_state: 32").is_err());
+        assert!(parse_feature_request(b"number_of_queues: 1").is_err());
+        assert!(parse_feature_request(b"power_state 2").is_err());
     }
 }
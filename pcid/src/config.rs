@@ -1,14 +1,27 @@
 use std::collections::BTreeMap;
+use std::fs;
+use std::io;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
+use thiserror::Error;
+
+use crate::pci::PciHeader;
 
 #[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
     pub drivers: Vec<DriverConfig>,
+    /// Where the INTx# pins of devices are wired to, for when firmware didn't tell. The ACPI
+    /// daemon can supply the `_PRT` routing by writing these into the config directory.
+    #[serde(default)]
+    pub interrupt_routes: Vec<InterruptRoute>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DriverConfig {
     pub name: Option<String>,
     pub class: Option<u8>,
@@ -18,6 +31,380 @@ pub struct DriverConfig {
     pub vendor: Option<u16>,
     pub device: Option<u16>,
     pub device_id_range: Option<Range<u16>>,
+    pub subsystem_vendor: Option<u16>,
+    pub subsystem_device: Option<u16>,
+    pub revision: Option<u8>,
+    pub revision_range: Option<Range<u8>>,
+    /// Functions that the driver must not be launched for, even though they match.
+    pub exclude: Option<Vec<Exclusion>>,
+    /// When several drivers match a function, only the one with the highest priority is launched,
+    /// or the first of them if they are equal. Defaults to 0.
+    pub priority: Option<i32>,
     pub command: Option<Vec<String>>,
     pub channel_name: Option<String>,
+    /// Whether to restart the driver when it exits, defaults to `never`.
+    pub restart: Option<RestartPolicy>,
+    /// How many times in a row the driver may be restarted before pcid gives up.
+    pub max_restarts: Option<u32>,
+}
+
+/// An entry of `exclude`, which matches a function if every field that is set matches.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exclusion {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    pub subsystem_vendor: Option<u16>,
+    pub subsystem_device: Option<u16>,
+    pub revision: Option<u8>,
+}
+
+/// An entry of an interrupt routing table, like those of the ACPI `_PRT` objects: the IRQ that an
+/// interrupt pin of a device is wired to. Devices behind a bridge without an entry of their own use
+/// the entry of the bridge, see `intx::route`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterruptRoute {
+    pub bus: u8,
+    pub device: u8,
+    /// The interrupt pin, from `A` to `D`.
+    pub pin: char,
+    pub irq: u8,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Always,
+    OnFailure,
+    Never,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+
+    #[error("failed to parse {}: {}", .0.display(), .1)]
+    Parse(PathBuf, #[source] toml::de::Error),
+
+    #[error("{}: driver {}: {}", .0.display(), .1, .2)]
+    Invalid(PathBuf, String, String),
+
+    #[error("{}: interrupt route {}: {}", .0.display(), .1, .2)]
+    InvalidRoute(PathBuf, usize, String),
+}
+
+/// Parse a vendor ID used as a key of `ids`, which is written in hexadecimal.
+fn parse_vendor_key(key: &str) -> Option<u16> {
+    u16::from_str_radix(key.trim_start_matches("0x"), 16).ok()
+}
+
+impl Config {
+    /// Load the driver configs from a file, or from every file in a directory. Files that cannot
+    /// be read or parsed, and drivers that are invalid, are skipped and returned as errors, so
+    /// that the rest of the drivers can still be launched.
+    pub fn load(path: &Path) -> (Self, Vec<ConfigError>) {
+        let mut config = Self::default();
+        let mut errors = Vec::new();
+
+        let paths = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => match fs::read_dir(path) {
+                Ok(entries) => {
+                    let mut paths = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>();
+                    paths.sort();
+                    paths
+                }
+                Err(error) => return (config, vec![ConfigError::Io(path.to_owned(), error)]),
+            }
+            Ok(_) => vec![path.to_owned()],
+            Err(error) => return (config, vec![ConfigError::Io(path.to_owned(), error)]),
+        };
+
+        for path in paths {
+            let file_config = match fs::read_to_string(&path) {
+                Ok(data) => match toml::from_str::<Config>(&data) {
+                    Ok(file_config) => file_config,
+                    Err(error) => {
+                        errors.push(ConfigError::Parse(path, error));
+                        continue;
+                    }
+                }
+                Err(error) => {
+                    errors.push(ConfigError::Io(path, error));
+                    continue;
+                }
+            };
+
+            for (index, driver) in file_config.drivers.into_iter().enumerate() {
+                match driver.validate() {
+                    Ok(()) => config.drivers.push(driver),
+                    Err(reason) => {
+                        let name = match driver.name {
+                            Some(ref name) => format!("{} ({:?})", index, name),
+                            None => index.to_string(),
+                        };
+                        errors.push(ConfigError::Invalid(path.clone(), name, reason));
+                    }
+                }
+            }
+            for (index, route) in file_config.interrupt_routes.into_iter().enumerate() {
+                match route.validate() {
+                    Ok(()) => config.interrupt_routes.push(route),
+                    Err(reason) => errors.push(ConfigError::InvalidRoute(path.clone(), index, reason)),
+                }
+            }
+        }
+
+        (config, errors)
+    }
+
+    /// The driver to launch for a function; the matching driver with the highest priority.
+    pub fn best_match(&self, header: &PciHeader) -> Option<&DriverConfig> {
+        self.drivers.iter().filter(|driver| driver.matches(header)).fold(None, |best: Option<&DriverConfig>, driver| match best {
+            Some(best) if best.priority() >= driver.priority() => Some(best),
+            _ => Some(driver),
+        })
+    }
+}
+
+impl InterruptRoute {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.device >= 32 {
+            return Err(format!("device {:#04X} is out of range", self.device));
+        }
+        if !('A'..='D').contains(&self.pin) {
+            return Err(format!("pin {:?} is not one of `A` to `D`", self.pin));
+        }
+        if self.irq == 0xFF {
+            return Err("IRQ 0xFF means not connected".to_owned());
+        }
+        Ok(())
+    }
+    /// The pin, numbered like the Interrupt Pin register, from 1 for INTA#.
+    pub fn pin_number(&self) -> u8 {
+        self.pin as u8 - b'A' + 1
+    }
+}
+
+impl DriverConfig {
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+
+    /// Check that the driver can be launched, and that its matching rules make sense.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.command {
+            Some(ref command) if !command.is_empty() => (),
+            _ => return Err("`command` is missing or empty".to_owned()),
+        }
+
+        if let Some(ref ids) = self.ids {
+            if self.vendor.is_some() || self.device.is_some() {
+                return Err("`ids` cannot be combined with `vendor` or `device`".to_owned());
+            }
+            if let Some(key) = ids.keys().find(|key| parse_vendor_key(key).is_none()) {
+                return Err(format!("`ids` key {:?} is not a hexadecimal vendor ID", key));
+            }
+        }
+        if self.device.is_some() && self.vendor.is_none() {
+            return Err("`device` requires `vendor`".to_owned());
+        }
+        if self.subsystem_device.is_some() && self.subsystem_vendor.is_none() {
+            return Err("`subsystem_device` requires `subsystem_vendor`".to_owned());
+        }
+        if let Some(ref range) = self.device_id_range {
+            if range.start >= range.end {
+                return Err(format!("`device_id_range` {:#06X}..{:#06X} is empty", range.start, range.end));
+            }
+        }
+        if let Some(ref range) = self.revision_range {
+            if range.start >= range.end {
+                return Err(format!("`revision_range` {}..{} is empty", range.start, range.end));
+            }
+        }
+        if let Some(ref exclude) = self.exclude {
+            if exclude.iter().any(|exclusion| exclusion.is_empty()) {
+                return Err("an `exclude` entry would exclude every function".to_owned());
+            }
+        }
+
+        let matches_anything = self.class.is_none() && self.ids.is_none() && self.vendor.is_none()
+            && self.subsystem_vendor.is_none() && self.device_id_range.is_none();
+        if matches_anything {
+            return Err("matches every function; set at least `class`, `vendor`, `ids` or `subsystem_vendor`".to_owned());
+        }
+
+        Ok(())
+    }
+
+    pub fn matches(&self, header: &PciHeader) -> bool {
+        let raw_class: u8 = header.class().into();
+
+        if let Some(class) = self.class {
+            if class != raw_class { return false; }
+        }
+
+        if let Some(subclass) = self.subclass {
+            if subclass != header.subclass() { return false; }
+        }
+
+        if let Some(interface) = self.interface {
+            if interface != header.interface() { return false; }
+        }
+
+        if let Some(ref ids) = self.ids {
+            let device_found = ids.iter().any(|(vendor, devices)| {
+                parse_vendor_key(vendor) == Some(header.vendor_id()) && devices.contains(&header.device_id())
+            });
+            if !device_found { return false; }
+        } else {
+            if let Some(vendor) = self.vendor {
+                if vendor != header.vendor_id() { return false; }
+            }
+
+            if let Some(device) = self.device {
+                if device != header.device_id() { return false; }
+            }
+        }
+
+        if let Some(ref device_id_range) = self.device_id_range {
+            if !device_id_range.contains(&header.device_id()) { return false; }
+        }
+
+        if let Some(subsystem_vendor) = self.subsystem_vendor {
+            if Some(subsystem_vendor) != header.subsystem_vendor_id() { return false; }
+        }
+
+        if let Some(subsystem_device) = self.subsystem_device {
+            if Some(subsystem_device) != header.subsystem_id() { return false; }
+        }
+
+        if let Some(revision) = self.revision {
+            if revision != header.revision() { return false; }
+        }
+
+        if let Some(ref revision_range) = self.revision_range {
+            if !revision_range.contains(&header.revision()) { return false; }
+        }
+
+        if let Some(ref exclude) = self.exclude {
+            if exclude.iter().any(|exclusion| exclusion.matches(header)) { return false; }
+        }
+
+        true
+    }
+}
+
+impl Exclusion {
+    fn is_empty(&self) -> bool {
+        self.vendor.is_none() && self.device.is_none() && self.subsystem_vendor.is_none()
+            && self.subsystem_device.is_none() && self.revision.is_none()
+    }
+    pub fn matches(&self, header: &PciHeader) -> bool {
+        self.vendor.map_or(true, |vendor| vendor == header.vendor_id())
+            && self.device.map_or(true, |device| device == header.device_id())
+            && self.subsystem_vendor.map_or(true, |vendor| Some(vendor) == header.subsystem_vendor_id())
+            && self.subsystem_device.map_or(true, |device| Some(device) == header.subsystem_id())
+            && self.revision.map_or(true, |revision| revision == header.revision())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, DriverConfig};
+    use crate::pci::PciHeader;
+
+    fn header(vendor: u16, device: u16, class: u8, revision: u8, subsystem_vendor: u16, subsystem_device: u16) -> PciHeader {
+        let mut bytes = [0u8; 64];
+        bytes[0..2].copy_from_slice(&vendor.to_le_bytes());
+        bytes[2..4].copy_from_slice(&device.to_le_bytes());
+        bytes[8] = revision;
+        bytes[11] = class;
+        bytes[44..46].copy_from_slice(&subsystem_vendor.to_le_bytes());
+        bytes[46..48].copy_from_slice(&subsystem_device.to_le_bytes());
+        PciHeader::from_reader(&bytes[..]).unwrap()
+    }
+
+    fn parse(toml: &str) -> Vec<Result<DriverConfig, String>> {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.drivers.into_iter().map(|driver| driver.validate().map(|()| driver)).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let drivers = parse(r#"
+            [[drivers]]
+            class = 2
+            ids = { 0x10ec = [0x8168] }
+            command = ["rtl8168d"]
+
+            [[drivers]]
+            class = 3
+            command = []
+
+            [[drivers]]
+            ids = { foo = [0x1234] }
+            command = ["foo"]
+
+            [[drivers]]
+            device = 0x1234
+            command = ["foo"]
+
+            [[drivers]]
+            vendor = 0x1234
+            revision_range = { start = 4, end = 4 }
+            command = ["foo"]
+
+            [[drivers]]
+            class = 3
+            exclude = [{}]
+            command = ["foo"]
+
+            [[drivers]]
+            subclass = 6
+            command = ["foo"]
+        "#);
+
+        assert!(drivers[0].is_ok());
+        assert!(drivers[1..].iter().all(|driver| driver.is_err()));
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(toml::from_str::<Config>("[[drivers]]\nclass = 1\nsubsytem_vendor = 4\n").is_err());
+    }
+
+    #[test]
+    fn test_best_match() {
+        let config: Config = toml::from_str(r#"
+            [[drivers]]
+            name = "generic"
+            class = 3
+            command = ["vesad"]
+
+            [[drivers]]
+            name = "specific"
+            class = 3
+            vendor = 0x1234
+            subsystem_vendor = 0x1af4
+            revision_range = { start = 2, end = 4 }
+            exclude = [{ device = 0x2222 }]
+            priority = 10
+            command = ["bgad"]
+
+            [[drivers]]
+            name = "also generic"
+            class = 3
+            command = ["other"]
+        "#).unwrap();
+
+        let name = |header| config.best_match(&header).and_then(|driver| driver.name.as_ref()).map(String::as_str);
+
+        assert_eq!(name(header(0x1234, 0x1111, 3, 2, 0x1af4, 0x1100)), Some("specific"));
+        assert_eq!(name(header(0x1234, 0x2222, 3, 2, 0x1af4, 0x1100)), Some("generic"));
+        assert_eq!(name(header(0x1234, 0x1111, 3, 4, 0x1af4, 0x1100)), Some("generic"));
+        assert_eq!(name(header(0x1234, 0x1111, 3, 2, 0x8086, 0x1100)), Some("generic"));
+        assert_eq!(name(header(0x1234, 0x1111, 2, 2, 0x1af4, 0x1100)), None);
+    }
 }
@@ -1,6 +1,12 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs::{File, OpenOptions};
+use std::future::Future;
 use std::io::prelude::*;
-use std::{env, io};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::{env, io, thread};
 
 use std::os::unix::io::{FromRawFd, RawFd};
 
@@ -8,7 +14,10 @@ use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use thiserror::Error;
 
 pub use crate::pci::PciBar;
+pub use crate::pci::express;
+pub use crate::pci::ext_cap::{self, ExtendedCapability};
 pub use crate::pci::msi;
+pub use crate::pci::pm::{self, PowerState};
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[repr(u8)]
@@ -37,10 +46,10 @@ pub struct PciFunction {
     /// PCI Base Address Registers
     pub bars: [PciBar; 6],
 
-    /// BAR sizes
-    pub bar_sizes: [u32; 6],
+    /// BAR sizes. The size of a 64-bit BAR is stored at the index of its lower half.
+    pub bar_sizes: [u64; 6],
 
-    /// Legacy IRQ line
+    /// Legacy IRQ line, or 0xFF if the INTx# routing is unknown
     pub legacy_interrupt_line: u8,
 
     /// Legacy interrupt pin (INTx#), none if INTx# interrupts aren't supported at all.
@@ -80,6 +89,12 @@ impl FeatureStatus {
 pub enum PciFeature {
     Msi,
     MsiX,
+    /// The PCI Express capability. It cannot be enabled or disabled, and is always enabled when
+    /// present.
+    Pcie,
+    /// The power management capability. Like `Pcie`, it is always enabled when present; the
+    /// power state is set through `PcidClientRequest::SetPowerState`.
+    PowerManagement,
 }
 impl PciFeature {
     pub fn is_msi(&self) -> bool {
@@ -88,11 +103,27 @@ impl PciFeature {
     pub fn is_msix(&self) -> bool {
         if let &Self::MsiX = self { true } else { false }
     }
+    pub fn is_pcie(&self) -> bool {
+        if let &Self::Pcie = self { true } else { false }
+    }
+    pub fn is_power_management(&self) -> bool {
+        if let &Self::PowerManagement = self { true } else { false }
+    }
+}
+/// The kind of message signaled interrupts to allocate vectors for.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum InterruptKind {
+    /// MSI, where multiple vectors have to be a power of two, and are allocated contiguously.
+    Msi,
+    /// MSI-X, where every table entry gets its own vector.
+    MsiX,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PciFeatureInfo {
     Msi(msi::MsiCapability),
     MsiX(msi::MsixCapability),
+    Pcie(express::PcieCapability),
+    PowerManagement(pm::PowerManagementCapability),
 }
 
 #[derive(Debug, Error)]
@@ -111,6 +142,18 @@ pub enum PcidClientHandleError {
 
     #[error("invalid response: {0:?}")]
     InvalidResponse(PcidClientResponse),
+
+    #[error("frame of {0} bytes is larger than the maximum")]
+    FrameTooLarge(u64),
+
+    #[error("frame is too short to hold a request ID")]
+    MalformedFrame,
+
+    #[error("request {0} could not be decoded: {1}")]
+    MalformedRequest(u64, #[source] bincode::Error),
+
+    #[error("the connection to pcid was closed")]
+    Disconnected,
 }
 pub type Result<T, E = PcidClientHandleError> = std::result::Result<T, E>;
 
@@ -122,12 +165,40 @@ pub enum PcidClientRequest {
     EnableFeature(PciFeature),
     FeatureStatus(PciFeature),
     FeatureInfo(PciFeature),
+    RequestExtendedCapabilities,
+    /// Allocate `count` interrupt vectors, program them into the MSI capability or the MSI-X
+    /// table, and enable that kind of interrupts. Any vectors previously allocated for the
+    /// function are replaced.
+    AllocateInterrupts {
+        count: u16,
+        kind: InterruptKind,
+    },
+    /// Reset the function, using a Function Level Reset if supported, or otherwise a transition
+    /// through D3hot. Its configuration, including any allocated interrupt vectors, is saved
+    /// before and restored after the reset, but the device state itself is lost.
+    ResetFunction,
+    /// Move the function into a power state. The configuration is saved when leaving D0, and
+    /// restored when the function comes back to D0.
+    SetPowerState(PowerState),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum PcidServerResponseError {
     NonexistentFeature(PciFeature),
+    /// The number of interrupt vectors requested was zero, more than the function supports, or
+    /// not a power of two when using MSI.
+    InvalidInterruptCount(u16),
+    /// There weren't enough free interrupt vectors, or they couldn't be programmed.
+    InterruptAllocationFailed,
+    /// The function supports neither Function Level Reset nor resetting through D3hot.
+    ResetUnsupported,
+    /// The power state is optional, and not supported by the function.
+    PowerStateUnsupported(PowerState),
+    /// The function didn't respond to configuration requests in time after being reset.
+    DeviceNotReady,
+    /// The request couldn't be decoded, e.g. because it was added in a newer version of pcid.
+    UnknownRequest,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -139,44 +210,162 @@ pub enum PcidClientResponse {
     FeatureStatus(PciFeature, FeatureStatus),
     Error(PcidServerResponseError),
     FeatureInfo(PciFeature, PciFeatureInfo),
+    ExtendedCapabilities(Vec<(u16, ExtendedCapability)>),
+    /// The IRQ allocated for each vector, in the order of the MSI message numbers or the MSI-X
+    /// table entries.
+    InterruptsAllocated(InterruptKind, Vec<u8>),
+    FunctionReset,
+    PowerStateSet(PowerState),
+}
+
+/// A message that pcid sends on its own, rather than in response to a request.
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum PcidNotification {
+    /// The driver is being unbound from the function, and will be asked to exit shortly.
+    Unbinding,
+    /// The function is gone, e.g. because it was hot-unplugged. Accesses to its BARs no longer
+    /// reach it.
+    FunctionRemoved,
+    /// An error was logged by Advanced Error Reporting, along with the (correctable or
+    /// uncorrectable) error status register.
+    AerError {
+        correctable: bool,
+        status: u32,
+    },
 }
 
 // TODO: Ideally, pcid might have its own scheme, like lots of other Redox drivers, where this kind of IPC is done. Otherwise, instead of writing serde messages over
 // a channel, the communication could potentially be done via mmap, using a channel
 // very similar to crossbeam-channel or libstd's mpsc (except the cycle, enqueue and dequeue fields
 // are stored in the same buffer as the actual data).
-/// A handle from a `pcid` client (e.g. `ahcid`) to `pcid`.
-pub struct PcidServerHandle {
-    pcid_to_client: File,
-    pcid_from_client: File,
-}
 
-pub(crate) fn send<W: Write, T: Serialize>(w: &mut W, message: &T) -> Result<()> {
-    let mut data = Vec::new();
+/// The ID of the frames from pcid that carry a `PcidNotification` rather than a response.
+/// Requests are numbered from 1.
+const NOTIFICATION_ID: u64 = 0;
+
+/// The largest frame that is sent or accepted, so that a corrupt length cannot exhaust memory.
+pub const MAX_FRAME_SIZE: u64 = 0x100_000;
+
+/// Write a frame: its length, the ID of the request it is or answers, and the bincode-encoded
+/// message. The frame is written at once, so that frames from several threads don't interleave.
+pub(crate) fn send_frame<W: Write, T: Serialize>(w: &mut W, id: u64, message: &T) -> Result<()> {
+    let mut data = u64::to_le_bytes(0).to_vec();
+    data.extend_from_slice(&u64::to_le_bytes(id));
     bincode::serialize_into(&mut data, message)?;
-    let length_bytes = u64::to_le_bytes(data.len() as u64);
-    w.write_all(&length_bytes)?;
+
+    let length = data.len() as u64 - 8;
+    if length > MAX_FRAME_SIZE {
+        return Err(PcidClientHandleError::FrameTooLarge(length));
+    }
+    data[..8].copy_from_slice(&u64::to_le_bytes(length));
+
     w.write_all(&data)?;
     Ok(())
 }
-pub(crate) fn recv<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<T> {
+/// Read a frame, returning its ID and the still encoded message.
+pub(crate) fn recv_frame<R: Read>(r: &mut R) -> Result<(u64, Vec<u8>)> {
     let mut length_bytes = [0u8; 8];
     r.read_exact(&mut length_bytes)?;
     let length = u64::from_le_bytes(length_bytes);
-    if length > 0x100_000 {
-        panic!("pcid_interface: buffer too large");
+
+    if length > MAX_FRAME_SIZE {
+        return Err(PcidClientHandleError::FrameTooLarge(length));
+    }
+    if length < 8 {
+        return Err(PcidClientHandleError::MalformedFrame);
     }
-    let mut data = vec! [0u8; length as usize];
+    let mut data = vec![0u8; length as usize];
     r.read_exact(&mut data)?;
 
-    Ok(bincode::deserialize_from(&data[..])?)
+    let message = data.split_off(8);
+    let mut id_bytes = [0u8; 8];
+    id_bytes.copy_from_slice(&data);
+
+    Ok((u64::from_le_bytes(id_bytes), message))
+}
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(data)?)
+}
+
+/// A handle from a `pcid` client (e.g. `ahcid`) to `pcid`. Every method blocks until the response
+/// to its request has arrived; notifications that arrive meanwhile are queued.
+pub struct PcidServerHandle {
+    pcid_to_client: File,
+    pcid_from_client: File,
+    next_id: u64,
+    notifications: VecDeque<PcidNotification>,
+}
+
+/// The end of the channel that `pcid` serves a driver's requests on.
+pub struct PcidClientConnection {
+    pcid_to_client: Arc<Mutex<File>>,
+    pcid_from_client: File,
+}
+
+impl PcidClientConnection {
+    pub fn connect(pcid_to_client: RawFd, pcid_from_client: RawFd) -> Self {
+        Self {
+            pcid_to_client: Arc::new(Mutex::new(unsafe { File::from_raw_fd(pcid_to_client) })),
+            pcid_from_client: unsafe { File::from_raw_fd(pcid_from_client) },
+        }
+    }
+    /// Receive the next request and its ID. A request that cannot be decoded is returned as
+    /// `MalformedRequest`, after which the connection can still be used, unlike after other
+    /// errors.
+    pub fn recv_request(&mut self) -> Result<(u64, PcidClientRequest)> {
+        let (id, data) = recv_frame(&mut self.pcid_from_client)?;
+
+        match bincode::deserialize(&data) {
+            Ok(request) => Ok((id, request)),
+            Err(error) => Err(PcidClientHandleError::MalformedRequest(id, error)),
+        }
+    }
+    pub fn send_response(&mut self, id: u64, response: &PcidClientResponse) -> Result<()> {
+        send_frame(&mut *self.pcid_to_client.lock().unwrap(), id, response)
+    }
+    /// A handle for sending notifications to the client from other threads.
+    pub fn notifier(&self) -> PcidNotifier {
+        PcidNotifier {
+            pcid_to_client: Arc::clone(&self.pcid_to_client),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PcidNotifier {
+    pcid_to_client: Arc<Mutex<File>>,
+}
+
+impl PcidNotifier {
+    pub fn notify(&self, notification: &PcidNotification) -> Result<()> {
+        send_frame(&mut *self.pcid_to_client.lock().unwrap(), NOTIFICATION_ID, notification)
+    }
+}
+
+fn pipe() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
 }
 
 impl PcidServerHandle {
+    /// Create a handle connected over a pair of pipes to a `PcidClientConnection`, instead of to
+    /// `pcid`, so that the protocol and drivers can be tested without it.
+    pub fn loopback() -> Result<(Self, PcidClientConnection)> {
+        let (to_client_read, to_client_write) = pipe()?;
+        let (from_client_read, from_client_write) = pipe()?;
+
+        Ok((Self::connect(to_client_read, from_client_write)?, PcidClientConnection::connect(to_client_write, from_client_read)))
+    }
     pub fn connect(pcid_to_client: RawFd, pcid_from_client: RawFd) -> Result<Self> {
         Ok(Self {
             pcid_to_client: unsafe { File::from_raw_fd(pcid_to_client) },
             pcid_from_client: unsafe { File::from_raw_fd(pcid_from_client) },
+            next_id: NOTIFICATION_ID + 1,
+            notifications: VecDeque::new(),
         })
     }
     pub fn connect_default() -> Result<Self> {
@@ -185,45 +374,477 @@ impl PcidServerHandle {
 
         Self::connect(pcid_to_client_fd, pcid_from_client_fd)
     }
-    pub(crate) fn send(&mut self, req: &PcidClientRequest) -> Result<()> {
-        send(&mut self.pcid_from_client, req)
+    /// Turn the handle into one that can have several requests in flight, for use from an async
+    /// executor.
+    pub fn into_async(self) -> AsyncPcidServerHandle {
+        let shared = Arc::new(Mutex::new(AsyncShared {
+            notifications: self.notifications,
+            ..AsyncShared::default()
+        }));
+
+        let reader_shared = Arc::clone(&shared);
+        let pcid_to_client = self.pcid_to_client;
+        thread::spawn(move || read_frames(pcid_to_client, reader_shared));
+
+        AsyncPcidServerHandle {
+            pcid_from_client: Mutex::new(self.pcid_from_client),
+            shared,
+            next_id: AtomicU64::new(self.next_id),
+        }
+    }
+    /// Send a request and wait for its response.
+    fn call(&mut self, request: &PcidClientRequest) -> Result<PcidClientResponse> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        send_frame(&mut self.pcid_from_client, id, request)?;
+
+        loop {
+            let (frame_id, data) = recv_frame(&mut self.pcid_to_client)?;
+
+            if frame_id == id {
+                return decode(&data);
+            }
+            if frame_id == NOTIFICATION_ID {
+                // Notifications added in a newer version of pcid are skipped.
+                if let Ok(notification) = decode(&data) {
+                    self.notifications.push_back(notification);
+                }
+            }
+            // Anything else is the late response to a request that failed before its response
+            // was read.
+        }
+    }
+    /// Take a notification that arrived while waiting for a response, if any.
+    pub fn try_recv_notification(&mut self) -> Option<PcidNotification> {
+        self.notifications.pop_front()
     }
-    pub(crate) fn recv(&mut self) -> Result<PcidClientResponse> {
-        recv(&mut self.pcid_to_client)
+    /// Wait for the next notification.
+    pub fn recv_notification(&mut self) -> Result<PcidNotification> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(notification);
+        }
+        loop {
+            let (frame_id, data) = recv_frame(&mut self.pcid_to_client)?;
+
+            if frame_id == NOTIFICATION_ID {
+                if let Ok(notification) = decode(&data) {
+                    return Ok(notification);
+                }
+            }
+        }
     }
     pub fn fetch_config(&mut self) -> Result<SubdriverArguments> {
-        self.send(&PcidClientRequest::RequestConfig)?;
-        match self.recv()? {
+        match self.call(&PcidClientRequest::RequestConfig)? {
             PcidClientResponse::Config(a) => Ok(a),
             other => Err(PcidClientHandleError::InvalidResponse(other)),
         }
     }
     pub fn fetch_all_features(&mut self) -> Result<Vec<(PciFeature, FeatureStatus)>> {
-        self.send(&PcidClientRequest::RequestFeatures)?;
-        match self.recv()? {
+        match self.call(&PcidClientRequest::RequestFeatures)? {
             PcidClientResponse::AllFeatures(a) => Ok(a),
             other => Err(PcidClientHandleError::InvalidResponse(other)),
         }
     }
     pub fn feature_status(&mut self, feature: PciFeature) -> Result<FeatureStatus> {
-        self.send(&PcidClientRequest::FeatureStatus(feature))?;
-        match self.recv()? {
+        match self.call(&PcidClientRequest::FeatureStatus(feature))? {
             PcidClientResponse::FeatureStatus(feat, status) if feat == feature => Ok(status),
             other => Err(PcidClientHandleError::InvalidResponse(other)),
         }
     }
     pub fn enable_feature(&mut self, feature: PciFeature) -> Result<()> {
-        self.send(&PcidClientRequest::EnableFeature(feature))?;
-        match self.recv()? {
+        match self.call(&PcidClientRequest::EnableFeature(feature))? {
             PcidClientResponse::FeatureEnabled(feat) if feat == feature => Ok(()),
             other => Err(PcidClientHandleError::InvalidResponse(other)),
         }
     }
     pub fn feature_info(&mut self, feature: PciFeature) -> Result<PciFeatureInfo> {
-        self.send(&PcidClientRequest::FeatureInfo(feature))?;
-        match self.recv()? {
+        match self.call(&PcidClientRequest::FeatureInfo(feature))? {
+            PcidClientResponse::FeatureInfo(feat, info) if feat == feature => Ok(info),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    /// Fetch the PCI Express extended capabilities of the function, along with their offsets.
+    /// This is empty if the extended configuration space isn't accessible.
+    pub fn fetch_extended_capabilities(&mut self) -> Result<Vec<(u16, ExtendedCapability)>> {
+        match self.call(&PcidClientRequest::RequestExtendedCapabilities)? {
+            PcidClientResponse::ExtendedCapabilities(a) => Ok(a),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    /// Allocate `count` MSI or MSI-X vectors, and open the `irq:` handle of each. The handles are
+    /// in the order of the MSI message numbers or the MSI-X table entries.
+    pub fn allocate_interrupts(&mut self, count: u16, kind: InterruptKind) -> Result<Vec<File>> {
+        match self.call(&PcidClientRequest::AllocateInterrupts { count, kind })? {
+            PcidClientResponse::InterruptsAllocated(k, irqs) if k == kind => open_irqs(irqs),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    /// Reset the function, restoring its configuration afterwards.
+    pub fn reset_function(&mut self) -> Result<()> {
+        match self.call(&PcidClientRequest::ResetFunction)? {
+            PcidClientResponse::FunctionReset => Ok(()),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    /// Move the function into a power state.
+    pub fn set_power_state(&mut self, state: PowerState) -> Result<()> {
+        match self.call(&PcidClientRequest::SetPowerState(state))? {
+            PcidClientResponse::PowerStateSet(s) if s == state => Ok(()),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+}
+
+fn open_irqs(irqs: Vec<u8>) -> Result<Vec<File>> {
+    Ok(irqs.into_iter().map(|irq| File::create(format!("irq:{}", irq))).collect::<io::Result<Vec<_>>>()?)
+}
+
+#[derive(Default)]
+struct AsyncShared {
+    /// The requests whose responses are still awaited, by a `ResponseFuture` that hasn't been
+    /// dropped.
+    outstanding: BTreeSet<u64>,
+    responses: BTreeMap<u64, Result<PcidClientResponse>>,
+    response_wakers: BTreeMap<u64, Waker>,
+    notifications: VecDeque<PcidNotification>,
+    notification_waker: Option<Waker>,
+    /// Set once the reader thread has stopped, after which nothing more arrives.
+    disconnected: bool,
+}
+
+/// Read every frame from pcid, and hand it to the future waiting for it.
+fn read_frames(mut pcid_to_client: File, shared: Arc<Mutex<AsyncShared>>) {
+    // A malformed frame leaves the stream at an unknown position, so it ends the connection.
+    while let Ok((id, data)) = recv_frame(&mut pcid_to_client) {
+        let mut shared = shared.lock().unwrap();
+
+        if id == NOTIFICATION_ID {
+            if let Ok(notification) = decode(&data) {
+                shared.notifications.push_back(notification);
+                if let Some(waker) = shared.notification_waker.take() {
+                    waker.wake();
+                }
+            }
+        } else if shared.outstanding.contains(&id) {
+            shared.responses.insert(id, decode(&data));
+            if let Some(waker) = shared.response_wakers.remove(&id) {
+                waker.wake();
+            }
+        }
+    }
+
+    let mut shared = shared.lock().unwrap();
+    shared.disconnected = true;
+    for (_, waker) in std::mem::replace(&mut shared.response_wakers, BTreeMap::new()) {
+        waker.wake();
+    }
+    if let Some(waker) = shared.notification_waker.take() {
+        waker.wake();
+    }
+}
+
+/// A handle to `pcid` that can have several requests in flight, without blocking while waiting
+/// for their responses. A thread reads the responses, and wakes the futures waiting for them.
+pub struct AsyncPcidServerHandle {
+    pcid_from_client: Mutex<File>,
+    shared: Arc<Mutex<AsyncShared>>,
+    next_id: AtomicU64,
+}
+
+impl AsyncPcidServerHandle {
+    /// Send a request, returning a future that resolves to its response.
+    pub fn request(&self, request: &PcidClientRequest) -> Result<ResponseFuture> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.shared.lock().unwrap().outstanding.insert(id);
+        let future = ResponseFuture {
+            shared: Arc::clone(&self.shared),
+            id,
+        };
+        send_frame(&mut *self.pcid_from_client.lock().unwrap(), id, request)?;
+
+        Ok(future)
+    }
+    /// Wait for the next notification. Only one task should wait for notifications at a time.
+    pub fn next_notification(&self) -> NotificationFuture {
+        NotificationFuture {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+    pub async fn fetch_config(&self) -> Result<SubdriverArguments> {
+        match self.request(&PcidClientRequest::RequestConfig)?.await? {
+            PcidClientResponse::Config(a) => Ok(a),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn fetch_all_features(&self) -> Result<Vec<(PciFeature, FeatureStatus)>> {
+        match self.request(&PcidClientRequest::RequestFeatures)?.await? {
+            PcidClientResponse::AllFeatures(a) => Ok(a),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn feature_status(&self, feature: PciFeature) -> Result<FeatureStatus> {
+        match self.request(&PcidClientRequest::FeatureStatus(feature))?.await? {
+            PcidClientResponse::FeatureStatus(feat, status) if feat == feature => Ok(status),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn enable_feature(&self, feature: PciFeature) -> Result<()> {
+        match self.request(&PcidClientRequest::EnableFeature(feature))?.await? {
+            PcidClientResponse::FeatureEnabled(feat) if feat == feature => Ok(()),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn feature_info(&self, feature: PciFeature) -> Result<PciFeatureInfo> {
+        match self.request(&PcidClientRequest::FeatureInfo(feature))?.await? {
             PcidClientResponse::FeatureInfo(feat, info) if feat == feature => Ok(info),
             other => Err(PcidClientHandleError::InvalidResponse(other)),
         }
     }
+    pub async fn allocate_interrupts(&self, count: u16, kind: InterruptKind) -> Result<Vec<File>> {
+        match self.request(&PcidClientRequest::AllocateInterrupts { count, kind })?.await? {
+            PcidClientResponse::InterruptsAllocated(k, irqs) if k == kind => open_irqs(irqs),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn reset_function(&self) -> Result<()> {
+        match self.request(&PcidClientRequest::ResetFunction)?.await? {
+            PcidClientResponse::FunctionReset => Ok(()),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+    pub async fn set_power_state(&self, state: PowerState) -> Result<()> {
+        match self.request(&PcidClientRequest::SetPowerState(state))?.await? {
+            PcidClientResponse::PowerStateSet(s) if s == state => Ok(()),
+            other => Err(PcidClientHandleError::InvalidResponse(other)),
+        }
+    }
+}
+
+/// The response to a request sent through an `AsyncPcidServerHandle`.
+pub struct ResponseFuture {
+    shared: Arc<Mutex<AsyncShared>>,
+    id: u64,
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<PcidClientResponse>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+
+        if let Some(response) = shared.responses.remove(&self.id) {
+            shared.outstanding.remove(&self.id);
+            return Poll::Ready(response);
+        }
+        if shared.disconnected {
+            return Poll::Ready(Err(PcidClientHandleError::Disconnected));
+        }
+        shared.response_wakers.insert(self.id, context.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.outstanding.remove(&self.id);
+        shared.responses.remove(&self.id);
+        shared.response_wakers.remove(&self.id);
+    }
+}
+
+pub struct NotificationFuture {
+    shared: Arc<Mutex<AsyncShared>>,
+}
+
+impl Future for NotificationFuture {
+    type Output = Result<PcidNotification>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+
+        if let Some(notification) = shared.notifications.pop_front() {
+            return Poll::Ready(Ok(notification));
+        }
+        if shared.disconnected {
+            return Poll::Ready(Err(PcidClientHandleError::Disconnected));
+        }
+        shared.notification_waker = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::task::{RawWaker, RawWakerVTable};
+    use std::thread;
+
+    use super::*;
+
+    fn test_config() -> SubdriverArguments {
+        SubdriverArguments {
+            func: PciFunction {
+                bus_num: 1,
+                dev_num: 0,
+                func_num: 0,
+                bars: [PciBar::Memory64 { address: 0xFE80_0000, prefetchable: false }, PciBar::None, PciBar::None, PciBar::None, PciBar::None, PciBar::None],
+                bar_sizes: [0x4000, 0, 0, 0, 0, 0],
+                legacy_interrupt_line: 10,
+                legacy_interrupt_pin: Some(LegacyInterruptPin::IntA),
+                venid: 0x1B36,
+                devid: 0x0010,
+            },
+        }
+    }
+
+    /// Poll a future until it completes, without an executor.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        let waker = unsafe { Waker::from_raw(clone(std::ptr::null())) };
+        let mut context = Context::from_waker(&waker);
+
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut buffer = Vec::new();
+        send_frame(&mut buffer, 7, &PcidClientRequest::EnableFeature(PciFeature::MsiX)).unwrap();
+
+        let length = u64::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3], buffer[4], buffer[5], buffer[6], buffer[7]]);
+        assert_eq!(length as usize, buffer.len() - 8);
+
+        let (id, data) = recv_frame(&mut Cursor::new(&buffer)).unwrap();
+        assert_eq!(id, 7);
+        match decode(&data).unwrap() {
+            PcidClientRequest::EnableFeature(PciFeature::MsiX) => (),
+            other => panic!("unexpected request {:?}", other),
+        }
+        // A truncated frame is an error, rather than a partial message.
+        assert!(recv_frame(&mut Cursor::new(&buffer[..buffer.len() - 1])).is_err());
+    }
+
+    #[test]
+    fn test_malformed_frame() {
+        // A corrupt length is an error, rather than an allocation of that size.
+        let mut buffer = u64::to_le_bytes(u64::max_value()).to_vec();
+        buffer.extend_from_slice(&[0; 16]);
+        match recv_frame(&mut Cursor::new(&buffer)) {
+            Err(PcidClientHandleError::FrameTooLarge(length)) => assert_eq!(length, u64::max_value()),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut buffer = u64::to_le_bytes(4).to_vec();
+        buffer.extend_from_slice(&[0; 4]);
+        match recv_frame(&mut Cursor::new(&buffer)) {
+            Err(PcidClientHandleError::MalformedFrame) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // A request that can't be decoded keeps its ID, so that it can be answered.
+        let (handle, mut connection) = PcidServerHandle::loopback().unwrap();
+        let mut pcid_from_client = handle.pcid_from_client;
+        send_frame(&mut pcid_from_client, 3, &u32::max_value()).unwrap();
+        match connection.recv_request() {
+            Err(PcidClientHandleError::MalformedRequest(3, _)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (mut handle, mut connection) = PcidServerHandle::loopback().unwrap();
+        let notifier = connection.notifier();
+
+        let server = thread::spawn(move || {
+            while let Ok((id, request)) = connection.recv_request() {
+                let response = match request {
+                    PcidClientRequest::RequestConfig => PcidClientResponse::Config(test_config()),
+                    PcidClientRequest::FeatureStatus(feature) => {
+                        // A notification sent while a request is outstanding is queued by the client.
+                        notifier.notify(&PcidNotification::AerError { correctable: true, status: 0x40 }).unwrap();
+                        PcidClientResponse::FeatureStatus(feature, FeatureStatus::Disabled)
+                    }
+                    _ => PcidClientResponse::Error(PcidServerResponseError::ResetUnsupported),
+                };
+                connection.send_response(id, &response).unwrap();
+            }
+        });
+
+        let config = handle.fetch_config().unwrap();
+        assert_eq!((config.func.bus_num, config.func.venid, config.func.devid), (1, 0x1B36, 0x0010));
+        assert_eq!(config.func.bars[0], PciBar::Memory64 { address: 0xFE80_0000, prefetchable: false });
+        assert_eq!(config.func.bar_sizes[0], 0x4000);
+
+        assert!(handle.try_recv_notification().is_none());
+        assert_eq!(handle.feature_status(PciFeature::Msi).unwrap(), FeatureStatus::Disabled);
+        match handle.try_recv_notification() {
+            Some(PcidNotification::AerError { correctable: true, status: 0x40 }) => (),
+            other => panic!("unexpected notification {:?}", other),
+        }
+
+        match handle.reset_function() {
+            Err(PcidClientHandleError::InvalidResponse(PcidClientResponse::Error(PcidServerResponseError::ResetUnsupported))) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        drop(handle);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_async_out_of_order() {
+        let (handle, mut connection) = PcidServerHandle::loopback().unwrap();
+        let handle = handle.into_async();
+
+        let server = thread::spawn(move || {
+            // Answer the second request before the first, and notify in between.
+            let (first_id, first) = connection.recv_request().unwrap();
+            let (second_id, second) = connection.recv_request().unwrap();
+            match (first, second) {
+                (PcidClientRequest::RequestConfig, PcidClientRequest::FeatureStatus(PciFeature::MsiX)) => (),
+                other => panic!("unexpected requests {:?}", other),
+            }
+            connection.send_response(second_id, &PcidClientResponse::FeatureStatus(PciFeature::MsiX, FeatureStatus::Enabled)).unwrap();
+            connection.notifier().notify(&PcidNotification::Unbinding).unwrap();
+            connection.send_response(first_id, &PcidClientResponse::Config(test_config())).unwrap();
+        });
+
+        let config = handle.request(&PcidClientRequest::RequestConfig).unwrap();
+        let status = handle.request(&PcidClientRequest::FeatureStatus(PciFeature::MsiX)).unwrap();
+
+        match block_on(status).unwrap() {
+            PcidClientResponse::FeatureStatus(PciFeature::MsiX, FeatureStatus::Enabled) => (),
+            other => panic!("unexpected response {:?}", other),
+        }
+        match block_on(config).unwrap() {
+            PcidClientResponse::Config(config) => assert_eq!(config.func.devid, 0x0010),
+            other => panic!("unexpected response {:?}", other),
+        }
+        match block_on(handle.next_notification()).unwrap() {
+            PcidNotification::Unbinding => (),
+            other => panic!("unexpected notification {:?}", other),
+        }
+
+        // Once pcid has closed its end, requests fail instead of waiting forever.
+        server.join().unwrap();
+        match block_on(handle.fetch_config()) {
+            Err(PcidClientHandleError::Disconnected) | Err(PcidClientHandleError::IoError(_)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
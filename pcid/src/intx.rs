@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+
+use crate::config::InterruptRoute;
+use crate::scheme::format_address;
+use crate::State;
+
+/// The Interrupt Line value meaning that the routing is unknown, or that the pin isn't connected.
+pub const UNKNOWN_LINE: u8 = 0xFF;
+
+/// The pin on a bridge that an interrupt pin of a device behind it is wired to, following the
+/// swizzle of the PCI-to-PCI Bridge Architecture specification. Pins are numbered from 1 for INTA#.
+pub fn swizzle(pin: u8, dev_num: u8) -> u8 {
+    (pin - 1 + dev_num) % 4 + 1
+}
+
+/// The IRQ that an interrupt pin of a function is routed to. Unless there is a route for the
+/// device itself, the pin is followed through the bridges above it, until there is a route for
+/// one of them.
+pub fn route<P>(routes: &[InterruptRoute], address: (u8, u8, u8), pin: u8, parent: P) -> Option<u8>
+where
+    P: Fn((u8, u8, u8)) -> Option<(u8, u8, u8)>,
+{
+    let mut address = address;
+    let mut pin = pin;
+
+    loop {
+        let (bus_num, dev_num, _) = address;
+
+        if let Some(route) = routes.iter().find(|route| route.bus == bus_num && route.device == dev_num && route.pin_number() == pin) {
+            return Some(route.irq);
+        }
+        address = parent(address)?;
+        pin = swizzle(pin, dev_num);
+    }
+}
+
+/// Find the IRQ of every function using INTx# interrupts, once the whole hierarchy is known. The
+/// interrupt routes in the config take precedence over the Interrupt Line set by firmware, and
+/// the line register is updated to match.
+pub fn route_all(state: &State) {
+    let mut functions = state.functions.lock().unwrap();
+    let parents = functions.iter().map(|(&address, function)| (address, function.parent)).collect::<BTreeMap<_, _>>();
+
+    for (&address, function) in functions.iter_mut() {
+        let pin = function.header.interrupt_pin();
+        if pin == 0 || pin > 4 {
+            continue;
+        }
+
+        function.legacy_irq = match route(&state.config.interrupt_routes, address, pin, |address| parents.get(&address).and_then(|&parent| parent)) {
+            Some(irq) => {
+                let (bus_num, dev_num, func_num) = address;
+                let access = state.preferred_cfg_access();
+                unsafe {
+                    let data = access.read(bus_num, dev_num, func_num, 0x3C);
+                    access.write(bus_num, dev_num, func_num, 0x3C, (data & 0xFFFF_FF00) | u32::from(irq));
+                }
+                Some(irq)
+            }
+            None if function.header.interrupt_line() != UNKNOWN_LINE => Some(function.header.interrupt_line()),
+            None => {
+                println!("pcid: INTx routing of {} INT{}# is unknown", format_address(address), (b'A' + pin - 1) as char);
+                None
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::config::Config;
+    use super::{route, swizzle};
+
+    #[test]
+    fn test_swizzle() {
+        assert_eq!(swizzle(1, 0), 1);
+        assert_eq!(swizzle(1, 1), 2);
+        assert_eq!(swizzle(4, 1), 1);
+        assert_eq!(swizzle(2, 31), 2);
+    }
+
+    #[test]
+    fn test_route_through_bridges() {
+        let config: Config = toml::from_str(r#"
+            [[interrupt_routes]]
+            bus = 0
+            device = 0x1F
+            pin = 'A'
+            irq = 16
+
+            [[interrupt_routes]]
+            bus = 0
+            device = 0x1C
+            pin = 'C'
+            irq = 18
+        "#).unwrap();
+        assert!(config.interrupt_routes.iter().all(|route| route.validate().is_ok()));
+
+        // 01.00.0 and 01.01.0 are behind the root port at 00.1C.0, and 02.03.0 behind a bridge at
+        // 01.01.0.
+        let parents = [((1, 0, 0), (0, 0x1C, 0)), ((1, 1, 0), (0, 0x1C, 0)), ((2, 3, 0), (1, 1, 0))].iter().copied().collect::<BTreeMap<_, _>>();
+        let parent = |address: (u8, u8, u8)| parents.get(&address).copied();
+        let routes = &config.interrupt_routes;
+
+        assert_eq!(route(routes, (0, 0x1F, 2), 1, parent), Some(16));
+        assert_eq!(route(routes, (0, 0x1F, 2), 2, parent), None);
+        // INTC# of device 0 arrives at INTC# of the root port.
+        assert_eq!(route(routes, (1, 0, 0), 3, parent), Some(18));
+        // INTB# of device 1 is swizzled to INTC#.
+        assert_eq!(route(routes, (1, 1, 0), 2, parent), Some(18));
+        // INTC# of device 3 is swizzled to INTB# of the bridge, and then to INTC# of the root port.
+        assert_eq!(route(routes, (2, 3, 0), 3, parent), Some(18));
+        assert_eq!(route(routes, (2, 3, 0), 1, parent), None);
+    }
+}
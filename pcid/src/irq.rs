@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::mem;
+
+use syscall::flag::PhysmapFlags;
+
+use crate::pci::msi::MsixTableEntry;
+
+/// The IDT vector that IRQ 0 is delivered at; the vectors below it are used for exceptions.
+pub const IRQ_VECTOR_OFFSET: u8 = 32;
+
+/// Read the local APIC id of the bootstrap processor.
+pub fn read_bsp_apic_id() -> io::Result<u32> {
+    let mut buffer = [0u8; 8];
+
+    let mut file = File::open("irq:bsp")?;
+    let bytes_read = file.read(&mut buffer)?;
+
+    if bytes_read == 8 {
+        Ok(u64::from_le_bytes(buffer) as u32)
+    } else if bytes_read == 4 {
+        Ok(u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]))
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("`irq:bsp` responded with {} bytes", bytes_read)))
+    }
+}
+
+/// The IRQs that the `irq:` scheme lists as available, and that can be delivered at a valid
+/// vector.
+pub fn available_irqs() -> io::Result<BTreeSet<u8>> {
+    let mut irqs = BTreeSet::new();
+
+    for entry in fs::read_dir("irq:")? {
+        let entry = entry?;
+
+        let irq = match entry.file_name().to_str().and_then(|name| name.parse::<u8>().ok()) {
+            Some(irq) => irq,
+            None => continue,
+        };
+        if irq.checked_add(IRQ_VECTOR_OFFSET).is_some() {
+            irqs.insert(irq);
+        }
+    }
+    Ok(irqs)
+}
+
+/// Pick `count` IRQs that are available, and not already taken by another function. Multi-message
+/// MSI requires the vectors to be `contiguous`, in which case the first vector is also aligned to
+/// `count`, since the function sets the lower bits of the message data to the message number.
+pub fn pick_irqs(available: &BTreeSet<u8>, taken: &BTreeSet<u8>, count: usize, contiguous: bool) -> Option<Vec<u8>> {
+    let is_free = |irq: &u8| available.contains(irq) && !taken.contains(irq);
+
+    if !contiguous {
+        let irqs = available.iter().copied().filter(is_free).take(count).collect::<Vec<_>>();
+        return if irqs.len() == count { Some(irqs) } else { None };
+    }
+
+    available.iter().copied().filter(|&irq| (usize::from(irq) + usize::from(IRQ_VECTOR_OFFSET)) % count == 0).find_map(|first| {
+        let irqs = (0..count).map(|i| first.checked_add(i as u8)).collect::<Option<Vec<_>>>()?;
+
+        if irqs.iter().all(is_free) && irqs.last()?.checked_add(IRQ_VECTOR_OFFSET).is_some() {
+            Some(irqs)
+        } else {
+            None
+        }
+    })
+}
+
+/// The first entries of an MSI-X table, mapped into memory.
+pub struct MsixTable {
+    virt: usize,
+    entries: *mut MsixTableEntry,
+    len: usize,
+}
+
+impl MsixTable {
+    /// Map `len` entries of the MSI-X table located at the physical address `table_base`.
+    pub fn map(table_base: usize, len: usize) -> syscall::Result<Self> {
+        const PAGE_SIZE: usize = 4096;
+
+        let map_base = table_base & !(PAGE_SIZE - 1);
+        let map_size = (table_base - map_base + len * mem::size_of::<MsixTableEntry>() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let virt = unsafe { syscall::physmap(map_base, map_size, PhysmapFlags::PHYSMAP_WRITE | PhysmapFlags::PHYSMAP_NO_CACHE)? };
+
+        Ok(Self {
+            virt,
+            entries: (virt + (table_base - map_base)) as *mut MsixTableEntry,
+            len,
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn entry(&mut self, k: usize) -> &mut MsixTableEntry {
+        assert!(k < self.len);
+        unsafe { &mut *self.entries.add(k) }
+    }
+}
+
+impl Drop for MsixTable {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall::physunmap(self.virt) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use super::pick_irqs;
+
+    #[test]
+    fn test_pick_scattered() {
+        let available = [3, 5, 7, 9].iter().copied().collect::<BTreeSet<u8>>();
+        let taken = [5].iter().copied().collect::<BTreeSet<u8>>();
+
+        assert_eq!(pick_irqs(&available, &taken, 2, false), Some(vec![3, 7]));
+        assert_eq!(pick_irqs(&available, &taken, 4, false), None);
+    }
+
+    #[test]
+    fn test_pick_contiguous_aligned() {
+        let available = (1..16).collect::<BTreeSet<u8>>();
+        let taken = [8].iter().copied().collect::<BTreeSet<u8>>();
+
+        assert_eq!(pick_irqs(&available, &taken, 4, true), Some(vec![4, 5, 6, 7]));
+        // IRQ 8 is the only one delivered at a vector aligned to 8, but it's taken.
+        assert_eq!(pick_irqs(&available, &taken, 8, true), None);
+    }
+}
@@ -1,23 +1,40 @@
 #![feature(asm)]
 
-use std::fs::{File, metadata, read_dir};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
 use std::io::prelude::*;
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Command;
-use std::sync::{Arc, Mutex};
-use std::{env, io, i64, thread};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{cmp, env, io, thread};
 
+use syscall::data::Packet;
+use syscall::flag::CloneFlags;
 use syscall::iopl;
+use syscall::scheme::SchemeMut;
 
-use crate::config::Config;
-use crate::pci::{CfgAccess, Pci, PciIter, PciBar, PciBus, PciClass, PciDev, PciFunc, PciHeader, PciHeaderError, PciHeaderType};
+use crate::config::{Config, DriverConfig, RestartPolicy};
+use crate::pci::{CfgAccess, ConfigReader, ConfigWriter, Pci, PciBar, PciBus, PciClass, PciDev, PciFunc, PciHeader, PciHeaderType};
 use crate::pci::cap::Capability as PciCapability;
+use crate::pci::ext_cap::{ExtendedCapabilitiesIter, ExtendedCapability, ExtendedCapabilityOffsetsIter};
+use crate::driver_interface::{PcidClientConnection, PcidNotification, PcidNotifier};
 use crate::pcie::Pcie;
+use crate::scheme::PciScheme;
 
 mod config;
 mod driver_interface;
+mod intx;
+mod irq;
+mod monitor;
 mod pci;
 mod pcie;
+mod power;
+mod scheme;
+mod topology;
 
 pub struct DriverHandler {
     config: config::DriverConfig,
@@ -26,10 +43,12 @@ pub struct DriverHandler {
     func_num: u8,
     header: PciHeader,
     capabilities: Vec<(u8, PciCapability)>,
+    /// The configuration saved when the function left D0, to be restored when it returns.
+    saved_config: Option<power::SavedConfig>,
 
     state: Arc<State>,
 }
-fn with_pci_func_raw<T, F: FnOnce(&PciFunc) -> T>(pci: &Pci, bus_num: u8, dev_num: u8, func_num: u8, function: F) -> T {
+fn with_pci_func_raw<T, F: FnOnce(&PciFunc) -> T>(pci: &dyn CfgAccess, bus_num: u8, dev_num: u8, func_num: u8, function: F) -> T {
     let bus = PciBus {
         pci,
         num: bus_num,
@@ -46,7 +65,7 @@ fn with_pci_func_raw<T, F: FnOnce(&PciFunc) -> T>(pci: &Pci, bus_num: u8, dev_nu
 }
 impl DriverHandler {
     fn with_pci_func_raw<T, F: FnOnce(&PciFunc) -> T>(&self, function: F) -> T {
-        with_pci_func_raw(&self.state.pci, self.bus_num, self.dev_num, self.func_num, function)
+        with_pci_func_raw(&*self.state.pci, self.bus_num, self.dev_num, self.func_num, function)
     }
     fn respond(&mut self, request: driver_interface::PcidClientRequest, args: &driver_interface::SubdriverArguments) -> driver_interface::PcidClientResponse {
         use driver_interface::*;
@@ -60,6 +79,8 @@ impl DriverHandler {
                 PcidClientResponse::AllFeatures(self.capabilities.iter().filter_map(|(_, capability)| match capability {
                     PciCapability::Msi(msi) => Some((PciFeature::Msi, FeatureStatus::enabled(msi.enabled()))),
                     PciCapability::MsiX(msix) => Some((PciFeature::MsiX, FeatureStatus::enabled(msix.msix_enabled()))),
+                    PciCapability::Pcie(_) => Some((PciFeature::Pcie, FeatureStatus::Enabled)),
+                    PciCapability::PowerManagement(_) => Some((PciFeature::PowerManagement, FeatureStatus::Enabled)),
                     _ => None,
                 }).collect())
             }
@@ -70,7 +91,7 @@ impl DriverHandler {
                         None => return PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature)),
                     };
                     unsafe {
-                        with_pci_func_raw(&self.state.pci, self.bus_num, self.dev_num, self.func_num, |func| {
+                        with_pci_func_raw(&*self.state.pci, self.bus_num, self.dev_num, self.func_num, |func| {
                             capability.set_enabled(true);
                             capability.write_message_control(func, offset);
                         });
@@ -83,13 +104,23 @@ impl DriverHandler {
                         None => return PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature)),
                     };
                     unsafe {
-                        with_pci_func_raw(&self.state.pci, self.bus_num, self.dev_num, self.func_num, |func| {
+                        with_pci_func_raw(&*self.state.pci, self.bus_num, self.dev_num, self.func_num, |func| {
                             capability.set_msix_enabled(true);
                             capability.write_a(func, offset);
                         });
                     }
                     PcidClientResponse::FeatureEnabled(feature)
                 }
+                PciFeature::Pcie => if self.capabilities.iter().any(|(_, capability)| capability.as_pcie().is_some()) {
+                    PcidClientResponse::FeatureEnabled(feature)
+                } else {
+                    PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature))
+                }
+                PciFeature::PowerManagement => if self.capabilities.iter().any(|(_, capability)| capability.as_power_management().is_some()) {
+                    PcidClientResponse::FeatureEnabled(feature)
+                } else {
+                    PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature))
+                }
             }
             PcidClientRequest::FeatureStatus(feature) => PcidClientResponse::FeatureStatus(feature, match feature {
                 PciFeature::Msi => self.capabilities.iter().find_map(|(_, capability)| if let PciCapability::Msi(msi) = capability {
@@ -102,7 +133,25 @@ impl DriverHandler {
                 } else {
                     None
                 }).unwrap_or(FeatureStatus::Disabled),
+                PciFeature::Pcie => FeatureStatus::enabled(self.capabilities.iter().any(|(_, capability)| capability.as_pcie().is_some())),
+                PciFeature::PowerManagement => FeatureStatus::enabled(self.capabilities.iter().any(|(_, capability)| capability.as_power_management().is_some())),
             }),
+            PcidClientRequest::RequestExtendedCapabilities => {
+                let functions = self.state.functions.lock().unwrap();
+                PcidClientResponse::ExtendedCapabilities(functions.get(&(self.bus_num, self.dev_num, self.func_num)).map(|function| function.extended_capabilities.clone()).unwrap_or_default())
+            }
+            PcidClientRequest::AllocateInterrupts { count, kind } => match self.allocate_interrupts(count, kind, args.func.bars) {
+                Ok(irqs) => PcidClientResponse::InterruptsAllocated(kind, irqs),
+                Err(error) => PcidClientResponse::Error(error),
+            }
+            PcidClientRequest::ResetFunction => match self.reset_function(args.func.bars) {
+                Ok(()) => PcidClientResponse::FunctionReset,
+                Err(error) => PcidClientResponse::Error(error),
+            }
+            PcidClientRequest::SetPowerState(state) => match self.set_power_state(state, args.func.bars) {
+                Ok(()) => PcidClientResponse::PowerStateSet(state),
+                Err(error) => PcidClientResponse::Error(error),
+            }
             PcidClientRequest::FeatureInfo(feature) => PcidClientResponse::FeatureInfo(feature, match feature {
                 PciFeature::Msi => if let Some(info) = self.capabilities.iter().find_map(|(_, capability)| capability.as_msi()) {
                     PciFeatureInfo::Msi(*info)
@@ -114,39 +163,319 @@ impl DriverHandler {
                 } else {
                     return PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature));
                 }
+                PciFeature::Pcie => if let Some(info) = self.capabilities.iter().find_map(|(_, capability)| capability.as_pcie()) {
+                    PciFeatureInfo::Pcie(*info)
+                } else {
+                    return PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature));
+                }
+                PciFeature::PowerManagement => if let Some(info) = self.capabilities.iter().find_map(|(_, capability)| capability.as_power_management()) {
+                    PciFeatureInfo::PowerManagement(*info)
+                } else {
+                    return PcidClientResponse::Error(PcidServerResponseError::NonexistentFeature(feature));
+                }
             }),
         }
     }
-    fn handle_spawn(mut self, pcid_to_client_write: Option<usize>, pcid_from_client_read: Option<usize>, args: driver_interface::SubdriverArguments) {
+    /// Reserve `count` IRQs for the function and program the MSI capability or MSI-X table to
+    /// deliver them, returning the IRQ of each vector.
+    fn allocate_interrupts(&mut self, count: u16, kind: driver_interface::InterruptKind, bars: [PciBar; 6]) -> Result<Vec<u8>, driver_interface::PcidServerResponseError> {
         use driver_interface::*;
+        use crate::pci::msi::x86_64::{self as x86_64_msi, DeliveryMode};
+
+        let (feature, max_count) = match kind {
+            InterruptKind::Msi => (PciFeature::Msi, self.capabilities.iter().find_map(|(_, capability)| capability.as_msi()).map(|msi| u16::from(msi.max_vectors()))),
+            InterruptKind::MsiX => (PciFeature::MsiX, self.capabilities.iter().find_map(|(_, capability)| capability.as_msix()).map(|msix| msix.table_size())),
+        };
+        let max_count = max_count.ok_or(PcidServerResponseError::NonexistentFeature(feature))?;
+
+        if count == 0 || count > max_count || (kind == InterruptKind::Msi && !count.is_power_of_two()) {
+            return Err(PcidServerResponseError::InvalidInterruptCount(count));
+        }
+
+        let destination_id = match irq::read_bsp_apic_id() {
+            Ok(id) if id <= u32::from(u8::max_value()) => id as u8,
+            Ok(id) => {
+                eprintln!("pcid: BSP APIC id {} doesn't fit in an MSI message address", id);
+                return Err(PcidServerResponseError::InterruptAllocationFailed);
+            }
+            Err(err) => {
+                eprintln!("pcid: failed to read BSP APIC id: {}", err);
+                return Err(PcidServerResponseError::InterruptAllocationFailed);
+            }
+        };
+        let message_address = x86_64_msi::message_address(destination_id, false, false, 0b00);
+        let message_data = |irq: u8| x86_64_msi::message_data_edge_triggered(DeliveryMode::Fixed, irq + irq::IRQ_VECTOR_OFFSET);
+
+        let address = (self.bus_num, self.dev_num, self.func_num);
+        let mut interrupts = self.state.interrupts.lock().unwrap();
+
+        let available = irq::available_irqs().map_err(|err| {
+            eprintln!("pcid: failed to list available IRQs: {}", err);
+            PcidServerResponseError::InterruptAllocationFailed
+        })?;
+        // The IRQs previously allocated for this function are reused, since they are replaced.
+        let taken = interrupts.iter().filter(|&(&other, _)| other != address).flat_map(|(_, irqs)| irqs.iter().copied()).collect::<BTreeSet<u8>>();
+
+        let irqs = match irq::pick_irqs(&available, &taken, usize::from(count), kind == InterruptKind::Msi) {
+            Some(irqs) => irqs,
+            None => {
+                eprintln!("pcid: not enough free IRQs for {} {:?} vectors", count, kind);
+                return Err(PcidServerResponseError::InterruptAllocationFailed);
+            }
+        };
+
+        unsafe {
+            match kind {
+                InterruptKind::Msi => self.program_msi(message_address, message_data(irqs[0]) as u16, count),
+                InterruptKind::MsiX => self.program_msix(message_address, &irqs.iter().map(|&irq| message_data(irq)).collect::<Vec<_>>(), bars)?,
+            }
+        }
+
+        interrupts.insert(address, irqs.clone());
+        Ok(irqs)
+    }
+    /// Program the MSI capability to send `count` messages, the first of which carrying
+    /// `message_data`, and enable MSI in place of MSI-X.
+    unsafe fn program_msi(&mut self, message_address: u32, message_data: u16, count: u16) {
+        let (bus_num, dev_num, func_num) = (self.bus_num, self.dev_num, self.func_num);
+        let pci = &*self.state.pci;
 
-        if let (Some(pcid_to_client_fd), Some(pcid_from_client_fd)) = (pcid_to_client_write, pcid_from_client_read) {
-            let mut pcid_to_client = unsafe { File::from_raw_fd(pcid_to_client_fd as RawFd) };
-            let mut pcid_from_client = unsafe { File::from_raw_fd(pcid_from_client_fd as RawFd) };
+        for &mut (offset, ref mut capability) in self.capabilities.iter_mut() {
+            match capability {
+                PciCapability::MsiX(msix) => with_pci_func_raw(pci, bus_num, dev_num, func_num, |func| {
+                    msix.set_msix_enabled(false);
+                    msix.write_a(func, offset);
+                }),
+                PciCapability::Msi(msi) => with_pci_func_raw(pci, bus_num, dev_num, func_num, |func| {
+                    // Disable MSI while the message is changed, so that no interrupt is sent to a
+                    // half-written address.
+                    msi.set_enabled(false);
+                    msi.write_message_control(func, offset);
 
-            while let Ok(msg) = recv(&mut pcid_from_client) {
-                let response = self.respond(msg, &args);
-                send(&mut pcid_to_client, &response).unwrap();
+                    msi.set_message_address(u64::from(message_address));
+                    msi.set_message_data(message_data);
+                    msi.write_message(func, offset);
+
+                    if let Some(mask_bits) = msi.mask_bits() {
+                        msi.set_mask_bits(mask_bits & !(((1u64 << count) - 1) as u32));
+                        msi.write_mask_bits(func, offset);
+                    }
+
+                    msi.set_multi_message_enabled(count.trailing_zeros() as u8);
+                    msi.set_enabled(true);
+                    msi.write_message_control(func, offset);
+                }),
+                _ => (),
+            }
+        }
+    }
+    /// Program and unmask the first entries of the MSI-X table, one for each of `message_data`,
+    /// and enable MSI-X in place of MSI.
+    unsafe fn program_msix(&mut self, message_address: u32, message_data: &[u32], bars: [PciBar; 6]) -> Result<(), driver_interface::PcidServerResponseError> {
+        use syscall::io::Io;
+
+        let table_base = match self.capabilities.iter().find_map(|(_, capability)| capability.as_msix()) {
+            Some(msix) => msix.table_base_pointer(bars),
+            None => return Err(driver_interface::PcidServerResponseError::NonexistentFeature(driver_interface::PciFeature::MsiX)),
+        };
+        let mut table = match irq::MsixTable::map(table_base, message_data.len()) {
+            Ok(table) => table,
+            Err(err) => {
+                eprintln!("pcid: failed to map MSI-X table at {:#x}: {}", table_base, err);
+                return Err(driver_interface::PcidServerResponseError::InterruptAllocationFailed);
+            }
+        };
+
+        let (bus_num, dev_num, func_num) = (self.bus_num, self.dev_num, self.func_num);
+        let pci = &*self.state.pci;
+
+        for &mut (offset, ref mut capability) in self.capabilities.iter_mut() {
+            match capability {
+                PciCapability::Msi(msi) => with_pci_func_raw(pci, bus_num, dev_num, func_num, |func| {
+                    msi.set_enabled(false);
+                    msi.write_message_control(func, offset);
+                }),
+                PciCapability::MsiX(msix) => with_pci_func_raw(pci, bus_num, dev_num, func_num, |func| {
+                    // Keep every vector masked while the table is being written.
+                    msix.set_function_mask(true);
+                    msix.set_msix_enabled(true);
+                    msix.write_a(func, offset);
+
+                    for (k, &data) in message_data.iter().enumerate() {
+                        let entry = table.entry(k);
+                        entry.addr_lo.write(message_address);
+                        entry.addr_hi.write(0);
+                        entry.msg_data.write(data);
+                        entry.unmask();
+                    }
+
+                    msix.set_function_mask(false);
+                    msix.write_a(func, offset);
+                }),
+                _ => (),
             }
         }
+
+        Ok(())
     }
+    /// Reset the function, with a Function Level Reset when the PCI Express capability advertises
+    /// one, or otherwise by cycling it through D3hot.
+    fn reset_function(&mut self, bars: [PciBar; 6]) -> Result<(), driver_interface::PcidServerResponseError> {
+        let flr_offset = self.capabilities.iter().find_map(|&(offset, ref capability)| capability.as_pcie().filter(|pcie| pcie.function_level_reset_capable()).map(|_| offset));
+        let pm_offset = self.capabilities.iter().find_map(|&(offset, ref capability)| capability.as_power_management().filter(|pm| !pm.no_soft_reset()).map(|_| offset));
+
+        if flr_offset.is_none() && pm_offset.is_none() {
+            return Err(driver_interface::PcidServerResponseError::ResetUnsupported);
+        }
+
+        let saved_config = self.saved_config.take();
+        let capabilities = &self.capabilities;
+        let address = (self.bus_num, self.dev_num, self.func_num);
+
+        // The function may read as all ones until it has recovered, which mustn't be taken for
+        // it being gone.
+        self.state.resetting.lock().unwrap().insert(address);
+        let result = self.with_pci_func_raw(|func| unsafe {
+            let saved = saved_config.unwrap_or_else(|| power::save(func, capabilities, bars));
+
+            // Stop decoding and bus mastering, so that the function doesn't issue DMA while being
+            // reset.
+            func.write_u32(0x04, func.read_u32(0x04) & 0xFFF8);
+
+            let result = match (flr_offset, pm_offset) {
+                (Some(offset), _) => power::function_level_reset(func, offset),
+                (None, Some(offset)) => power::power_management_reset(func, offset),
+                (None, None) => unreachable!(),
+            };
+            if result.is_ok() {
+                power::restore(func, &saved);
+            }
+            result
+        });
+        self.state.resetting.lock().unwrap().remove(&address);
+        result
+    }
+    /// Move the function into a power state, saving its configuration when it leaves D0, and
+    /// restoring it when it comes back.
+    fn set_power_state(&mut self, state: driver_interface::PowerState, bars: [PciBar; 6]) -> Result<(), driver_interface::PcidServerResponseError> {
+        use driver_interface::{PciFeature, PcidServerResponseError, PowerState};
+        use crate::pci::pm::PowerManagementCapability;
+
+        let offset = self.capabilities.iter().find_map(|&(offset, ref capability)| capability.as_power_management().map(|_| offset)).ok_or(PcidServerResponseError::NonexistentFeature(PciFeature::PowerManagement))?;
+
+        let saved_config = &mut self.saved_config;
+        let capabilities = &self.capabilities;
+        let (bus_num, dev_num, func_num) = (self.bus_num, self.dev_num, self.func_num);
+
+        // Leaving D3hot resets functions without No_Soft_Reset, see `reset_function`.
+        self.state.resetting.lock().unwrap().insert((bus_num, dev_num, func_num));
+        let result = with_pci_func_raw(&*self.state.pci, bus_num, dev_num, func_num, |func| unsafe {
+            let current = PowerManagementCapability::parse(func, offset).power_state();
+
+            if current == PowerState::D0 && state != PowerState::D0 {
+                *saved_config = Some(power::save(func, capabilities, bars));
+            }
+
+            power::set_power_state(func, offset, state)?;
+
+            if state == PowerState::D0 {
+                if let Some(saved) = saved_config.take() {
+                    power::restore(func, &saved);
+                }
+            }
+            Ok(())
+        });
+        self.state.resetting.lock().unwrap().remove(&(bus_num, dev_num, func_num));
+        result
+    }
+    fn handle_spawn(mut self, connection: Option<PcidClientConnection>, args: driver_interface::SubdriverArguments) {
+        use driver_interface::*;
+
+        if let Some(mut connection) = connection {
+            loop {
+                let (id, response) = match connection.recv_request() {
+                    Ok((id, msg)) => (id, self.respond(msg, &args)),
+                    // Most likely a request from a newer driver_interface, which the driver may
+                    // be able to do without.
+                    Err(PcidClientHandleError::MalformedRequest(id, _)) => (id, PcidClientResponse::Error(PcidServerResponseError::UnknownRequest)),
+                    // The driver closed its end of the channel.
+                    Err(PcidClientHandleError::IoError(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => {
+                        println!("pcid: failed to receive request from driver: {}", err);
+                        break;
+                    }
+                };
+                if let Err(err) = connection.send_response(id, &response) {
+                    println!("pcid: failed to send response to driver: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// A function found while enumerating the bus.
+pub struct Function {
+    header: PciHeader,
+    bars: [PciBar; 6],
+    bar_sizes: [u64; 6],
+    capabilities: Vec<(u8, PciCapability)>,
+    /// Empty unless the extended configuration space is accessible.
+    extended_capabilities: Vec<(u16, ExtendedCapability)>,
+    /// The bridge this function was found behind, if any.
+    parent: Option<(u8, u8, u8)>,
+    /// The secondary and subordinate bus numbers, if this is a bridge.
+    bus_range: Option<(u8, u8)>,
+    /// The IRQ that INTx# is routed to, if the function uses it and the routing is known.
+    legacy_irq: Option<u8>,
 }
 
 pub struct State {
-    threads: Mutex<Vec<thread::JoinHandle<()>>>,
-    pci: Arc<Pci>,
+    /// Held while spawning a driver, see `spawn_driver`.
+    spawn_lock: Mutex<()>,
+    /// Every function found during enumeration, by bus, device and function number.
+    functions: Mutex<BTreeMap<(u8, u8, u8), Function>>,
+    /// The driver bound to each function, see `bind_driver`.
+    bindings: Mutex<BTreeMap<(u8, u8, u8), Binding>>,
+    next_binding_id: AtomicU64,
+    config: Config,
+    /// The IRQs allocated for MSI or MSI-X, by the function they were allocated for.
+    interrupts: Mutex<BTreeMap<(u8, u8, u8), Vec<u8>>>,
+    /// The functions being reset or changing power state, which the monitor leaves alone. Held
+    /// by the monitor while it checks a function.
+    resetting: Mutex<BTreeSet<(u8, u8, u8)>>,
+    /// The configuration space as reachable through port I/O, or a mock of it in tests.
+    pci: Arc<dyn CfgAccess + Send + Sync>,
     pcie: Option<Pcie>,
 }
 impl State {
+    fn new(pci: Arc<dyn CfgAccess + Send + Sync>, pcie: Option<Pcie>, config: Config) -> Self {
+        Self {
+            spawn_lock: Mutex::new(()),
+            functions: Mutex::new(BTreeMap::new()),
+            bindings: Mutex::new(BTreeMap::new()),
+            next_binding_id: AtomicU64::new(0),
+            config,
+            interrupts: Mutex::new(BTreeMap::new()),
+            resetting: Mutex::new(BTreeSet::new()),
+            pci,
+            pcie,
+        }
+    }
     fn preferred_cfg_access(&self) -> &dyn CfgAccess {
         self.pcie.as_ref().map(|pcie| pcie as &dyn CfgAccess).unwrap_or(&*self.pci as &dyn CfgAccess)
     }
+    /// The size of the configuration space that can be accessed for functions on a bus; the
+    /// extended configuration space is only reachable through ECAM.
+    fn config_space_size(&self, bus_num: u8) -> u16 {
+        match self.pcie {
+            Some(ref pcie) if pcie.covers_bus(bus_num) => 4096,
+            _ => 256,
+        }
+    }
 }
 
-fn handle_parsed_header(state: Arc<State>, config: &Config, bus_num: u8,
-                        dev_num: u8, func_num: u8, header: PciHeader) {
-    let pci = &state.pci;
-
+fn print_header(bus_num: u8, dev_num: u8, func_num: u8, header: &PciHeader) {
     let raw_class: u8 = header.class().into();
     let mut string = format!("PCI {:>02X}/{:>02X}/{:>02X} {:>04X}:{:>04X} {:>02X}.{:>02X}.{:>02X}.{:>02X} {:?}",
                              bus_num, dev_num, func_num, header.vendor_id(), header.device_id(), raw_class,
@@ -194,305 +523,610 @@ fn handle_parsed_header(state: Arc<State>, config: &Config, bus_num: u8,
     string.push('\n');
 
     print!("{}", string);
+}
 
-    for driver in config.drivers.iter() {
-        if let Some(class) = driver.class {
-            if class != raw_class { continue; }
-        }
+/// Size the BARs of a function, by writing all ones to each BAR register and reading back which
+/// address bits are hardwired to zero. The halves of a 64-bit BAR are sized together, and its size
+/// is stored at the index of the lower half.
+unsafe fn probe_bar_sizes(pci: &dyn CfgAccess, bus_num: u8, dev_num: u8, func_num: u8, bars: &[PciBar]) -> [u64; 6] {
+    let mut bar_sizes = [0u64; 6];
+
+    let probe = |offset: u8| -> u32 {
+        let original = pci.read(bus_num, dev_num, func_num, offset.into());
+        pci.write(bus_num, dev_num, func_num, offset.into(), 0xFFFFFFFF);
+        let new = pci.read(bus_num, dev_num, func_num, offset.into());
+        pci.write(bus_num, dev_num, func_num, offset.into(), original);
+        new
+    };
+
+    // Disable I/O and memory decoding while the BARs temporarily hold bogus addresses. The upper
+    // half of the dword is the status register, whose bits are cleared by writing ones.
+    let command = pci.read(bus_num, dev_num, func_num, 0x04) & 0xFFFF;
+    pci.write(bus_num, dev_num, func_num, 0x04, command & !0x3);
+
+    for (i, bar) in bars.iter().enumerate() {
+        let offset = 0x10 + (i as u8) * 4;
+
+        bar_sizes[i] = match bar {
+            PciBar::None => 0,
+            PciBar::Port(_) => {
+                // Only the lower 16 bits of I/O BARs are required to be implemented.
+                let masked = probe(offset) & 0xFFFC;
+                if masked == 0 { 0 } else { u64::from((!masked & 0xFFFF) + 1) }
+            }
+            PciBar::Memory32 { .. } => {
+                let masked = probe(offset) & 0xFFFFFFF0;
+                if masked == 0 { 0 } else { u64::from(!masked) + 1 }
+            }
+            PciBar::Memory64 { .. } => {
+                let masked = u64::from(probe(offset) & 0xFFFFFFF0) | (u64::from(probe(offset + 4)) << 32);
+                if masked == 0 { 0 } else { (!masked).wrapping_add(1) }
+            }
+        };
+    }
+
+    pci.write(bus_num, dev_num, func_num, 0x04, command);
+
+    bar_sizes
+}
+
+/// Size the BARs of a function and walk its capability list. This has to be done before any
+/// driver is started, since sizing a BAR temporarily overwrites it.
+fn probe_function(state: &State, bus_num: u8, dev_num: u8, func_num: u8, header: PciHeader) -> Function {
+    let pci = &state.pci;
+
+    let bars = {
+        let mut bars = [PciBar::None; 6];
+        let header_bars = header.bars();
+        bars[..header_bars.len()].copy_from_slice(header_bars);
+        bars
+    };
+    let bar_sizes = unsafe { probe_bar_sizes(&**pci, bus_num, dev_num, func_num, &bars[..header.bars().len()]) };
+
+    let capabilities = with_pci_func_raw(state.preferred_cfg_access(), bus_num, dev_num, func_num, |func| {
+        crate::pci::cap::CapabilitiesIter { inner: crate::pci::cap::CapabilityOffsetsIter::new(header.cap_pointer(), func) }.collect::<Vec<_>>()
+    });
+
+    // Port I/O can only reach the first 256 bytes.
+    let extended_capabilities = if state.config_space_size(bus_num) > 256 {
+        with_pci_func_raw(state.preferred_cfg_access(), bus_num, dev_num, func_num, |func| {
+            ExtendedCapabilitiesIter { inner: ExtendedCapabilityOffsetsIter::new(func) }.collect::<Vec<_>>()
+        })
+    } else {
+        Vec::new()
+    };
+
+    Function {
+        header,
+        bars,
+        bar_sizes,
+        capabilities,
+        extended_capabilities,
+        parent: None,
+        bus_range: None,
+        legacy_irq: None,
+    }
+}
+
+fn handle_parsed_header(state: &Arc<State>, bus_num: u8, dev_num: u8, func_num: u8, function: &Function) {
+    if let Some(driver) = state.config.best_match(&function.header) {
+        launch_driver(state, driver, bus_num, dev_num, func_num, function);
+    }
+}
+
+/// Bind a driver to a function, and spawn it.
+fn launch_driver(state: &Arc<State>, driver: &DriverConfig, bus_num: u8, dev_num: u8, func_num: u8, function: &Function) {
+    let pci = &state.pci;
+
+    let header = function.header;
+
+    let args = match driver.command {
+        Some(ref args) => args,
+        None => return,
+    };
+
+    // Drivers that can use MSI or MSI-X don't need the legacy IRQ, so they are still launched,
+    // with the line that means unknown.
+    if function.legacy_irq.is_none() && args.iter().any(|arg| arg == "$IRQ") {
+        println!("pcid: warning: INTx routing of {} is unknown, passing IRQ {:#04X} to {}", scheme::format_address((bus_num, dev_num, func_num)), intx::UNKNOWN_LINE, args[0]);
+    }
+
+    // Enable bus mastering, memory space, and I/O space
+    unsafe {
+        let mut data = pci.read(bus_num, dev_num, func_num, 0x04);
+        data |= 7;
+        pci.write(bus_num, dev_num, func_num, 0x04, data);
+    }
+
+    let irq = function.legacy_irq.unwrap_or(intx::UNKNOWN_LINE);
+    let interrupt_pin = header.interrupt_pin();
+
+    let bars = function.bars;
+    let bar_sizes = function.bar_sizes;
+    let capabilities = function.capabilities.clone();
+    println!("PCI DEVICE CAPABILITIES for {}: {:?}", args.iter().map(|string| string.as_ref()).nth(0).unwrap_or("[unknown]"), capabilities);
+
+    use driver_interface::LegacyInterruptPin;
 
-        if let Some(subclass) = driver.subclass {
-            if subclass != header.subclass() { continue; }
+    let legacy_interrupt_pin = match interrupt_pin {
+        0 => None,
+        1 => Some(LegacyInterruptPin::IntA),
+        2 => Some(LegacyInterruptPin::IntB),
+        3 => Some(LegacyInterruptPin::IntC),
+        4 => Some(LegacyInterruptPin::IntD),
+
+        other => {
+            println!("pcid: invalid interrupt pin: {}", other);
+            None
         }
+    };
+
+    let func = driver_interface::PciFunction {
+        bars,
+        bar_sizes,
+        bus_num,
+        dev_num,
+        func_num,
+        devid: header.device_id(),
+        legacy_interrupt_line: irq,
+        legacy_interrupt_pin,
+        venid: header.vendor_id(),
+    };
+
+    let subdriver_args = driver_interface::SubdriverArguments {
+        func,
+    };
+
+    let command = args.iter().map(|arg| match arg.as_str() {
+        "$BUS" => format!("{:>02X}", bus_num),
+        "$DEV" => format!("{:>02X}", dev_num),
+        "$FUNC" => format!("{:>02X}", func_num),
+        "$NAME" => format!("pci-{:>02X}.{:>02X}.{:>02X}", bus_num, dev_num, func_num),
+        "$BAR0" => format!("{}", bars[0]),
+        "$BAR1" => format!("{}", bars[1]),
+        "$BAR2" => format!("{}", bars[2]),
+        "$BAR3" => format!("{}", bars[3]),
+        "$BAR4" => format!("{}", bars[4]),
+        "$BAR5" => format!("{}", bars[5]),
+        "$BARSIZE0" => format!("{:>08X}", bar_sizes[0]),
+        "$BARSIZE1" => format!("{:>08X}", bar_sizes[1]),
+        "$BARSIZE2" => format!("{:>08X}", bar_sizes[2]),
+        "$BARSIZE3" => format!("{:>08X}", bar_sizes[3]),
+        "$BARSIZE4" => format!("{:>08X}", bar_sizes[4]),
+        "$BARSIZE5" => format!("{:>08X}", bar_sizes[5]),
+        "$IRQ" => format!("{}", irq),
+        "$VENID" => format!("{:>04X}", header.vendor_id()),
+        "$DEVID" => format!("{:>04X}", header.device_id()),
+        _ => arg.clone()
+    }).collect::<Vec<_>>();
+
+    let binding_id = state.next_binding_id.fetch_add(1, Ordering::Relaxed);
+    state.bindings.lock().unwrap().insert((bus_num, dev_num, func_num), Binding {
+        id: binding_id,
+        name: driver.name.clone().unwrap_or_else(|| command[0].clone()),
+        process_group: None,
+        exited: None,
+        notifier: None,
+    });
+
+    spawn_driver(state, DriverLaunch {
+        binding_id,
+        config: driver.clone(),
+        bus_num,
+        dev_num,
+        func_num,
+        header,
+        capabilities,
+        args: subdriver_args,
+        command,
+    }, 0);
+}
+
+/// A driver bound to a function. The binding outlives the driver process when it is restarted,
+/// and is removed once the driver is unbound, or exits for good.
+struct Binding {
+    /// Distinguishes the binding from later ones for the same function, so that the supervisor of
+    /// an unbound driver doesn't restart it.
+    id: u64,
+    name: String,
+    /// The process group of the running driver, which also contains the processes it forked.
+    /// None while the driver is waiting to be restarted.
+    process_group: Option<u32>,
+    /// Receives once every process in the process group has exited.
+    exited: Option<mpsc::Receiver<()>>,
+    /// Sends notifications over the channel of the running driver, if it has one.
+    notifier: Option<PcidNotifier>,
+}
+
+/// How long a driver may take to exit after being asked to terminate, before it is killed.
+const UNBIND_TIMEOUT: Duration = Duration::from_secs(5);
 
-        if let Some(interface) = driver.interface {
-            if interface != header.interface() { continue; }
+/// Everything needed to (re)start a driver for a function.
+#[derive(Clone)]
+struct DriverLaunch {
+    binding_id: u64,
+    config: config::DriverConfig,
+    bus_num: u8,
+    dev_num: u8,
+    func_num: u8,
+    header: PciHeader,
+    capabilities: Vec<(u8, PciCapability)>,
+    args: driver_interface::SubdriverArguments,
+    /// The program, followed by its arguments with all variables already substituted.
+    command: Vec<String>,
+}
+
+const RESTART_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// Spawn a driver, along with the threads that serve its channel and supervise it. `restarts`
+/// is the number of times this driver has already been restarted in a row.
+fn spawn_driver(state: &Arc<State>, launch: DriverLaunch, restarts: u32) {
+    let address = (launch.bus_num, launch.dev_num, launch.func_num);
+
+    let mut command = Command::new(&launch.command[0]);
+    command.args(&launch.command[1..]);
+
+    println!("PCID SPAWN {:?}", command);
+
+    // Pipes created here must not leak into a driver spawned concurrently by another
+    // supervisor thread, or that driver would keep them open.
+    let spawn_guard = state.spawn_lock.lock().unwrap();
+
+    let (connection, envs, client_fds) = if launch.config.channel_name.is_some() {
+        let mut fds1 = [0usize; 2];
+        let mut fds2 = [0usize; 2];
+
+        syscall::pipe2(&mut fds1, syscall::O_CLOEXEC).expect("pcid: failed to create pcid->client pipe");
+        syscall::pipe2(&mut fds2, syscall::O_CLOEXEC).expect("pcid: failed to create client->pcid pipe");
+
+        let [pcid_to_client_read, pcid_to_client_write] = fds1;
+        let [pcid_from_client_read, pcid_from_client_write] = fds2;
+
+        (Some(PcidClientConnection::connect(pcid_to_client_write as RawFd, pcid_from_client_read as RawFd)), vec! [("PCID_TO_CLIENT_FD", format!("{}", pcid_to_client_read)), ("PCID_FROM_CLIENT_FD", format!("{}", pcid_from_client_write))], vec! [pcid_to_client_read, pcid_from_client_write])
+    } else {
+        (None, vec! [], vec! [])
+    };
+
+    // The write end of this pipe is inherited by the driver and every process it forks, but is
+    // never written to. Once all of them have exited, reading returns EOF, which is how drivers
+    // that daemonize are still supervised.
+    let mut liveness_fds = [0usize; 2];
+    syscall::pipe2(&mut liveness_fds, syscall::O_CLOEXEC).expect("pcid: failed to create liveness pipe");
+    let [liveness_read, liveness_write] = liveness_fds;
+
+    // Every pipe is created close-on-exec, so that pcid's ends are never inherited by a driver,
+    // and only the ends meant for this driver are made inheritable.
+    for &fd in client_fds.iter().chain(Some(&liveness_write)) {
+        syscall::fcntl(fd, syscall::F_SETFD, 0).expect("pcid: failed to make pipe inheritable");
+    }
+
+    // Give the driver a process group of its own, so that it can be terminated along with any
+    // process it forks when it is unbound.
+    unsafe {
+        command.pre_exec(|| match syscall::setpgid(0, 0) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(io::Error::from_raw_os_error(err.errno)),
+        });
+    }
+    let spawn_result = command.envs(envs).spawn();
+
+    for fd in client_fds.into_iter().chain(Some(liveness_write)) {
+        let _ = syscall::close(fd);
+    }
+    drop(spawn_guard);
+
+    let mut child = match spawn_result {
+        Ok(child) => child,
+        Err(err) => {
+            println!("pcid: failed to execute {:?}: {}", command, err);
+            let _ = syscall::close(liveness_read);
+            remove_binding(state, address, launch.binding_id);
+            return;
         }
+    };
+    let started = Instant::now();
 
-        if let Some(ref ids) = driver.ids {
-            let mut device_found = false;
-            for (vendor, devices) in ids {
-                let vendor_without_prefix = vendor.trim_start_matches("0x");
-                let vendor = i64::from_str_radix(vendor_without_prefix, 16).unwrap() as u16;
+    let pid = child.id();
+    let (exited_sender, exited_receiver) = mpsc::channel();
 
-                if vendor != header.vendor_id() { continue; }
+    match state.bindings.lock().unwrap().get_mut(&address) {
+        Some(binding) if binding.id == launch.binding_id => {
+            binding.process_group = Some(pid);
+            binding.exited = Some(exited_receiver);
+            binding.notifier = connection.as_ref().map(PcidClientConnection::notifier);
+        }
+        // Unbound while spawning.
+        _ => kill_process_group(pid, syscall::SIGKILL),
+    }
 
-                for device in devices {
-                    if *device == header.device_id() {
-                        device_found = true;
-                        break;
-                    }
+    let driver_handler = DriverHandler {
+        bus_num: launch.bus_num,
+        dev_num: launch.dev_num,
+        func_num: launch.func_num,
+        config: launch.config.clone(),
+        header: launch.header,
+        state: Arc::clone(state),
+        capabilities: launch.capabilities.clone(),
+        saved_config: None,
+    };
+    let subdriver_args = launch.args.clone();
+    // The channel is served for as long as the driver keeps its end open, which usually
+    // outlives the process we spawned, since most drivers daemonize.
+    thread::spawn(move || {
+        driver_handler.handle_spawn(connection, subdriver_args);
+    });
+
+    let waiter_state = Arc::clone(state);
+    thread::spawn(move || {
+        let exited_successfully = match child.wait() {
+            Ok(status) => {
+                if !status.success() {
+                    println!("pcid: {:?} exited with {}", command, status);
                 }
+                status.success()
             }
-            if !device_found { continue; }
-        } else {
-            if let Some(vendor) = driver.vendor {
-                if vendor != header.vendor_id() { continue; }
+            Err(err) => {
+                println!("pcid: failed to wait for {:?}: {}", command, err);
+                false
             }
+        };
+        let daemonized = wait_for_liveness_eof(liveness_read);
 
-            if let Some(device) = driver.device {
-                if device != header.device_id() { continue; }
-            }
+        if daemonized {
+            println!("pcid: {:?} is no longer running", command);
         }
+        let _ = exited_sender.send(());
 
-        if let Some(ref device_id_range) = driver.device_id_range {
-            if header.device_id() < device_id_range.start  ||
-               device_id_range.end <= header.device_id() { continue; }
+        match waiter_state.bindings.lock().unwrap().get_mut(&address) {
+            Some(binding) if binding.id == launch.binding_id => {
+                binding.process_group = None;
+                binding.notifier = None;
+            }
+            // The driver was unbound, and must not be restarted.
+            _ => return,
         }
 
-        if let Some(ref args) = driver.command {
-            // Enable bus mastering, memory space, and I/O space
-            unsafe {
-                let mut data = pci.read(bus_num, dev_num, func_num, 0x04);
-                data |= 7;
-                pci.write(bus_num, dev_num, func_num, 0x04, data);
-            }
+        // Drivers are never asked to exit by pcid, so a daemon going away is always a failure.
+        let failed = !exited_successfully || daemonized;
 
-            // Set IRQ line to 9 if not set
-            let mut irq;
-            unsafe {
-                let mut data = pci.read(bus_num, dev_num, func_num, 0x3C);
-                irq = (data & 0xFF) as u8;
-                if irq == 0xFF {
-                    irq = 9;
-                }
-                data = (data & 0xFFFFFF00) | irq as u32;
-                pci.write(bus_num, dev_num, func_num, 0x3C, data);
-            }
+        let restart = match launch.config.restart.unwrap_or(RestartPolicy::Never) {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+        if !restart {
+            remove_binding(&waiter_state, address, launch.binding_id);
+            return;
+        }
 
-            let interrupt_pin = unsafe { pci.read(bus_num, dev_num, func_num, 0x3B) };
+        // A driver that stayed up for longer than the maximum backoff is considered to have
+        // recovered, so that occasional crashes don't eventually exhaust the retries.
+        let restarts = if started.elapsed() > RESTART_BACKOFF_MAX { 0 } else { restarts };
 
-            // Find BAR sizes
-            let mut bars = [PciBar::None; 6];
-            let mut bar_sizes = [0; 6];
-            unsafe {
-                let count = match header.header_type() {
-                    PciHeaderType::GENERAL => 6,
-                    PciHeaderType::PCITOPCI => 2,
-                    _ => 0,
-                };
+        let max_restarts = launch.config.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS);
+        if restarts >= max_restarts {
+            println!("pcid: {:?} failed {} times in a row, giving up", command, restarts + 1);
+            remove_binding(&waiter_state, address, launch.binding_id);
+            return;
+        }
 
-                for i in 0..count {
-                    bars[i] = header.get_bar(i);
+        let backoff = cmp::min(RESTART_BACKOFF_INITIAL * 2u32.saturating_pow(restarts), RESTART_BACKOFF_MAX);
+        println!("pcid: restarting {:?} in {:?} (attempt {}/{})", command, backoff, restarts + 1, max_restarts);
+        thread::sleep(backoff);
 
-                    let offset = 0x10 + (i as u8) * 4;
+        match waiter_state.bindings.lock().unwrap().get(&address) {
+            Some(binding) if binding.id == launch.binding_id => (),
+            _ => return,
+        }
+        spawn_driver(&waiter_state, launch, restarts + 1);
+    });
+}
 
-                    let original = pci.read(bus_num, dev_num, func_num, offset.into());
-                    pci.write(bus_num, dev_num, func_num, offset.into(), 0xFFFFFFFF);
+/// Block until every process holding the write end of a liveness pipe has exited, and close it.
+/// Returns whether any process was still holding it at the time of the call, i.e. whether the
+/// driver had daemonized.
+fn wait_for_liveness_eof(liveness_read: usize) -> bool {
+    let mut byte = [0u8];
 
-                    let new = pci.read(bus_num, dev_num, func_num, offset.into());
-                    pci.write(bus_num, dev_num, func_num, offset.into(), original);
+    let flags = syscall::fcntl(liveness_read, syscall::F_GETFL, 0).unwrap_or(0);
+    let _ = syscall::fcntl(liveness_read, syscall::F_SETFL, flags | syscall::O_NONBLOCK);
 
-                    let masked = if new & 1 == 1 {
-                        new & 0xFFFFFFFC
-                    } else {
-                        new & 0xFFFFFFF0
-                    };
+    let daemonized = match syscall::read(liveness_read, &mut byte) {
+        Err(err) if err.errno == syscall::EAGAIN => true,
+        _ => false,
+    };
 
-                    let size = !masked + 1;
-                    bar_sizes[i] = if size <= 1 {
-                        0
-                    } else {
-                        size
-                    };
-                }
+    if daemonized {
+        let _ = syscall::fcntl(liveness_read, syscall::F_SETFL, flags & !syscall::O_NONBLOCK);
+
+        loop {
+            match syscall::read(liveness_read, &mut byte) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(err) if err.errno == syscall::EINTR => continue,
+                Err(_) => break,
             }
+        }
+    }
 
-            let capabilities = {
-                let bus = PciBus {
-                    pci: state.preferred_cfg_access(),
-                    num: bus_num,
-                };
-                let dev = PciDev {
-                    bus: &bus,
-                    num: dev_num
-                };
-                let func = PciFunc {
-                    dev: &dev,
-                    num: func_num,
-                };
-                crate::pci::cap::CapabilitiesIter { inner: crate::pci::cap::CapabilityOffsetsIter::new(header.cap_pointer(), &func) }.collect::<Vec<_>>()
-            };
-            println!("PCI DEVICE CAPABILITIES for {}: {:?}", args.iter().map(|string| string.as_ref()).nth(0).unwrap_or("[unknown]"), capabilities);
+    let _ = syscall::close(liveness_read);
+    daemonized
+}
 
-            use driver_interface::LegacyInterruptPin;
+fn kill_process_group(process_group: u32, signal: usize) {
+    let _ = syscall::kill((-(process_group as isize)) as usize, signal);
+}
 
-            let legacy_interrupt_pin = match interrupt_pin {
-                0 => None,
-                1 => Some(LegacyInterruptPin::IntA),
-                2 => Some(LegacyInterruptPin::IntB),
-                3 => Some(LegacyInterruptPin::IntC),
-                4 => Some(LegacyInterruptPin::IntD),
+/// Remove a binding, unless it has already been replaced by another one.
+fn remove_binding(state: &State, address: (u8, u8, u8), binding_id: u64) {
+    let mut bindings = state.bindings.lock().unwrap();
 
-                other => {
-                    println!("pcid: invalid interrupt pin: {}", other);
-                    None
-                }
-            };
+    if bindings.get(&address).map(|binding| binding.id) == Some(binding_id) {
+        bindings.remove(&address);
+    }
+}
 
-            let func = driver_interface::PciFunction {
-                bars,
-                bar_sizes,
-                bus_num,
-                dev_num,
-                func_num,
-                devid: header.device_id(),
-                legacy_interrupt_line: irq,
-                legacy_interrupt_pin,
-                venid: header.vendor_id(),
-            };
+/// Bind a driver to a function that has none, and spawn it. Without a name, the driver that
+/// would have been chosen during enumeration is used.
+pub fn bind_driver(state: &Arc<State>, address: (u8, u8, u8), name: Option<&str>) -> syscall::Result<()> {
+    use syscall::error::{Error, EBUSY, ENODEV, ENOENT};
 
-            let subdriver_args = driver_interface::SubdriverArguments {
-                func,
-            };
+    if state.bindings.lock().unwrap().contains_key(&address) {
+        return Err(Error::new(EBUSY));
+    }
 
-            let mut args = args.iter();
-            if let Some(program) = args.next() {
-                let mut command = Command::new(program);
-                for arg in args {
-                    let arg = match arg.as_str() {
-                        "$BUS" => format!("{:>02X}", bus_num),
-                        "$DEV" => format!("{:>02X}", dev_num),
-                        "$FUNC" => format!("{:>02X}", func_num),
-                        "$NAME" => format!("pci-{:>02X}.{:>02X}.{:>02X}", bus_num, dev_num, func_num),
-                        "$BAR0" => format!("{}", bars[0]),
-                        "$BAR1" => format!("{}", bars[1]),
-                        "$BAR2" => format!("{}", bars[2]),
-                        "$BAR3" => format!("{}", bars[3]),
-                        "$BAR4" => format!("{}", bars[4]),
-                        "$BAR5" => format!("{}", bars[5]),
-                        "$BARSIZE0" => format!("{:>08X}", bar_sizes[0]),
-                        "$BARSIZE1" => format!("{:>08X}", bar_sizes[1]),
-                        "$BARSIZE2" => format!("{:>08X}", bar_sizes[2]),
-                        "$BARSIZE3" => format!("{:>08X}", bar_sizes[3]),
-                        "$BARSIZE4" => format!("{:>08X}", bar_sizes[4]),
-                        "$BARSIZE5" => format!("{:>08X}", bar_sizes[5]),
-                        "$IRQ" => format!("{}", irq),
-                        "$VENID" => format!("{:>04X}", header.vendor_id()),
-                        "$DEVID" => format!("{:>04X}", header.device_id()),
-                        _ => arg.clone()
-                    };
-                    command.arg(&arg);
-                }
+    let functions = state.functions.lock().unwrap();
+    let function = functions.get(&address).ok_or(Error::new(ENOENT))?;
 
-                println!("PCID SPAWN {:?}", command);
+    let driver = match name {
+        Some(name) => state.config.drivers.iter().find(|driver| driver.name.as_ref().map(String::as_str) == Some(name)).ok_or(Error::new(ENOENT))?,
+        None => state.config.best_match(&function.header).ok_or(Error::new(ENODEV))?,
+    };
 
-                let (pcid_to_client_write, pcid_from_client_read, envs) = if driver.channel_name.is_some() {
-                    let mut fds1 = [0usize; 2];
-                    let mut fds2 = [0usize; 2];
+    let (bus_num, dev_num, func_num) = address;
+    launch_driver(state, driver, bus_num, dev_num, func_num, function);
+    Ok(())
+}
 
-                    syscall::pipe2(&mut fds1, 0).expect("pcid: failed to create pcid->client pipe");
-                    syscall::pipe2(&mut fds2, 0).expect("pcid: failed to create client->pcid pipe");
+/// Terminate the driver bound to a function, and wait for it to exit. The function is then left
+/// with decoding, bus mastering and message signaled interrupts disabled, so that another driver
+/// can be bound to it from a clean state. Fails with `ETIMEDOUT` if the driver is still running
+/// even after being killed, in which case the function is left alone.
+pub fn unbind_driver(state: &State, address: (u8, u8, u8)) -> syscall::Result<()> {
+    use syscall::error::{Error, ENODEV, ETIMEDOUT};
 
-                    let [pcid_to_client_read, pcid_to_client_write] = fds1;
-                    let [pcid_from_client_read, pcid_from_client_write] = fds2;
+    let binding = state.bindings.lock().unwrap().remove(&address).ok_or(Error::new(ENODEV))?;
+    println!("pcid: unbinding {} from {}", binding.name, scheme::format_address(address));
 
-                    (Some(pcid_to_client_write), Some(pcid_from_client_read), vec! [("PCID_TO_CLIENT_FD", format!("{}", pcid_to_client_read)), ("PCID_FROM_CLIENT_FD", format!("{}", pcid_from_client_write))])
-                } else {
-                    (None, None, vec! [])
-                };
+    if let Some(notifier) = binding.notifier {
+        // Give the driver a chance to quiesce the device before it is asked to exit. A driver that
+        // already closed its channel just doesn't get the notification.
+        let _ = notifier.notify(&PcidNotification::Unbinding);
+    }
+    if let (Some(process_group), Some(exited)) = (binding.process_group, binding.exited) {
+        kill_process_group(process_group, syscall::SIGTERM);
 
-                match command.envs(envs).spawn() {
-                    Ok(mut child) => {
-                        let driver_handler = DriverHandler {
-                            bus_num,
-                            dev_num,
-                            func_num,
-                            config: driver.clone(),
-                            header,
-                            state: Arc::clone(&state),
-                            capabilities,
-                        };
-                        let thread = thread::spawn(move || {
-                            driver_handler.handle_spawn(pcid_to_client_write, pcid_from_client_read, subdriver_args);
-                        });
-                        match child.wait() {
-                            Ok(_status) => (),
-                            Err(err) => println!("pcid: failed to wait for {:?}: {}", command, err),
-                        }
-                    }
-                    Err(err) => println!("pcid: failed to execute {:?}: {}", command, err)
-                }
+        if exited.recv_timeout(UNBIND_TIMEOUT).is_err() {
+            println!("pcid: {} didn't exit within {:?}, killing it", binding.name, UNBIND_TIMEOUT);
+            kill_process_group(process_group, syscall::SIGKILL);
+
+            // A process that left the process group may still hold the liveness pipe, so the
+            // scheme mustn't wait for it forever.
+            if exited.recv_timeout(UNBIND_TIMEOUT).is_err() {
+                println!("pcid: {} is still running after being killed", binding.name);
+                return Err(Error::new(ETIMEDOUT));
             }
         }
     }
+
+    reset_function(state, address);
+    Ok(())
 }
 
-fn main() {
-    let mut config = Config::default();
+fn reset_function(state: &State, (bus_num, dev_num, func_num): (u8, u8, u8)) {
+    use crate::pci::cap::{MsiCapability, MsixCapability};
 
-    let mut args = env::args().skip(1);
-    if let Some(config_path) = args.next() {
-        if metadata(&config_path).unwrap().is_file() {
-            if let Ok(mut config_file) = File::open(&config_path) {
-                let mut config_data = String::new();
-                if let Ok(_) = config_file.read_to_string(&mut config_data) {
-                    config = toml::from_str(&config_data).unwrap_or(Config::default());
-                }
-            }
-        } else {
-            let paths = read_dir(&config_path).unwrap();
+    let pci = &*state.pci;
 
-            let mut config_data = String::new();
+    unsafe {
+        // Leave the status register alone, since its bits are cleared by writing ones.
+        let command = pci.read(bus_num, dev_num, func_num, 0x04) & 0xFFFF;
+        pci.write(bus_num, dev_num, func_num, 0x04, command & !0x7);
+    }
 
-            for path in paths {
-                if let Ok(mut config_file) = File::open(&path.unwrap().path()) {
-                    let mut tmp = String::new();
-                    if let Ok(_) = config_file.read_to_string(&mut tmp) {
-                        config_data.push_str(&tmp);
+    if let Some(function) = state.functions.lock().unwrap().get(&(bus_num, dev_num, func_num)) {
+        for &(offset, ref capability) in function.capabilities.iter() {
+            with_pci_func_raw(pci, bus_num, dev_num, func_num, |func| unsafe {
+                match capability {
+                    PciCapability::Msi(_) => {
+                        let mut msi = MsiCapability::parse(func, offset);
+                        msi.set_enabled(false);
+                        msi.set_multi_message_enabled(0);
+                        msi.write_message_control(func, offset);
                     }
+                    PciCapability::MsiX(_) => {
+                        let mut msix = MsixCapability { a: pci.read(bus_num, dev_num, func_num, u16::from(offset)), b: 0, c: 0 };
+                        msix.set_msix_enabled(false);
+                        msix.set_function_mask(false);
+                        msix.write_a(func, offset);
+                    }
+                    _ => (),
                 }
-            }
-
-            config = toml::from_str(&config_data).unwrap_or(Config::default());
+            });
         }
     }
+    state.interrupts.lock().unwrap().remove(&(bus_num, dev_num, func_num));
+}
 
-    let pci = Arc::new(Pci::new());
+fn main() {
+    let mut args = env::args().skip(1);
 
-    let state = Arc::new(State {
-        pci: Arc::clone(&pci),
-        pcie: match Pcie::new(Arc::clone(&pci)) {
-            Ok(pcie) => Some(pcie),
-            Err(error) => {
-                println!("Couldn't retrieve PCIe info, perhaps the kernel is not compiled with acpi? Using the PCI 3.0 configuration space instead. Error: {:?}", error);
-                None
+    let config = match args.next() {
+        Some(config_path) => {
+            let (config, errors) = Config::load(Path::new(&config_path));
+            for error in errors {
+                eprintln!("pcid: config: {}", error);
             }
-        },
-        threads: Mutex::new(Vec::new()),
-    });
+            config
+        }
+        None => Config::default(),
+    };
 
-    let pci = state.preferred_cfg_access();
+    let pci = Arc::new(Pci::new());
+
+    let pcie = match Pcie::new(Arc::clone(&pci)) {
+        Ok(pcie) => Some(pcie),
+        Err(error) => {
+            println!("Couldn't retrieve PCIe info, perhaps the kernel is not compiled with acpi? Using the PCI 3.0 configuration space instead. Error: {:?}", error);
+            None
+        }
+    };
+    let state = Arc::new(State::new(pci, pcie, config));
 
     print!("PCI BS/DV/FN VEND:DEVI CL.SC.IN.RV\n");
 
-    'bus: for bus in PciIter::new(pci) {
-        'dev: for dev in bus.devs() {
-            for func in dev.funcs() {
-                let func_num = func.num;
-                match PciHeader::from_reader(func) {
-                    Ok(header) => {
-                        handle_parsed_header(Arc::clone(&state), &config, bus.num, dev.num, func_num, header);
-                    }
-                    Err(PciHeaderError::NoDevice) => {
-                        if func_num == 0 {
-                            if dev.num == 0 {
-                                // println!("PCI {:>02X}: no bus", bus.num);
-                                continue 'bus;
-                            } else {
-                                // println!("PCI {:>02X}/{:>02X}: no dev", bus.num, dev.num);
-                                continue 'dev;
-                            }
-                        }
-                    },
-                    Err(PciHeaderError::UnknownHeaderType(id)) => {
-                        println!("pcid: unknown header type: {}", id);
-                    }
-                }
-            }
-        }
+    // Enumerate the whole bus before spawning anything, so that a slow driver cannot hold back
+    // the devices after it.
+    topology::enumerate(&state);
+    intx::route_all(&state);
+
+    // The scheme and the threads supervising drivers run in the background for as long as the
+    // system does. Forking only carries over the calling thread, so it happens before any driver
+    // is spawned, and the parent only returns once the drivers have been launched.
+    let mut ready_fds = [0usize; 2];
+    syscall::pipe2(&mut ready_fds, syscall::O_CLOEXEC).expect("pcid: failed to create ready pipe");
+    let [ready_read, ready_write] = ready_fds;
+    if unsafe { syscall::clone(CloneFlags::empty()).expect("pcid: failed to fork") } != 0 {
+        let _ = syscall::close(ready_write);
+        // Returns once the child has written to the pipe, or exited.
+        let mut byte = [0u8];
+        let _ = syscall::read(ready_read, &mut byte);
+        return;
     }
+    let _ = syscall::close(ready_read);
+
+    let mut socket = File::create(":pci").expect("pcid: failed to create pci scheme");
+
+    let addresses = state.functions.lock().unwrap().keys().copied().collect::<Vec<_>>();
+    for (bus_num, dev_num, func_num) in addresses {
+        let functions = state.functions.lock().unwrap();
+        let function = &functions[&(bus_num, dev_num, func_num)];
+        handle_parsed_header(&state, bus_num, dev_num, func_num, function);
+    }
+
+    let _ = syscall::write(ready_write, &[1]);
+    let _ = syscall::close(ready_write);
+
+    let monitor_state = Arc::clone(&state);
+    thread::spawn(move || monitor::run(&monitor_state));
 
-    for thread in state.threads.lock().unwrap().drain(..) {
-        thread.join().unwrap();
+    let mut scheme = PciScheme::new(Arc::clone(&state));
+    loop {
+        let mut packet = Packet::default();
+        socket.read_exact(&mut packet).expect("pcid: failed to read pci scheme");
+        scheme.handle(&mut packet);
+        socket.write_all(&packet).expect("pcid: failed to write pci scheme");
     }
 }
@@ -0,0 +1,109 @@
+//! Watching the functions that drivers are bound to, for errors logged by Advanced Error
+//! Reporting and for functions that are gone, so that their drivers can be notified.
+
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::Duration;
+
+use crate::driver_interface::PcidNotification;
+use crate::pci::CfgAccess;
+use crate::scheme::format_address;
+use crate::State;
+
+/// How often the functions are checked. Errors aren't delivered as interrupts to pcid, so they
+/// are polled for.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happened to a function since it was last checked. `aer_offset` is the offset of its AER
+/// capability, if it has one. The AER status registers are cleared, so that each error is only
+/// reported once.
+pub fn check_function(pci: &dyn CfgAccess, (bus_num, dev_num, func_num): (u8, u8, u8), aer_offset: Option<u16>) -> Vec<PcidNotification> {
+    // A function that is gone reads as all ones.
+    if unsafe { pci.read(bus_num, dev_num, func_num, 0x00) } == 0xFFFF_FFFF {
+        return vec![PcidNotification::FunctionRemoved];
+    }
+
+    let mut notifications = Vec::new();
+    if let Some(offset) = aer_offset {
+        // The Uncorrectable and Correctable Error Status registers
+        for &(status_offset, correctable) in [(0x04, false), (0x10, true)].iter() {
+            let status = unsafe { pci.read(bus_num, dev_num, func_num, offset + status_offset) };
+            if status != 0 {
+                // The status bits are cleared by writing ones.
+                unsafe { pci.write(bus_num, dev_num, func_num, offset + status_offset, status) };
+                notifications.push(PcidNotification::AerError { correctable, status });
+            }
+        }
+    }
+    notifications
+}
+
+/// Check every function that has a driver with a channel bound to it, forever. A function that
+/// is gone is reported once to the driver bound to it, and then no longer checked. Functions that
+/// are being reset are skipped, since they may read as all ones until they have recovered.
+pub fn run(state: &State) {
+    // The IDs of the bindings whose function is gone.
+    let mut removed = BTreeSet::new();
+
+    loop {
+        thread::sleep(MONITOR_INTERVAL);
+
+        let bound = state.bindings.lock().unwrap().iter().filter(|(_, binding)| !removed.contains(&binding.id)).filter_map(|(&address, binding)| {
+            binding.notifier.clone().map(|notifier| (address, binding.id, notifier))
+        }).collect::<Vec<_>>();
+
+        for (address, binding_id, notifier) in bound {
+            // Extended capabilities were only found if they are reachable through ECAM.
+            let aer_offset = state.functions.lock().unwrap().get(&address).and_then(|function| {
+                function.extended_capabilities.iter().find(|(_, capability)| capability.as_aer().is_some()).map(|&(offset, _)| offset)
+            });
+
+            let notifications = {
+                let resetting = state.resetting.lock().unwrap();
+                if resetting.contains(&address) {
+                    continue;
+                }
+                check_function(state.preferred_cfg_access(), address, aer_offset)
+            };
+            for notification in notifications {
+                if let PcidNotification::FunctionRemoved = notification {
+                    println!("pcid: {} is gone", format_address(address));
+                    removed.insert(binding_id);
+                }
+                // A driver that closed its channel just doesn't get the notification.
+                let _ = notifier.notify(&notification);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::driver_interface::PcidNotification;
+    use crate::pci::mock::{MockCfgAccess, QEMU_Q35_DUMP};
+    use super::check_function;
+
+    #[test]
+    fn test_check_function() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+
+        // A function with an AER capability that logged a Completion Timeout and a Bad TLP.
+        let mut config = vec![0u8; 4096];
+        config[0..4].copy_from_slice(&[0x36, 0x1B, 0x10, 0x00]);
+        config[0x100..0x104].copy_from_slice(&0x0001_0001u32.to_le_bytes());
+        config[0x104..0x108].copy_from_slice(&(1u32 << 14).to_le_bytes());
+        config[0x110..0x114].copy_from_slice(&(1u32 << 6).to_le_bytes());
+        mock.insert((2, 0, 0), &config);
+
+        match &check_function(&mock, (2, 0, 0), Some(0x100))[..] {
+            [PcidNotification::AerError { correctable: false, status: 0x4000 }, PcidNotification::AerError { correctable: true, status: 0x40 }] => (),
+            other => panic!("unexpected notifications {:?}", other),
+        }
+        assert!(check_function(&mock, (1, 0, 0), None).is_empty());
+
+        match &check_function(&mock, (3, 0, 0), Some(0x100))[..] {
+            [PcidNotification::FunctionRemoved] => (),
+            other => panic!("unexpected notifications {:?}", other),
+        }
+    }
+}
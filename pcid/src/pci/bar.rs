@@ -5,25 +5,95 @@ use serde::{Serialize, Deserialize};
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PciBar {
     None,
-    Memory(u32),
+    Memory32 {
+        address: u32,
+        prefetchable: bool,
+    },
+    /// A 64-bit memory BAR, which also occupies the BAR register after it. That register is
+    /// reported as `None`.
+    Memory64 {
+        address: u64,
+        prefetchable: bool,
+    },
     Port(u16)
 }
 
 impl PciBar {
+    pub const IO_SPACE_BIT: u32 = 1 << 0;
+    pub const MEMORY_TYPE_MASK: u32 = 0x0000_0006;
+    pub const MEMORY_TYPE_64: u32 = 0x0000_0004;
+    pub const PREFETCHABLE_BIT: u32 = 1 << 3;
+
     pub fn is_none(&self) -> bool {
         match self {
             &PciBar::None => true,
             _ => false,
         }
     }
+    /// Whether this BAR is a memory BAR that consumes two BAR registers.
+    pub fn is_64bit(&self) -> bool {
+        match self {
+            &PciBar::Memory64 { .. } => true,
+            _ => false,
+        }
+    }
+    pub fn is_prefetchable(&self) -> bool {
+        match self {
+            &PciBar::Memory32 { prefetchable, .. } | &PciBar::Memory64 { prefetchable, .. } => prefetchable,
+            _ => false,
+        }
+    }
+    /// The physical address of a memory BAR, regardless of its width.
+    pub fn memory_address(&self) -> Option<u64> {
+        match self {
+            &PciBar::Memory32 { address, .. } => Some(u64::from(address)),
+            &PciBar::Memory64 { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+    /// Whether the raw value of a BAR register describes the lower half of a 64-bit memory BAR.
+    pub fn raw_is_64bit(raw: u32) -> bool {
+        raw & Self::IO_SPACE_BIT == 0 && raw & Self::MEMORY_TYPE_MASK == Self::MEMORY_TYPE_64
+    }
+    /// Parse the raw values of consecutive BAR registers, pairing up the halves of 64-bit BARs.
+    pub fn parse_all(raw: &[u32], bars: &mut [PciBar]) {
+        assert_eq!(raw.len(), bars.len());
+
+        let mut i = 0;
+        while i < raw.len() {
+            match raw.get(i + 1) {
+                Some(&high) if Self::raw_is_64bit(raw[i]) => {
+                    let address = u64::from(raw[i] & 0xFFFF_FFF0) | (u64::from(high) << 32);
+
+                    bars[i] = if address == 0 {
+                        PciBar::None
+                    } else {
+                        PciBar::Memory64 {
+                            address,
+                            prefetchable: raw[i] & Self::PREFETCHABLE_BIT != 0,
+                        }
+                    };
+                    bars[i + 1] = PciBar::None;
+                    i += 2;
+                }
+                _ => {
+                    bars[i] = PciBar::from(raw[i]);
+                    i += 1;
+                }
+            }
+        }
+    }
 }
 
 impl From<u32> for PciBar {
     fn from(bar: u32) -> Self {
         if bar & 0xFFFFFFFC == 0 {
             PciBar::None
-        } else if bar & 1 == 0 {
-            PciBar::Memory(bar & 0xFFFFFFF0)
+        } else if bar & Self::IO_SPACE_BIT == 0 {
+            PciBar::Memory32 {
+                address: bar & 0xFFFFFFF0,
+                prefetchable: bar & Self::PREFETCHABLE_BIT != 0,
+            }
         } else {
             PciBar::Port((bar & 0xFFFC) as u16)
         }
@@ -33,7 +103,8 @@ impl From<u32> for PciBar {
 impl fmt::Display for PciBar {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &PciBar::Memory(address) => write!(f, "{:>08X}", address),
+            &PciBar::Memory32 { address, .. } => write!(f, "{:>08X}", address),
+            &PciBar::Memory64 { address, .. } => write!(f, "{:>016X}", address),
             &PciBar::Port(address) => write!(f, "{:>04X}", address),
             &PciBar::None => write!(f, "None")
         }
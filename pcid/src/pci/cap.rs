@@ -21,12 +21,13 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
-            assert_eq!(self.offset & 0xF8, self.offset, "capability must be dword aligned");
+            assert_eq!(self.offset & 0xFC, self.offset, "capability must be dword aligned");
 
             if self.offset == 0 { return None };
 
             let first_dword = dbg!(self.reader.read_u32(dbg!(u16::from(self.offset))));
-            let next = ((first_dword >> 8) & 0xFF) as u8;
+            // The lower two bits of the pointer are reserved.
+            let next = ((first_dword >> 8) & 0xFC) as u8;
 
             let offset = self.offset;
             self.offset = next;
@@ -38,6 +39,7 @@ where
 
 #[repr(u8)]
 pub enum CapabilityId {
+    PowerManagement = 0x01,
     Msi = 0x05,
     MsiX = 0x11,
     Pcie = 0x10,
@@ -74,8 +76,33 @@ pub enum MsiCapability {
 }
 
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// The PCI Express Capability structure. Only the registers that exist for the capability
+/// version and device/port type are read, the rest are left zeroed.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct PcieCapability {
+    pub capabilities: u16,
+    pub device_capabilities: u32,
+    pub device_control: u16,
+    pub device_status: u16,
+    pub link_capabilities: u32,
+    pub link_control: u16,
+    pub link_status: u16,
+    pub slot_capabilities: u32,
+    pub slot_control: u16,
+    pub slot_status: u16,
+    pub device_capabilities2: u32,
+    pub device_control2: u16,
+    pub link_capabilities2: u32,
+    pub link_control2: u16,
+}
+
+/// The PCI Power Management capability structure.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct PowerManagementCapability {
+    /// The Power Management Capabilities (PMC) register.
+    pub capabilities: u16,
+    /// The Power Management Control/Status (PMCSR) register.
+    pub control_status: u16,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
@@ -90,6 +117,7 @@ pub enum Capability {
     Msi(MsiCapability),
     MsiX(MsixCapability),
     Pcie(PcieCapability),
+    PowerManagement(PowerManagementCapability),
     Other(u8),
 }
 
@@ -106,6 +134,18 @@ impl Capability {
             _ => None,
         }
     }
+    pub fn as_pcie(&self) -> Option<&PcieCapability> {
+        match self {
+            &Self::Pcie(ref pcie) => Some(pcie),
+            _ => None,
+        }
+    }
+    pub fn as_power_management(&self) -> Option<&PowerManagementCapability> {
+        match self {
+            &Self::PowerManagement(ref pm) => Some(pm),
+            _ => None,
+        }
+    }
     pub fn as_msi_mut(&mut self) -> Option<&mut MsiCapability> {
         match self {
             &mut Self::Msi(ref mut msi) => Some(msi),
@@ -141,11 +181,14 @@ impl Capability {
         })
     }
     unsafe fn parse_pcie<R: ConfigReader>(reader: &R, offset: u8) -> Self {
-        // TODO
-        Self::Pcie(PcieCapability {})
+        Self::Pcie(PcieCapability::parse(reader, offset))
+    }
+    unsafe fn parse_power_management<R: ConfigReader>(reader: &R, offset: u8) -> Self {
+        Self::PowerManagement(PowerManagementCapability::parse(reader, offset))
     }
-    unsafe fn parse<R: ConfigReader>(reader: &R, offset: u8) -> Self {
-        assert_eq!(offset & 0xF8, offset, "capability must be dword aligned");
+    /// Parse the capability structure at `offset`, based on its ID.
+    pub unsafe fn parse<R: ConfigReader>(reader: &R, offset: u8) -> Self {
+        assert_eq!(offset & 0xFC, offset, "capability must be dword aligned");
 
         let dword = reader.read_u32(u16::from(offset));
         let capability_id = (dword & 0xFF) as u8;
@@ -156,6 +199,8 @@ impl Capability {
             Self::parse_msix(reader, offset)
         } else if capability_id == CapabilityId::Pcie as u8 {
             Self::parse_pcie(reader, offset)
+        } else if capability_id == CapabilityId::PowerManagement as u8 {
+            Self::parse_power_management(reader, offset)
         } else {
             Self::Other(capability_id)
             //panic!("unimplemented or malformed capability id: {}", capability_id)
@@ -0,0 +1,277 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+pub use super::cap::PcieCapability;
+use super::func::{ConfigReader, ConfigWriter};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum PcieDevicePortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RcIntegratedEndpoint,
+    RcEventCollector,
+    Reserved(u8),
+}
+
+impl From<u8> for PcieDevicePortType {
+    fn from(raw: u8) -> Self {
+        match raw {
+            0b0000 => Self::Endpoint,
+            0b0001 => Self::LegacyEndpoint,
+            0b0100 => Self::RootPort,
+            0b0101 => Self::UpstreamSwitchPort,
+            0b0110 => Self::DownstreamSwitchPort,
+            0b0111 => Self::PcieToPciBridge,
+            0b1000 => Self::PciToPcieBridge,
+            0b1001 => Self::RcIntegratedEndpoint,
+            0b1010 => Self::RcEventCollector,
+            reserved => Self::Reserved(reserved),
+        }
+    }
+}
+
+impl PcieDevicePortType {
+    /// Whether the function is attached to a link, and thus has valid link registers.
+    pub fn has_link(&self) -> bool {
+        match self {
+            Self::RcIntegratedEndpoint | Self::RcEventCollector => false,
+            _ => true,
+        }
+    }
+}
+
+/// A link speed, as encoded in the Link Capabilities and Link Status registers.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum PcieLinkSpeed {
+    /// 2.5 GT/s
+    Gen1,
+    /// 5.0 GT/s
+    Gen2,
+    /// 8.0 GT/s
+    Gen3,
+    /// 16.0 GT/s
+    Gen4,
+    /// 32.0 GT/s
+    Gen5,
+    Unknown(u8),
+}
+
+impl From<u8> for PcieLinkSpeed {
+    fn from(raw: u8) -> Self {
+        match raw {
+            1 => Self::Gen1,
+            2 => Self::Gen2,
+            3 => Self::Gen3,
+            4 => Self::Gen4,
+            5 => Self::Gen5,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl PcieLinkSpeed {
+    /// The raw transfer rate per lane, in megatransfers per second.
+    pub fn transfer_rate(&self) -> Option<u32> {
+        match self {
+            Self::Gen1 => Some(2_500),
+            Self::Gen2 => Some(5_000),
+            Self::Gen3 => Some(8_000),
+            Self::Gen4 => Some(16_000),
+            Self::Gen5 => Some(32_000),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PcieLinkSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.transfer_rate() {
+            Some(rate) => write!(f, "{}.{} GT/s", rate / 1000, (rate % 1000) / 100),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+impl PcieCapability {
+    pub const CAP_VERSION_MASK: u16 = 0x000F;
+    pub const CAP_DEVICE_PORT_TYPE_MASK: u16 = 0x00F0;
+    pub const CAP_DEVICE_PORT_TYPE_SHIFT: u8 = 4;
+    pub const CAP_SLOT_IMPLEMENTED_BIT: u16 = 1 << 8;
+
+    pub const DEVCAP_MAX_PAYLOAD_MASK: u32 = 0x0000_0007;
+    pub const DEVCAP_FLR_CAPABLE_BIT: u32 = 1 << 28;
+
+    pub const DEVCTL_MAX_PAYLOAD_MASK: u16 = 0x00E0;
+    pub const DEVCTL_MAX_PAYLOAD_SHIFT: u8 = 5;
+    pub const DEVCTL_MAX_READ_REQUEST_MASK: u16 = 0x7000;
+    pub const DEVCTL_MAX_READ_REQUEST_SHIFT: u8 = 12;
+    pub const DEVCTL_INITIATE_FLR_BIT: u16 = 1 << 15;
+
+    pub const DEVSTA_TRANSACTIONS_PENDING_BIT: u16 = 1 << 5;
+
+    pub const LINKCAP_MAX_SPEED_MASK: u32 = 0x0000_000F;
+    pub const LINKCAP_MAX_WIDTH_MASK: u32 = 0x0000_03F0;
+    pub const LINKCAP_MAX_WIDTH_SHIFT: u8 = 4;
+    pub const LINKCAP_PORT_NUMBER_SHIFT: u8 = 24;
+
+    pub const LINKSTA_SPEED_MASK: u16 = 0x000F;
+    pub const LINKSTA_WIDTH_MASK: u16 = 0x03F0;
+    pub const LINKSTA_WIDTH_SHIFT: u8 = 4;
+    pub const LINKSTA_TRAINING_BIT: u16 = 1 << 11;
+    pub const LINKSTA_DLL_LINK_ACTIVE_BIT: u16 = 1 << 13;
+
+    pub const SLOTCAP_ATTENTION_BUTTON_BIT: u32 = 1 << 0;
+    pub const SLOTCAP_POWER_CONTROLLER_BIT: u32 = 1 << 1;
+    pub const SLOTCAP_HOT_PLUG_SURPRISE_BIT: u32 = 1 << 5;
+    pub const SLOTCAP_HOT_PLUG_CAPABLE_BIT: u32 = 1 << 6;
+    pub const SLOTCAP_PHYSICAL_SLOT_SHIFT: u8 = 19;
+
+    pub const SLOTSTA_PRESENCE_DETECT_BIT: u16 = 1 << 6;
+
+    pub unsafe fn parse<R: ConfigReader>(reader: &R, offset: u8) -> Self {
+        let offset = u16::from(offset);
+
+        let mut this = Self {
+            capabilities: (reader.read_u32(offset) >> 16) as u16,
+            ..Self::default()
+        };
+
+        this.device_capabilities = reader.read_u32(offset + 0x04);
+        let dword = reader.read_u32(offset + 0x08);
+        this.device_control = dword as u16;
+        this.device_status = (dword >> 16) as u16;
+
+        if this.device_port_type().has_link() {
+            this.link_capabilities = reader.read_u32(offset + 0x0C);
+            let dword = reader.read_u32(offset + 0x10);
+            this.link_control = dword as u16;
+            this.link_status = (dword >> 16) as u16;
+        }
+        if this.slot_implemented() {
+            this.slot_capabilities = reader.read_u32(offset + 0x14);
+            let dword = reader.read_u32(offset + 0x18);
+            this.slot_control = dword as u16;
+            this.slot_status = (dword >> 16) as u16;
+        }
+        // The second set of registers only exists in version 2 and later.
+        if this.version() >= 2 {
+            this.device_capabilities2 = reader.read_u32(offset + 0x24);
+            this.device_control2 = reader.read_u32(offset + 0x28) as u16;
+
+            if this.device_port_type().has_link() {
+                this.link_capabilities2 = reader.read_u32(offset + 0x2C);
+                this.link_control2 = reader.read_u32(offset + 0x30) as u16;
+            }
+        }
+
+        this
+    }
+
+    /// Write the control registers that exist for the capability version and device/port type
+    /// back into configuration space. The status registers next to them are cleared by writing
+    /// ones, so they are written as zero.
+    pub unsafe fn write_controls<W: ConfigWriter>(&self, writer: &W, offset: u8) {
+        let offset = u16::from(offset);
+
+        writer.write_u32(offset + 0x08, u32::from(self.device_control));
+
+        if self.device_port_type().has_link() {
+            writer.write_u32(offset + 0x10, u32::from(self.link_control));
+        }
+        if self.slot_implemented() {
+            writer.write_u32(offset + 0x18, u32::from(self.slot_control));
+        }
+        if self.version() >= 2 {
+            writer.write_u32(offset + 0x28, u32::from(self.device_control2));
+
+            if self.device_port_type().has_link() {
+                writer.write_u32(offset + 0x30, u32::from(self.link_control2));
+            }
+        }
+    }
+
+    /// The version of the capability structure.
+    pub const fn version(&self) -> u8 {
+        (self.capabilities & Self::CAP_VERSION_MASK) as u8
+    }
+    pub fn device_port_type(&self) -> PcieDevicePortType {
+        PcieDevicePortType::from(((self.capabilities & Self::CAP_DEVICE_PORT_TYPE_MASK) >> Self::CAP_DEVICE_PORT_TYPE_SHIFT) as u8)
+    }
+    /// Whether the link of this port is connected to a slot, rather than an integrated
+    /// component. Only valid for root ports and downstream switch ports.
+    pub const fn slot_implemented(&self) -> bool {
+        self.capabilities & Self::CAP_SLOT_IMPLEMENTED_BIT != 0
+    }
+
+    /// The largest TLP payload the function supports, in bytes.
+    pub const fn max_payload_size_supported(&self) -> u16 {
+        128 << (self.device_capabilities & Self::DEVCAP_MAX_PAYLOAD_MASK)
+    }
+    /// Whether the function supports Function Level Reset.
+    pub const fn function_level_reset_capable(&self) -> bool {
+        self.device_capabilities & Self::DEVCAP_FLR_CAPABLE_BIT != 0
+    }
+    /// The TLP payload size currently programmed, in bytes. Writes issued by the function must not
+    /// exceed this.
+    pub const fn max_payload_size(&self) -> u16 {
+        128 << ((self.device_control & Self::DEVCTL_MAX_PAYLOAD_MASK) >> Self::DEVCTL_MAX_PAYLOAD_SHIFT)
+    }
+    /// The largest read request the function may issue, in bytes.
+    pub const fn max_read_request_size(&self) -> u16 {
+        128 << ((self.device_control & Self::DEVCTL_MAX_READ_REQUEST_MASK) >> Self::DEVCTL_MAX_READ_REQUEST_SHIFT)
+    }
+    /// Whether there are non-posted requests issued by the function that haven't completed yet.
+    pub const fn transactions_pending(&self) -> bool {
+        self.device_status & Self::DEVSTA_TRANSACTIONS_PENDING_BIT != 0
+    }
+
+    pub fn max_link_speed(&self) -> PcieLinkSpeed {
+        PcieLinkSpeed::from((self.link_capabilities & Self::LINKCAP_MAX_SPEED_MASK) as u8)
+    }
+    /// The maximum number of lanes.
+    pub const fn max_link_width(&self) -> u8 {
+        ((self.link_capabilities & Self::LINKCAP_MAX_WIDTH_MASK) >> Self::LINKCAP_MAX_WIDTH_SHIFT) as u8
+    }
+    pub const fn port_number(&self) -> u8 {
+        (self.link_capabilities >> Self::LINKCAP_PORT_NUMBER_SHIFT) as u8
+    }
+    /// The negotiated link speed.
+    pub fn current_link_speed(&self) -> PcieLinkSpeed {
+        PcieLinkSpeed::from((self.link_status & Self::LINKSTA_SPEED_MASK) as u8)
+    }
+    /// The negotiated number of lanes.
+    pub const fn negotiated_link_width(&self) -> u8 {
+        ((self.link_status & Self::LINKSTA_WIDTH_MASK) >> Self::LINKSTA_WIDTH_SHIFT) as u8
+    }
+    pub const fn link_training(&self) -> bool {
+        self.link_status & Self::LINKSTA_TRAINING_BIT != 0
+    }
+    pub const fn data_link_layer_link_active(&self) -> bool {
+        self.link_status & Self::LINKSTA_DLL_LINK_ACTIVE_BIT != 0
+    }
+
+    pub const fn attention_button_present(&self) -> bool {
+        self.slot_capabilities & Self::SLOTCAP_ATTENTION_BUTTON_BIT != 0
+    }
+    pub const fn power_controller_present(&self) -> bool {
+        self.slot_capabilities & Self::SLOTCAP_POWER_CONTROLLER_BIT != 0
+    }
+    pub const fn hot_plug_surprise(&self) -> bool {
+        self.slot_capabilities & Self::SLOTCAP_HOT_PLUG_SURPRISE_BIT != 0
+    }
+    pub const fn hot_plug_capable(&self) -> bool {
+        self.slot_capabilities & Self::SLOTCAP_HOT_PLUG_CAPABLE_BIT != 0
+    }
+    pub const fn physical_slot_number(&self) -> u16 {
+        (self.slot_capabilities >> Self::SLOTCAP_PHYSICAL_SLOT_SHIFT) as u16
+    }
+    pub const fn presence_detected(&self) -> bool {
+        self.slot_status & Self::SLOTSTA_PRESENCE_DETECT_BIT != 0
+    }
+}
@@ -0,0 +1,323 @@
+//! PCI Express extended capabilities, which live in the extended configuration space
+//! (0x100-0xFFF), and are thus only reachable through ECAM.
+
+use serde::{Serialize, Deserialize};
+
+use super::func::ConfigReader;
+
+/// The offset of the first extended capability.
+pub const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+
+/// The maximum number of extended capabilities that can fit in the extended configuration space,
+/// which bounds the walk in case the list contains a loop.
+const MAX_EXTENDED_CAPABILITIES: usize = (4096 - 256) / 4;
+
+pub struct ExtendedCapabilityOffsetsIter<'a, R> {
+    offset: u16,
+    reader: &'a R,
+    visited: usize,
+}
+impl<'a, R> ExtendedCapabilityOffsetsIter<'a, R> {
+    pub fn new(reader: &'a R) -> Self {
+        Self {
+            offset: EXTENDED_CAPABILITIES_START,
+            reader,
+            visited: 0,
+        }
+    }
+}
+impl<'a, R> Iterator for ExtendedCapabilityOffsetsIter<'a, R>
+where
+    R: ConfigReader
+{
+    type Item = u16;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset < EXTENDED_CAPABILITIES_START || self.visited >= MAX_EXTENDED_CAPABILITIES {
+            return None;
+        }
+
+        let header = unsafe { self.reader.read_u32(self.offset & 0xFFC) };
+
+        // An empty list is indicated by a header of zero, and a function that isn't PCI Express
+        // (or a broken one) returns all ones.
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+
+        let offset = self.offset;
+        self.offset = (header >> 20) as u16 & 0xFFC;
+        self.visited += 1;
+
+        Some(offset)
+    }
+}
+
+#[repr(u16)]
+pub enum ExtendedCapabilityId {
+    Aer = 0x0001,
+    DeviceSerialNumber = 0x0003,
+    VendorSpecific = 0x000B,
+    Acs = 0x000D,
+    SrIov = 0x0010,
+    ResizableBar = 0x0015,
+    Ltr = 0x0018,
+}
+
+/// Advanced Error Reporting.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct AerCapability {
+    pub uncorrectable_status: u32,
+    pub uncorrectable_mask: u32,
+    pub uncorrectable_severity: u32,
+    pub correctable_status: u32,
+    pub correctable_mask: u32,
+    pub capabilities_and_control: u32,
+    pub header_log: [u32; 4],
+}
+
+impl AerCapability {
+    pub const FIRST_ERROR_POINTER_MASK: u32 = 0x0000_001F;
+
+    /// The bit index in the Uncorrectable Error Status register of the error the header log
+    /// belongs to.
+    pub const fn first_error_pointer(&self) -> u8 {
+        (self.capabilities_and_control & Self::FIRST_ERROR_POINTER_MASK) as u8
+    }
+}
+
+/// Single Root I/O Virtualization.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct SrIovCapability {
+    pub capabilities: u32,
+    pub control: u16,
+    pub status: u16,
+    pub initial_vfs: u16,
+    pub total_vfs: u16,
+    pub num_vfs: u16,
+    pub function_dependency_link: u8,
+    pub first_vf_offset: u16,
+    pub vf_stride: u16,
+    pub vf_device_id: u16,
+    pub supported_page_sizes: u32,
+    pub system_page_size: u32,
+    pub vf_bars: [u32; 6],
+}
+
+impl SrIovCapability {
+    pub const CTL_VF_ENABLE_BIT: u16 = 1 << 0;
+    pub const CTL_VF_MSE_BIT: u16 = 1 << 3;
+
+    pub const fn vf_enabled(&self) -> bool {
+        self.control & Self::CTL_VF_ENABLE_BIT != 0
+    }
+}
+
+/// Access Control Services.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct AcsCapability {
+    pub capability: u16,
+    pub control: u16,
+}
+
+impl AcsCapability {
+    pub const SOURCE_VALIDATION_BIT: u16 = 1 << 0;
+    pub const TRANSLATION_BLOCKING_BIT: u16 = 1 << 1;
+    pub const P2P_REQUEST_REDIRECT_BIT: u16 = 1 << 2;
+    pub const P2P_COMPLETION_REDIRECT_BIT: u16 = 1 << 3;
+    pub const UPSTREAM_FORWARDING_BIT: u16 = 1 << 4;
+}
+
+/// Latency Tolerance Reporting.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct LtrCapability {
+    pub max_snoop_latency: u16,
+    pub max_no_snoop_latency: u16,
+}
+
+impl LtrCapability {
+    /// Decode a latency register into nanoseconds.
+    pub fn latency_ns(raw: u16) -> u64 {
+        let value = u64::from(raw & 0x03FF);
+        let scale = u32::from((raw >> 10) & 0x7);
+
+        if scale > 5 {
+            // reserved scales
+            return 0;
+        }
+        value * 32u64.pow(scale)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct ResizableBarEntry {
+    pub capability: u32,
+    pub control: u32,
+}
+
+impl ResizableBarEntry {
+    pub const CTL_BAR_INDEX_MASK: u32 = 0x0000_0007;
+    pub const CTL_BAR_SIZE_MASK: u32 = 0x0000_3F00;
+    pub const CTL_BAR_SIZE_SHIFT: u8 = 8;
+
+    /// The index of the BAR this entry controls.
+    pub const fn bar_index(&self) -> u8 {
+        (self.control & Self::CTL_BAR_INDEX_MASK) as u8
+    }
+    /// The currently programmed BAR size, in bytes.
+    pub const fn current_size(&self) -> u64 {
+        1 << (20 + ((self.control & Self::CTL_BAR_SIZE_MASK) >> Self::CTL_BAR_SIZE_SHIFT))
+    }
+    /// Iterate over all supported BAR sizes, in bytes.
+    pub fn supported_sizes(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..28u32).filter(move |&bit| self.capability & (1 << (bit + 4)) != 0).map(|bit| 1u64 << (20 + bit))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct VendorSpecificCapability {
+    pub vsec_id: u16,
+    pub vsec_rev: u8,
+    /// The length of the entire capability structure, in bytes.
+    pub vsec_length: u16,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum ExtendedCapability {
+    Aer(AerCapability),
+    DeviceSerialNumber(u64),
+    VendorSpecific(VendorSpecificCapability),
+    Acs(AcsCapability),
+    SrIov(SrIovCapability),
+    ResizableBar(Vec<ResizableBarEntry>),
+    Ltr(LtrCapability),
+    Other { id: u16, version: u8 },
+}
+
+impl ExtendedCapability {
+    pub fn as_aer(&self) -> Option<&AerCapability> {
+        match self {
+            &Self::Aer(ref aer) => Some(aer),
+            _ => None,
+        }
+    }
+    pub fn as_sriov(&self) -> Option<&SrIovCapability> {
+        match self {
+            &Self::SrIov(ref sriov) => Some(sriov),
+            _ => None,
+        }
+    }
+    pub fn device_serial_number(&self) -> Option<u64> {
+        match self {
+            &Self::DeviceSerialNumber(dsn) => Some(dsn),
+            _ => None,
+        }
+    }
+
+    unsafe fn parse_aer<R: ConfigReader>(reader: &R, offset: u16) -> Self {
+        Self::Aer(AerCapability {
+            uncorrectable_status: reader.read_u32(offset + 0x04),
+            uncorrectable_mask: reader.read_u32(offset + 0x08),
+            uncorrectable_severity: reader.read_u32(offset + 0x0C),
+            correctable_status: reader.read_u32(offset + 0x10),
+            correctable_mask: reader.read_u32(offset + 0x14),
+            capabilities_and_control: reader.read_u32(offset + 0x18),
+            header_log: [
+                reader.read_u32(offset + 0x1C),
+                reader.read_u32(offset + 0x20),
+                reader.read_u32(offset + 0x24),
+                reader.read_u32(offset + 0x28),
+            ],
+        })
+    }
+    unsafe fn parse_sriov<R: ConfigReader>(reader: &R, offset: u16) -> Self {
+        let control_status = reader.read_u32(offset + 0x08);
+        let initial_total = reader.read_u32(offset + 0x0C);
+        let num_dependency = reader.read_u32(offset + 0x10);
+        let offset_stride = reader.read_u32(offset + 0x14);
+
+        let mut vf_bars = [0u32; 6];
+        for (i, vf_bar) in vf_bars.iter_mut().enumerate() {
+            *vf_bar = reader.read_u32(offset + 0x24 + i as u16 * 4);
+        }
+
+        Self::SrIov(SrIovCapability {
+            capabilities: reader.read_u32(offset + 0x04),
+            control: control_status as u16,
+            status: (control_status >> 16) as u16,
+            initial_vfs: initial_total as u16,
+            total_vfs: (initial_total >> 16) as u16,
+            num_vfs: num_dependency as u16,
+            function_dependency_link: (num_dependency >> 16) as u8,
+            first_vf_offset: offset_stride as u16,
+            vf_stride: (offset_stride >> 16) as u16,
+            vf_device_id: (reader.read_u32(offset + 0x18) >> 16) as u16,
+            supported_page_sizes: reader.read_u32(offset + 0x1C),
+            system_page_size: reader.read_u32(offset + 0x20),
+            vf_bars,
+        })
+    }
+    unsafe fn parse_resizable_bar<R: ConfigReader>(reader: &R, offset: u16) -> Self {
+        // The number of entries is stored in the control register of the first one.
+        let count = (reader.read_u32(offset + 0x08) >> 5) & 0x7;
+
+        Self::ResizableBar((0..count as u16).map(|i| ResizableBarEntry {
+            capability: reader.read_u32(offset + 0x04 + i * 8),
+            control: reader.read_u32(offset + 0x08 + i * 8),
+        }).collect())
+    }
+    pub unsafe fn parse<R: ConfigReader>(reader: &R, offset: u16) -> Self {
+        assert_eq!(offset & 0xFFC, offset, "extended capability must be dword aligned");
+
+        let header = reader.read_u32(offset);
+        let id = header as u16;
+        let version = ((header >> 16) & 0xF) as u8;
+
+        if id == ExtendedCapabilityId::Aer as u16 {
+            Self::parse_aer(reader, offset)
+        } else if id == ExtendedCapabilityId::DeviceSerialNumber as u16 {
+            Self::DeviceSerialNumber(u64::from(reader.read_u32(offset + 0x04)) | (u64::from(reader.read_u32(offset + 0x08)) << 32))
+        } else if id == ExtendedCapabilityId::VendorSpecific as u16 {
+            let vsec_header = reader.read_u32(offset + 0x04);
+            Self::VendorSpecific(VendorSpecificCapability {
+                vsec_id: vsec_header as u16,
+                vsec_rev: ((vsec_header >> 16) & 0xF) as u8,
+                vsec_length: (vsec_header >> 20) as u16,
+            })
+        } else if id == ExtendedCapabilityId::Acs as u16 {
+            let dword = reader.read_u32(offset + 0x04);
+            Self::Acs(AcsCapability {
+                capability: dword as u16,
+                control: (dword >> 16) as u16,
+            })
+        } else if id == ExtendedCapabilityId::SrIov as u16 {
+            Self::parse_sriov(reader, offset)
+        } else if id == ExtendedCapabilityId::ResizableBar as u16 {
+            Self::parse_resizable_bar(reader, offset)
+        } else if id == ExtendedCapabilityId::Ltr as u16 {
+            let dword = reader.read_u32(offset + 0x04);
+            Self::Ltr(LtrCapability {
+                max_snoop_latency: dword as u16,
+                max_no_snoop_latency: (dword >> 16) as u16,
+            })
+        } else {
+            Self::Other { id, version }
+        }
+    }
+}
+
+pub struct ExtendedCapabilitiesIter<'a, R> {
+    pub inner: ExtendedCapabilityOffsetsIter<'a, R>,
+}
+
+impl<'a, R> Iterator for ExtendedCapabilitiesIter<'a, R>
+where
+    R: ConfigReader
+{
+    type Item = (u16, ExtendedCapability);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.inner.next()?;
+        Some((offset, unsafe { ExtendedCapability::parse(self.inner.reader, offset) }))
+    }
+}
@@ -21,7 +21,7 @@ bitflags! {
         /// A PCI-to-PCI bridge device (Type 0x02).
         const CARDBUSBRIDGE = 0b00000010;
         /// A multifunction device.
-        const MULTIFUNCTION = 0b01000000;
+        const MULTIFUNCTION = 0b10000000;
         /// Mask used for fetching the header type.
         const HEADER_TYPE   = 0b00000011;
     }
@@ -112,14 +112,10 @@ impl PciHeader {
             match header_type & PciHeaderType::HEADER_TYPE {
                 PciHeaderType::GENERAL => {
                     let bytes = unsafe { reader.read_range(16, 48) };
-                    let bars = [
-                        PciBar::from(LittleEndian::read_u32(&bytes[0..4])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[4..8])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[8..12])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[12..16])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[16..20])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[20..24])),
-                    ];
+                    let mut raw_bars = [0u32; 6];
+                    LittleEndian::read_u32_into(&bytes[0..24], &mut raw_bars);
+                    let mut bars = [PciBar::None; 6];
+                    PciBar::parse_all(&raw_bars, &mut bars);
                     let cardbus_cis_ptr = LittleEndian::read_u32(&bytes[24..28]);
                     let subsystem_vendor_id = LittleEndian::read_u16(&bytes[28..30]);
                     let subsystem_id = LittleEndian::read_u16(&bytes[30..32]);
@@ -139,10 +135,10 @@ impl PciHeader {
                 },
                 PciHeaderType::PCITOPCI => {
                     let bytes = unsafe { reader.read_range(16, 48) };
-                    let bars = [
-                        PciBar::from(LittleEndian::read_u32(&bytes[0..4])),
-                        PciBar::from(LittleEndian::read_u32(&bytes[4..8])),
-                    ];
+                    let mut raw_bars = [0u32; 2];
+                    LittleEndian::read_u32_into(&bytes[0..8], &mut raw_bars);
+                    let mut bars = [PciBar::None; 2];
+                    PciBar::parse_all(&raw_bars, &mut bars);
                     let primary_bus_num = bytes[8];
                     let secondary_bus_num = bytes[9];
                     let subordinate_bus_num = bytes[10];
@@ -256,6 +252,22 @@ impl PciHeader {
         }
     }
 
+    /// Return the Subsystem Vendor ID field, which only exists in the header of general devices.
+    pub fn subsystem_vendor_id(&self) -> Option<u16> {
+        match self {
+            &PciHeader::General { subsystem_vendor_id, .. } => Some(subsystem_vendor_id),
+            &PciHeader::PciToPci { .. } => None,
+        }
+    }
+
+    /// Return the Subsystem ID field, which only exists in the header of general devices.
+    pub fn subsystem_id(&self) -> Option<u16> {
+        match self {
+            &PciHeader::General { subsystem_id, .. } => Some(subsystem_id),
+            &PciHeader::PciToPci { .. } => None,
+        }
+    }
+
     /// Return the Interrupt Line field.
     pub fn interrupt_line(&self) -> u8 {
         match self {
@@ -264,6 +276,15 @@ impl PciHeader {
         }
     }
 
+    /// Return the Interrupt Pin field, 1 for INTA# up to 4 for INTD#, or 0 if the function doesn't
+    /// use INTx# interrupts.
+    pub fn interrupt_pin(&self) -> u8 {
+        match self {
+            &PciHeader::General { interrupt_pin, .. } | &PciHeader::PciToPci { interrupt_pin, .. } =>
+                interrupt_pin,
+        }
+    }
+
     pub fn cap_pointer(&self) -> u8 {
         match self {
             &PciHeader::General { cap_pointer, .. } | &PciHeader::PciToPci { cap_pointer, .. } => cap_pointer,
@@ -273,7 +294,7 @@ impl PciHeader {
 
 #[cfg(test)]
 impl<'a> ConfigReader for &'a [u8] {
-    unsafe fn read_u32(&self, offset: u8) -> u32 {
+    unsafe fn read_u32(&self, offset: u16) -> u32 {
         let offset = offset as usize;
         assert!(offset < self.len());
         LittleEndian::read_u32(&self[offset..offset + 4])
@@ -317,13 +338,15 @@ mod test {
         assert_eq!(header.class(), PciClass::Network);
         assert_eq!(header.subclass(), 0);
         assert_eq!(header.bars().len(), 6);
-        assert_eq!(header.get_bar(0), PciBar::Memory(0xf7500000));
+        assert_eq!(header.get_bar(0), PciBar::Memory32 { address: 0xf7500000, prefetchable: false });
         assert_eq!(header.get_bar(1), PciBar::None);
         assert_eq!(header.get_bar(2), PciBar::Port(0xb000));
-        assert_eq!(header.get_bar(3), PciBar::Memory(0xf7580000));
+        assert_eq!(header.get_bar(3), PciBar::Memory32 { address: 0xf7580000, prefetchable: false });
         assert_eq!(header.get_bar(4), PciBar::None);
         assert_eq!(header.get_bar(5), PciBar::None);
         assert_eq!(header.interrupt_line(), 10);
+        assert_eq!(header.subsystem_vendor_id(), Some(0x15d9));
+        assert_eq!(header.subsystem_id(), Some(0x1533));
     }
 
     #[test]
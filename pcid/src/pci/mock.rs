@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{CfgAccess, PciBar};
+
+/// A dump of a QEMU q35 machine with an NVMe controller behind a root port, in the format of
+/// `lspci -xxxx`.
+pub const QEMU_Q35_DUMP: &str = include_str!("../../testdata/qemu-q35.txt");
+
+#[derive(Debug, PartialEq)]
+pub enum MockParseError {
+    /// A line of configuration space bytes came before any function address, at a line number.
+    NoFunction(usize),
+    /// A function address couldn't be parsed.
+    InvalidAddress(usize),
+    /// A line of configuration space bytes couldn't be parsed, or was out of range.
+    InvalidBytes(usize),
+}
+
+struct MockFunction {
+    config: Vec<u8>,
+    /// The size of each BAR, which decides the address bits that read back as zero after writing
+    /// all ones. The size of a 64-bit BAR is stored at the index of its lower half.
+    bar_sizes: [u64; 6],
+}
+
+impl MockFunction {
+    fn new(config: &[u8]) -> Self {
+        let mut function = Self {
+            config: vec![0; if config.len() > 256 { 4096 } else { 256 }],
+            bar_sizes: [0; 6],
+        };
+        function.config[..config.len()].copy_from_slice(config);
+
+        // Without anything better to go on, assume each BAR is as large as its address alignment
+        // allows.
+        let mut bars = [PciBar::None; 6];
+        let count = function.bar_count();
+        PciBar::parse_all(&function.raw_bars()[..count], &mut bars[..count]);
+
+        for (size, bar) in function.bar_sizes.iter_mut().zip(bars.iter()) {
+            *size = match *bar {
+                PciBar::None => 0,
+                PciBar::Port(address) => u64::from(address & address.wrapping_neg()),
+                PciBar::Memory32 { .. } | PciBar::Memory64 { .. } => {
+                    let address = bar.memory_address().unwrap();
+                    address & address.wrapping_neg()
+                }
+            };
+        }
+        function
+    }
+    fn read(&self, offset: u16) -> u32 {
+        LittleEndian::read_u32(&self.config[usize::from(offset & !3)..])
+    }
+    /// The number of BAR registers, which depends on the header type.
+    fn bar_count(&self) -> usize {
+        match self.config[0x0E] & 0x7F {
+            0 => 6,
+            1 => 2,
+            _ => 1,
+        }
+    }
+    fn raw_bars(&self) -> [u32; 6] {
+        let mut raw = [0; 6];
+        for (i, value) in raw.iter_mut().enumerate().take(self.bar_count()) {
+            *value = self.read(0x10 + i as u16 * 4);
+        }
+        raw
+    }
+    /// The value that a BAR register takes when `value` is written to it, keeping the address
+    /// bits below the size of the BAR and the flag bits, which are read-only.
+    fn bar_value(&self, i: usize, value: u32) -> u32 {
+        let raw = self.raw_bars();
+        let current = raw[i];
+
+        // Find out whether the register is the upper half of a 64-bit BAR.
+        let mut j = 0;
+        while j < i {
+            if PciBar::raw_is_64bit(raw[j]) && j + 1 == i {
+                let mask = !(self.bar_sizes[j].wrapping_sub(1)) >> 32;
+                return if self.bar_sizes[j] == 0 { 0 } else { value & mask as u32 };
+            }
+            j += if PciBar::raw_is_64bit(raw[j]) { 2 } else { 1 };
+        }
+
+        let size = self.bar_sizes[i];
+        if size == 0 {
+            return current;
+        }
+        let (flags, address_mask) = if current & PciBar::IO_SPACE_BIT != 0 {
+            (current & 0x3, 0xFFFF_FFFC)
+        } else {
+            (current & 0xF, 0xFFFF_FFF0)
+        };
+        (value & address_mask & !(size.wrapping_sub(1) as u32)) | flags
+    }
+    fn write(&mut self, offset: u16, value: u32) {
+        let offset = offset & !3;
+
+        let value = match offset {
+            // The IDs, revision and class are read-only.
+            0x00 | 0x08 => return,
+            // The status register is cleared by writing ones.
+            0x04 => {
+                let status = (self.read(0x04) >> 16) & !(value >> 16);
+                (status << 16) | (value & 0xFFFF)
+            }
+            0x10..=0x24 if usize::from((offset - 0x10) / 4) < self.bar_count() => {
+                self.bar_value(usize::from((offset - 0x10) / 4), value)
+            }
+            _ => value,
+        };
+        LittleEndian::write_u32(&mut self.config[usize::from(offset)..], value);
+    }
+}
+
+/// A configuration space backed by memory, for testing enumeration without any hardware. Every
+/// function that wasn't inserted reads as all ones, like a missing device. Registers are
+/// writable, except for the IDs and the class, the status bits that are cleared by writing ones,
+/// and the BAR bits below the size of each BAR.
+pub struct MockCfgAccess {
+    functions: Mutex<BTreeMap<(u8, u8, u8), MockFunction>>,
+}
+
+impl MockCfgAccess {
+    pub fn new() -> Self {
+        Self {
+            functions: Mutex::new(BTreeMap::new()),
+        }
+    }
+    /// Parse the output of `lspci -xxxx` (or `-xxx`, which only dumps the first 256 bytes). Each
+    /// function starts with a line beginning with its address, and is followed by lines of the
+    /// offset and sixteen bytes.
+    pub fn from_lspci(dump: &str) -> Result<Self, MockParseError> {
+        let mock = Self::new();
+        let mut current: Option<((u8, u8, u8), Vec<u8>)> = None;
+
+        for (i, line) in dump.lines().enumerate() {
+            let line_num = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let first = line.split_whitespace().next().unwrap();
+
+            if first.contains('.') {
+                if let Some((address, config)) = current.take() {
+                    mock.insert(address, &config);
+                }
+                let address = parse_address(first).ok_or(MockParseError::InvalidAddress(line_num))?;
+                current = Some((address, Vec::new()));
+                continue;
+            }
+
+            let config = match current {
+                Some((_, ref mut config)) => config,
+                None => return Err(MockParseError::NoFunction(line_num)),
+            };
+            let offset = Some(first).filter(|first| first.ends_with(':')).and_then(|first| usize::from_str_radix(&first[..first.len() - 1], 16).ok()).ok_or(MockParseError::InvalidBytes(line_num))?;
+            let bytes = line.split_whitespace().skip(1).map(|byte| u8::from_str_radix(byte, 16).ok()).collect::<Option<Vec<u8>>>().ok_or(MockParseError::InvalidBytes(line_num))?;
+
+            if offset + bytes.len() > 4096 {
+                return Err(MockParseError::InvalidBytes(line_num));
+            }
+            if config.len() < offset + bytes.len() {
+                config.resize(offset + bytes.len(), 0);
+            }
+            config[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        if let Some((address, config)) = current {
+            mock.insert(address, &config);
+        }
+
+        Ok(mock)
+    }
+    /// Add a function, with the configuration space starting with `config`, and the rest zeroed.
+    pub fn insert(&self, address: (u8, u8, u8), config: &[u8]) {
+        assert!(config.len() <= 4096);
+        self.functions.lock().unwrap().insert(address, MockFunction::new(config));
+    }
+    /// Set the size of a BAR, for BAR sizing to find. For a 64-bit BAR, `bar` is the index of its
+    /// lower half.
+    pub fn set_bar_size(&self, address: (u8, u8, u8), bar: usize, size: u64) {
+        assert!(size == 0 || size.is_power_of_two());
+        self.functions.lock().unwrap().get_mut(&address).expect("no such function").bar_sizes[bar] = size;
+    }
+}
+
+/// Parse a function address of the form `BB:DD.F`, optionally preceded by a `DDDD:` domain.
+fn parse_address(address: &str) -> Option<(u8, u8, u8)> {
+    let mut parts = address.rsplit(|c| c == ':' || c == '.');
+
+    let func = u8::from_str_radix(parts.next()?, 16).ok().filter(|&func| func < 8)?;
+    let dev = u8::from_str_radix(parts.next()?, 16).ok().filter(|&dev| dev < 32)?;
+    let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+
+    Some((bus, dev, func))
+}
+
+impl CfgAccess for MockCfgAccess {
+    unsafe fn read_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32 {
+        self.read(bus, dev, func, offset)
+    }
+    unsafe fn read(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32 {
+        match self.functions.lock().unwrap().get(&(bus, dev, func)) {
+            Some(function) if usize::from(offset) < function.config.len() => function.read(offset),
+            _ => 0xFFFF_FFFF,
+        }
+    }
+    unsafe fn write_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16, value: u32) {
+        self.write(bus, dev, func, offset, value)
+    }
+    unsafe fn write(&self, bus: u8, dev: u8, func: u8, offset: u16, value: u32) {
+        if let Some(function) = self.functions.lock().unwrap().get_mut(&(bus, dev, func)) {
+            if usize::from(offset) < function.config.len() {
+                function.write(offset, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MockCfgAccess, MockParseError, QEMU_Q35_DUMP};
+    use super::super::{CfgAccess, PciHeader};
+    use super::super::bar::PciBar;
+
+    #[test]
+    fn test_parse_dump() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+
+        unsafe {
+            assert_eq!(mock.read(0, 0x1F, 2, 0), 0x2922_8086);
+            assert_eq!(mock.read(1, 0, 0, 0), 0x0010_1B36);
+            assert_eq!(mock.read(0, 2, 0, 0), 0xFFFF_FFFF);
+            // Only the first 256 bytes were dumped.
+            assert_eq!(mock.read(1, 0, 0, 0x100), 0xFFFF_FFFF);
+        }
+
+        assert_eq!(MockCfgAccess::from_lspci("00: 86 80").err(), Some(MockParseError::NoFunction(1)));
+        assert_eq!(MockCfgAccess::from_lspci("00:20.0 Bogus").err(), Some(MockParseError::InvalidAddress(1)));
+        assert_eq!(MockCfgAccess::from_lspci("0000:00:01.0 Bridge\n00: 86 zz").err(), Some(MockParseError::InvalidBytes(2)));
+    }
+
+    #[test]
+    fn test_bar_sizing() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+        mock.set_bar_size((1, 0, 0), 0, 0x4000);
+
+        unsafe {
+            // The 64-bit BAR of the NVMe controller.
+            mock.write(1, 0, 0, 0x10, 0xFFFF_FFFF);
+            mock.write(1, 0, 0, 0x14, 0xFFFF_FFFF);
+            assert_eq!(mock.read(1, 0, 0, 0x10), 0xFFFF_C004);
+            assert_eq!(mock.read(1, 0, 0, 0x14), 0xFFFF_FFFF);
+
+            // The I/O BAR of the AHCI controller, sized by its alignment.
+            mock.write(0, 0x1F, 2, 0x20, 0xFFFF_FFFF);
+            assert_eq!(mock.read(0, 0x1F, 2, 0x20), 0xFFFF_FFC1);
+            mock.write(0, 0x1F, 2, 0x20, 0xC041);
+
+            let header = PciHeader::from_reader(&mock_func_bytes(&mock, (0, 0x1F, 2))[..]).unwrap();
+            assert_eq!(header.get_bar(4), PciBar::Port(0xC040));
+
+            // The IDs are read-only.
+            mock.write(0, 0x1F, 2, 0x00, 0);
+            assert_eq!(mock.read(0, 0x1F, 2, 0), 0x2922_8086);
+        }
+    }
+
+    fn mock_func_bytes(mock: &MockCfgAccess, (bus, dev, func): (u8, u8, u8)) -> Vec<u8> {
+        (0..64).flat_map(|i| unsafe { mock.read(bus, dev, func, i * 4) }.to_le_bytes().to_vec()).collect()
+    }
+}
@@ -5,7 +5,7 @@ pub use self::bar::PciBar;
 pub use self::bus::{PciBus, PciBusIter};
 pub use self::class::PciClass;
 pub use self::dev::{PciDev, PciDevIter};
-pub use self::func::PciFunc;
+pub use self::func::{ConfigReader, ConfigWriter, PciFunc};
 pub use self::header::{PciHeader, PciHeaderError, PciHeaderType};
 
 mod bar;
@@ -13,9 +13,14 @@ mod bus;
 pub mod cap;
 mod class;
 mod dev;
+pub mod express;
+pub mod ext_cap;
 mod func;
 pub mod header;
+#[cfg(test)]
+pub mod mock;
 pub mod msi;
+pub mod pm;
 
 pub trait CfgAccess {
     unsafe fn read_nolock(&self, bus: u8, dev: u8, func: u8, offset: u16) -> u32;
@@ -119,7 +124,7 @@ impl<'pci> PciIter<'pci> {
 impl<'pci> Iterator for PciIter<'pci> {
     type Item = PciBus<'pci>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.num < 255 { /* TODO: Do not ignore 0xFF bus */
+        if self.num < 256 {
             let bus = PciBus {
                 pci: self.pci,
                 num: self.num as u8
@@ -108,6 +108,75 @@ impl MsiCapability {
         new_message_control |= (u16::from(log_mme) << Self::MC_MULTI_MESSAGE_ENABLE_SHIFT);
         self.set_message_control(new_message_control);
     }
+    /// The number of vectors the function is capable of using, a power of two from 1 to 32.
+    pub fn max_vectors(&self) -> u8 {
+        1 << self.multi_message_capable()
+    }
+
+    /// Set the message address. The upper half has to be zero, unless the function supports 64-bit
+    /// message addresses.
+    pub fn set_message_address(&mut self, address: u64) {
+        match self {
+            Self::_32BitAddress { ref mut message_address, .. } | Self::_32BitAddressWithPvm { ref mut message_address, .. } => {
+                assert_eq!(address >> 32, 0, "MSI message address doesn't fit in 32 bits");
+                *message_address = address as u32;
+            }
+            Self::_64BitAddress { ref mut message_address_lo, ref mut message_address_hi, .. } | Self::_64BitAddressWithPvm { ref mut message_address_lo, ref mut message_address_hi, .. } => {
+                *message_address_lo = address as u32;
+                *message_address_hi = (address >> 32) as u32;
+            }
+        }
+    }
+    /// Set the message data. When multiple messages are enabled, the function modifies the lower
+    /// bits of the data to indicate the vector.
+    pub fn set_message_data(&mut self, data: u16) {
+        match self {
+            Self::_32BitAddress { ref mut message_data, .. }
+                | Self::_64BitAddress { ref mut message_data, .. }
+                | Self::_32BitAddressWithPvm { ref mut message_data, .. }
+                | Self::_64BitAddressWithPvm { ref mut message_data, .. } => {
+                *message_data &= 0xFFFF_0000;
+                *message_data |= u32::from(data);
+            }
+        }
+    }
+    /// Write the message address and data registers into configuration space.
+    pub unsafe fn write_message<W: ConfigWriter>(&self, writer: &W, offset: u8) {
+        match self {
+            &Self::_32BitAddress { message_address, message_data, .. } | &Self::_32BitAddressWithPvm { message_address, message_data, .. } => {
+                writer.write_u32(u16::from(offset + 4), message_address);
+                writer.write_u32(u16::from(offset + 8), message_data);
+            }
+            &Self::_64BitAddress { message_address_lo, message_address_hi, message_data, .. } | &Self::_64BitAddressWithPvm { message_address_lo, message_address_hi, message_data, .. } => {
+                writer.write_u32(u16::from(offset + 4), message_address_lo);
+                writer.write_u32(u16::from(offset + 8), message_address_hi);
+                writer.write_u32(u16::from(offset + 12), message_data);
+            }
+        }
+    }
+    /// The per-vector mask bits, if the function supports per-vector masking.
+    pub fn mask_bits(&self) -> Option<u32> {
+        match self {
+            &Self::_32BitAddressWithPvm { mask_bits, .. } | &Self::_64BitAddressWithPvm { mask_bits, .. } => Some(mask_bits),
+            _ => None,
+        }
+    }
+    /// Set the per-vector mask bits. This is ignored if per-vector masking isn't supported.
+    pub fn set_mask_bits(&mut self, bits: u32) {
+        match self {
+            Self::_32BitAddressWithPvm { ref mut mask_bits, .. } | Self::_64BitAddressWithPvm { ref mut mask_bits, .. } => *mask_bits = bits,
+            _ => (),
+        }
+    }
+    /// Write the per-vector mask bits into configuration space, if the function supports per-vector
+    /// masking.
+    pub unsafe fn write_mask_bits<W: ConfigWriter>(&self, writer: &W, offset: u8) {
+        match self {
+            &Self::_32BitAddressWithPvm { mask_bits, .. } => writer.write_u32(u16::from(offset + 12), mask_bits),
+            &Self::_64BitAddressWithPvm { mask_bits, .. } => writer.write_u32(u16::from(offset + 16), mask_bits),
+            _ => (),
+        }
+    }
 }
 
 impl MsixCapability {
@@ -199,7 +268,7 @@ impl MsixCapability {
         }
         let base = bars[usize::from(self.table_bir())];
 
-        if let PciBar::Memory(ptr) = base {
+        if let Some(ptr) = base.memory_address() {
             ptr as usize + self.table_offset() as usize
         } else {
             panic!("MSI-X Table BIR referenced a non-memory BAR: {:?}", base);
@@ -215,7 +284,7 @@ impl MsixCapability {
         }
         let base = bars[usize::from(self.pba_bir())];
 
-        if let PciBar::Memory(ptr) = base {
+        if let Some(ptr) = base.memory_address() {
             ptr as usize + self.pba_offset() as usize
         } else {
             panic!("MSI-X PBA BIR referenced a non-memory BAR: {:?}", base);
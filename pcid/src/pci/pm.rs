@@ -0,0 +1,102 @@
+use serde::{Serialize, Deserialize};
+
+pub use super::cap::PowerManagementCapability;
+use super::func::{ConfigReader, ConfigWriter};
+
+/// A device power state, as set in the Power Management Control/Status register. D3cold is not
+/// included, since it can't be entered through the function itself.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    pub fn from_raw(raw: u8) -> Self {
+        match raw & 0b11 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+    pub fn as_raw(&self) -> u8 {
+        match self {
+            Self::D0 => 0,
+            Self::D1 => 1,
+            Self::D2 => 2,
+            Self::D3Hot => 3,
+        }
+    }
+}
+
+impl PowerManagementCapability {
+    pub const PMC_VERSION_MASK: u16 = 0x0007;
+    pub const PMC_D1_SUPPORT_BIT: u16 = 1 << 9;
+    pub const PMC_D2_SUPPORT_BIT: u16 = 1 << 10;
+    pub const PMC_PME_SUPPORT_MASK: u16 = 0xF800;
+    pub const PMC_PME_SUPPORT_SHIFT: u8 = 11;
+
+    pub const PMCSR_POWER_STATE_MASK: u16 = 0x0003;
+    pub const PMCSR_NO_SOFT_RESET_BIT: u16 = 1 << 3;
+    pub const PMCSR_PME_ENABLE_BIT: u16 = 1 << 8;
+    pub const PMCSR_PME_STATUS_BIT: u16 = 1 << 15;
+
+    pub unsafe fn parse<R: ConfigReader>(reader: &R, offset: u8) -> Self {
+        Self {
+            capabilities: (reader.read_u32(u16::from(offset)) >> 16) as u16,
+            control_status: reader.read_u32(u16::from(offset + 4)) as u16,
+        }
+    }
+
+    /// The version of the Power Management specification that the function complies with.
+    pub const fn version(&self) -> u8 {
+        (self.capabilities & Self::PMC_VERSION_MASK) as u8
+    }
+    pub const fn d1_supported(&self) -> bool {
+        self.capabilities & Self::PMC_D1_SUPPORT_BIT != 0
+    }
+    pub const fn d2_supported(&self) -> bool {
+        self.capabilities & Self::PMC_D2_SUPPORT_BIT != 0
+    }
+    /// Whether a power state can be entered. D0 and D3hot are supported by every function.
+    pub fn supports(&self, state: PowerState) -> bool {
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => self.d1_supported(),
+            PowerState::D2 => self.d2_supported(),
+        }
+    }
+    /// The power states from which the function can assert PME#, as a bitmask of D0, D1, D2,
+    /// D3hot and D3cold, from the lowest bit.
+    pub const fn pme_support(&self) -> u8 {
+        ((self.capabilities & Self::PMC_PME_SUPPORT_MASK) >> Self::PMC_PME_SUPPORT_SHIFT) as u8
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        PowerState::from_raw((self.control_status & Self::PMCSR_POWER_STATE_MASK) as u8)
+    }
+    pub fn set_power_state(&mut self, state: PowerState) {
+        self.control_status &= !Self::PMCSR_POWER_STATE_MASK;
+        self.control_status |= u16::from(state.as_raw());
+    }
+    /// Whether the function keeps its configuration when transitioning from D3hot to D0. If not,
+    /// the transition resets the function, and its configuration has to be restored.
+    pub const fn no_soft_reset(&self) -> bool {
+        self.control_status & Self::PMCSR_NO_SOFT_RESET_BIT != 0
+    }
+    pub const fn pme_enabled(&self) -> bool {
+        self.control_status & Self::PMCSR_PME_ENABLE_BIT != 0
+    }
+    pub const fn pme_status(&self) -> bool {
+        self.control_status & Self::PMCSR_PME_STATUS_BIT != 0
+    }
+
+    /// Write the Power Management Control/Status register into configuration space. The PME
+    /// status bit is cleared by writing one, so it is always written as zero.
+    pub unsafe fn write_control_status<W: ConfigWriter>(&self, writer: &W, offset: u8) {
+        writer.write_u32(u16::from(offset + 4), u32::from(self.control_status & !Self::PMCSR_PME_STATUS_BIT));
+    }
+}
@@ -177,6 +177,11 @@ impl Pcie {
         });
         f(Some(&mut *virt_pointer.offset((offset as usize / mem::size_of::<u32>()) as isize)))
     }
+    /// Whether the extended configuration space of the functions on this bus is mapped through
+    /// ECAM, rather than falling back to port I/O.
+    pub fn covers_bus(&self, bus: u8) -> bool {
+        self.mcfgs.at_bus(bus).is_some()
+    }
     pub fn buses<'pcie>(&'pcie self) -> PciIter<'pcie> {
         PciIter::new(self)
     }
@@ -0,0 +1,200 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use syscall::io::Io;
+
+use crate::driver_interface::PcidServerResponseError;
+use crate::irq::MsixTable;
+use crate::pci::cap::Capability as PciCapability;
+use crate::pci::express::PcieCapability;
+use crate::pci::pm::{PowerManagementCapability, PowerState};
+use crate::pci::{ConfigReader, ConfigWriter, PciBar};
+
+/// How long to wait for a function to finish its outstanding requests before resetting it.
+const PENDING_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long a function must be left alone after initiating an FLR.
+const FLR_DELAY: Duration = Duration::from_millis(100);
+/// How long a function may keep answering with Configuration Request Retry Status after a reset.
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+/// The recovery time after a transition to or from D3hot.
+const D3HOT_DELAY: Duration = Duration::from_millis(10);
+/// The recovery time after a transition to or from D2.
+const D2_DELAY: Duration = Duration::from_micros(200);
+
+/// The vendor ID read back while a function is still initializing, and completes configuration
+/// requests with Configuration Request Retry Status.
+const CRS_VENDOR_ID: u16 = 0x0001;
+
+/// The configuration of a function that is lost when it is reset, saved so that it can be
+/// written back afterwards.
+pub struct SavedConfig {
+    header: [u32; 16],
+    capabilities: Vec<(u8, PciCapability)>,
+    /// The physical address and the entries of the MSI-X table, if MSI-X is enabled.
+    msix_table: Option<(usize, Vec<[u32; 4]>)>,
+}
+
+/// Save the header, the registers of the MSI, MSI-X and PCI Express capabilities, and the MSI-X
+/// table when in use.
+pub unsafe fn save<F: ConfigReader>(func: &F, capabilities: &[(u8, PciCapability)], bars: [PciBar; 6]) -> SavedConfig {
+    let mut header = [0u32; 16];
+    for (i, dword) in header.iter_mut().enumerate() {
+        *dword = func.read_u32(i as u16 * 4);
+    }
+
+    let capabilities = capabilities.iter().map(|&(offset, _)| (offset, PciCapability::parse(func, offset))).collect::<Vec<_>>();
+
+    let msix_table = capabilities.iter().find_map(|(_, capability)| capability.as_msix()).filter(|msix| msix.msix_enabled()).and_then(|msix| {
+        let table_base = msix.table_base_pointer(bars);
+        let len = usize::from(msix.table_size());
+
+        let mut table = match MsixTable::map(table_base, len) {
+            Ok(table) => table,
+            Err(err) => {
+                eprintln!("pcid: failed to map MSI-X table at {:#x}: {}", table_base, err);
+                return None;
+            }
+        };
+        let entries = (0..len).map(|k| {
+            let entry = table.entry(k);
+            [entry.addr_lo.read(), entry.addr_hi.read(), entry.msg_data.read(), entry.vec_ctl.read()]
+        }).collect();
+
+        Some((table_base, entries))
+    });
+
+    SavedConfig {
+        header,
+        capabilities,
+        msix_table,
+    }
+}
+
+/// Write back a saved configuration.
+pub unsafe fn restore<F: ConfigReader + ConfigWriter>(func: &F, saved: &SavedConfig) {
+    // Restore the header backwards, so that the command register only enables decoding once the
+    // BARs are programmed again.
+    for i in (1..16).rev() {
+        let value = match i {
+            // The status register is cleared by writing ones.
+            1 => saved.header[i] & 0xFFFF,
+            // Don't start a built-in self test.
+            3 => saved.header[i] & 0x00FF_FFFF,
+            _ => saved.header[i],
+        };
+        func.write_u32(i as u16 * 4, value);
+    }
+
+    for &(offset, ref capability) in saved.capabilities.iter() {
+        match capability {
+            PciCapability::Msi(msi) => {
+                let mut msi = *msi;
+                msi.write_message(func, offset);
+                msi.write_mask_bits(func, offset);
+                msi.write_message_control(func, offset);
+            }
+            PciCapability::MsiX(msix) => {
+                let mut masked = *msix;
+
+                // Keep every vector masked until the table has been written back.
+                if let Some((table_base, ref entries)) = saved.msix_table {
+                    masked.set_function_mask(true);
+                    masked.write_a(func, offset);
+
+                    match MsixTable::map(table_base, entries.len()) {
+                        Ok(mut table) => for (k, &[addr_lo, addr_hi, msg_data, vec_ctl]) in entries.iter().enumerate() {
+                            let entry = table.entry(k);
+                            entry.addr_lo.write(addr_lo);
+                            entry.addr_hi.write(addr_hi);
+                            entry.msg_data.write(msg_data);
+                            entry.vec_ctl.write(vec_ctl);
+                        }
+                        Err(err) => eprintln!("pcid: failed to map MSI-X table at {:#x}: {}", table_base, err),
+                    }
+                }
+                msix.write_a(func, offset);
+            }
+            PciCapability::Pcie(pcie) => pcie.write_controls(func, offset),
+            _ => (),
+        }
+    }
+}
+
+/// Wait until a function that was reset responds to configuration requests again.
+unsafe fn wait_until_ready<F: ConfigReader>(func: &F) -> Result<(), PcidServerResponseError> {
+    let start = Instant::now();
+
+    loop {
+        let id = func.read_u32(0);
+
+        if id != 0xFFFF_FFFF && id as u16 != CRS_VENDOR_ID {
+            return Ok(());
+        }
+        if start.elapsed() >= READY_TIMEOUT {
+            return Err(PcidServerResponseError::DeviceNotReady);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Initiate a PCI Express Function Level Reset, through the capability at `offset`, and wait
+/// for the function to come back.
+pub unsafe fn function_level_reset<F: ConfigReader + ConfigWriter>(func: &F, offset: u8) -> Result<(), PcidServerResponseError> {
+    // The completions of requests still outstanding would be dropped by the reset.
+    let start = Instant::now();
+    while PcieCapability::parse(func, offset).transactions_pending() {
+        if start.elapsed() >= PENDING_TIMEOUT {
+            eprintln!("pcid: transactions still pending, resetting anyway");
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let control = func.read_u32(u16::from(offset) + 0x08) & 0xFFFF;
+    func.write_u32(u16::from(offset) + 0x08, control | u32::from(PcieCapability::DEVCTL_INITIATE_FLR_BIT));
+
+    thread::sleep(FLR_DELAY);
+    wait_until_ready(func)
+}
+
+/// Reset a function by cycling it through D3hot, through the power management capability at
+/// `offset`. This only works for functions that don't set No_Soft_Reset, and fails with
+/// `ResetUnsupported` for the others, which keep their state across D3hot.
+pub unsafe fn power_management_reset<F: ConfigReader + ConfigWriter>(func: &F, offset: u8) -> Result<(), PcidServerResponseError> {
+    if PowerManagementCapability::parse(func, offset).no_soft_reset() {
+        return Err(PcidServerResponseError::ResetUnsupported);
+    }
+    set_power_state(func, offset, PowerState::D3Hot)?;
+    set_power_state(func, offset, PowerState::D0)?;
+    wait_until_ready(func)
+}
+
+/// Move a function into another power state, through the power management capability at
+/// `offset`, and wait for the transition to complete. Going from one low power state to another
+/// goes through D0, since only transitions to and from D0 are defined.
+pub unsafe fn set_power_state<F: ConfigReader + ConfigWriter>(func: &F, offset: u8, state: PowerState) -> Result<(), PcidServerResponseError> {
+    let mut pm = PowerManagementCapability::parse(func, offset);
+
+    if !pm.supports(state) {
+        return Err(PcidServerResponseError::PowerStateUnsupported(state));
+    }
+    let mut from = pm.power_state();
+    if from == state {
+        return Ok(());
+    }
+    if from != PowerState::D0 && state != PowerState::D0 {
+        set_power_state(func, offset, PowerState::D0)?;
+        pm = PowerManagementCapability::parse(func, offset);
+        from = PowerState::D0;
+    }
+
+    pm.set_power_state(state);
+    pm.write_control_status(func, offset);
+
+    if from == PowerState::D3Hot || state == PowerState::D3Hot {
+        thread::sleep(D3HOT_DELAY);
+    } else if from == PowerState::D2 || state == PowerState::D2 {
+        thread::sleep(D2_DELAY);
+    }
+    Ok(())
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, prelude::*};
+use std::sync::Arc;
+use std::{cmp, str};
+
+use syscall::data::Stat;
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EISDIR, ENODEV, ENOENT, ENOTDIR};
+use syscall::flag::{MODE_DIR, MODE_FILE, O_DIRECTORY, O_STAT, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+use crate::{topology, State};
+
+/// The files found in the directory of every function.
+const FUNCTION_ENTRIES: [&str; 5] = ["header", "config", "bars", "capabilities", "driver"];
+
+enum Handle {
+    TopLevel(usize, Vec<u8>),                          // offset, contents
+    Tree(usize, Vec<u8>),                              // offset, contents
+    Function((u8, u8, u8), usize, Vec<u8>),            // address, offset, contents
+    Entry((u8, u8, u8), &'static str, usize, Vec<u8>), // address, entry name, offset, contents
+    Config((u8, u8, u8), usize),                       // address, offset
+}
+
+/// The `pci:` scheme, which exposes every enumerated function as `pci:BB.DD.F/`, and the bus
+/// hierarchy as `pci:tree`.
+///
+/// Reading `pci:BB.DD.F/driver` returns the name of the driver bound to the function, if any.
+/// Writing `unbind`, `bind`, `bind NAME`, `rebind` or `rebind NAME` to it terminates the driver,
+/// spawns the driver with that name in the config, or the best matching one without a name.
+pub struct PciScheme {
+    state: Arc<State>,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize,
+}
+
+pub fn format_address((bus_num, dev_num, func_num): (u8, u8, u8)) -> String {
+    format!("{:>02X}.{:>02X}.{:X}", bus_num, dev_num, func_num)
+}
+pub fn parse_address(string: &str) -> Option<(u8, u8, u8)> {
+    let mut parts = string.split('.');
+
+    let bus_num = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let dev_num = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let func_num = u8::from_str_radix(parts.next()?, 16).ok()?;
+
+    if parts.next().is_some() || dev_num > 0x1F || func_num > 0x07 {
+        return None;
+    }
+    Some((bus_num, dev_num, func_num))
+}
+
+fn seek_offset(offset: usize, len: usize, pos: usize, whence: usize) -> Result<usize> {
+    Ok(match whence {
+        SEEK_SET => cmp::min(len, pos),
+        SEEK_CUR => cmp::max(0, cmp::min(len as isize, offset as isize + pos as isize)) as usize,
+        SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
+        _ => return Err(Error::new(EINVAL)),
+    })
+}
+
+impl PciScheme {
+    pub fn new(state: Arc<State>) -> Self {
+        Self {
+            state,
+            handles: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+    fn lookup(&self, component: &str) -> Result<(u8, u8, u8)> {
+        let address = parse_address(component).ok_or(Error::new(ENOENT))?;
+
+        if self.state.functions.lock().unwrap().contains_key(&address) {
+            Ok(address)
+        } else {
+            Err(Error::new(ENOENT))
+        }
+    }
+    fn entry_contents(&self, address: (u8, u8, u8), entry: &str) -> Result<Vec<u8>> {
+        let functions = self.state.functions.lock().unwrap();
+        let function = functions.get(&address).ok_or(Error::new(ENOENT))?;
+
+        let mut contents = String::new();
+
+        match entry {
+            "header" => writeln!(contents, "{:#?}", function.header).unwrap(),
+            "bars" => for (i, (bar, size)) in function.bars.iter().zip(function.bar_sizes.iter()).enumerate() {
+                if !bar.is_none() {
+                    writeln!(contents, "{}={} size={:>08X}", i, bar, size).unwrap();
+                }
+            }
+            "capabilities" => {
+                for (offset, capability) in function.capabilities.iter() {
+                    writeln!(contents, "{:>02X} {:?}", offset, capability).unwrap();
+                }
+                for (offset, capability) in function.extended_capabilities.iter() {
+                    writeln!(contents, "{:>03X} {:?}", offset, capability).unwrap();
+                }
+            }
+            "driver" => if let Some(binding) = self.state.bindings.lock().unwrap().get(&address) {
+                writeln!(contents, "{}", binding.name).unwrap();
+            }
+            _ => return Err(Error::new(ENOENT)),
+        }
+
+        Ok(contents.into_bytes())
+    }
+    fn read_config(&self, (bus_num, dev_num, func_num): (u8, u8, u8), offset: &mut usize, buf: &mut [u8]) -> usize {
+        let size = usize::from(self.state.config_space_size(bus_num));
+        let access = self.state.preferred_cfg_access();
+
+        let mut bytes_read = 0;
+
+        while bytes_read < buf.len() && *offset < size {
+            let dword = unsafe { access.read(bus_num, dev_num, func_num, (*offset & !3) as u16) };
+            let bytes = u32::to_le_bytes(dword);
+
+            let start = *offset % 4;
+            let count = cmp::min(4 - start, cmp::min(buf.len() - bytes_read, size - *offset));
+
+            buf[bytes_read..bytes_read + count].copy_from_slice(&bytes[start..start + count]);
+            bytes_read += count;
+            *offset += count;
+        }
+
+        bytes_read
+    }
+}
+
+impl SchemeMut for PciScheme {
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        let components = path_str.split('/').filter(|component| !component.is_empty()).collect::<Vec<_>>();
+
+        let handle = match &components[..] {
+            &[] => {
+                if flags & O_DIRECTORY == 0 && flags & O_STAT == 0 {
+                    return Err(Error::new(EISDIR));
+                }
+                let mut contents = String::from("tree\n");
+
+                for &address in self.state.functions.lock().unwrap().keys() {
+                    writeln!(contents, "{}", format_address(address)).unwrap();
+                }
+
+                Handle::TopLevel(0, contents.into_bytes())
+            }
+            &["tree"] => {
+                if flags & O_DIRECTORY != 0 && flags & O_STAT == 0 {
+                    return Err(Error::new(ENOTDIR));
+                }
+                Handle::Tree(0, topology::format_tree(&self.state.functions.lock().unwrap()).into_bytes())
+            }
+            &[function] => {
+                let address = self.lookup(function)?;
+
+                if flags & O_DIRECTORY == 0 && flags & O_STAT == 0 {
+                    return Err(Error::new(EISDIR));
+                }
+                let mut contents = String::new();
+
+                for entry in FUNCTION_ENTRIES.iter() {
+                    writeln!(contents, "{}", entry).unwrap();
+                }
+
+                Handle::Function(address, 0, contents.into_bytes())
+            }
+            &[function, entry] => {
+                let address = self.lookup(function)?;
+                let entry = FUNCTION_ENTRIES.iter().copied().find(|&name| name == entry).ok_or(Error::new(ENOENT))?;
+
+                if flags & O_DIRECTORY != 0 && flags & O_STAT == 0 {
+                    return Err(Error::new(ENOTDIR));
+                }
+
+                if entry == "config" {
+                    Handle::Config(address, 0)
+                } else {
+                    Handle::Entry(address, entry, 0, self.entry_contents(address, entry)?)
+                }
+            }
+            _ => return Err(Error::new(ENOENT)),
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let (address, mut offset) = match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel(ref mut offset, ref contents)
+            | Handle::Tree(ref mut offset, ref contents)
+            | Handle::Function(_, ref mut offset, ref contents)
+            | Handle::Entry(_, _, ref mut offset, ref contents) => {
+                let count = (&contents[cmp::min(*offset, contents.len())..]).read(buf).unwrap();
+                *offset += count;
+                return Ok(count);
+            }
+            &mut Handle::Config(address, offset) => (address, offset),
+        };
+
+        let count = self.read_config(address, &mut offset, buf);
+
+        if let Some(Handle::Config(_, ref mut handle_offset)) = self.handles.get_mut(&id) {
+            *handle_offset = offset;
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let address = match self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            &Handle::Entry(address, "driver", _, _) => address,
+            _ => return Err(Error::new(EBADF)),
+        };
+
+        let command = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim();
+        let mut words = command.split_whitespace();
+
+        let (verb, name) = (words.next(), words.next());
+        if words.next().is_some() {
+            return Err(Error::new(EINVAL));
+        }
+
+        match (verb, name) {
+            (Some("unbind"), None) => crate::unbind_driver(&self.state, address)?,
+            (Some("bind"), name) => crate::bind_driver(&self.state, address, name)?,
+            (Some("rebind"), name) => {
+                match crate::unbind_driver(&self.state, address) {
+                    Ok(()) => (),
+                    Err(err) if err.errno == ENODEV => (),
+                    Err(err) => return Err(err),
+                }
+                crate::bind_driver(&self.state, address, name)?;
+            }
+            _ => return Err(Error::new(EINVAL)),
+        }
+
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let state = &self.state;
+
+        match self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel(ref mut offset, ref contents)
+            | Handle::Tree(ref mut offset, ref contents)
+            | Handle::Function(_, ref mut offset, ref contents)
+            | Handle::Entry(_, _, ref mut offset, ref contents) => {
+                *offset = seek_offset(*offset, contents.len(), pos, whence)?;
+                Ok(*offset)
+            }
+            Handle::Config((bus_num, _, _), ref mut offset) => {
+                let len = usize::from(state.config_space_size(*bus_num));
+                *offset = seek_offset(*offset, len, pos, whence)?;
+                Ok(*offset)
+            }
+        }
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        match self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel(_, ref contents) | Handle::Function(_, _, ref contents) => {
+                stat.st_mode = MODE_DIR | 0o500;
+                stat.st_size = contents.len() as u64;
+            }
+            Handle::Entry(_, "driver", _, ref contents) => {
+                stat.st_mode = MODE_FILE | 0o600;
+                stat.st_size = contents.len() as u64;
+            }
+            Handle::Tree(_, ref contents) | Handle::Entry(_, _, _, ref contents) => {
+                stat.st_mode = MODE_FILE | 0o400;
+                stat.st_size = contents.len() as u64;
+            }
+            &Handle::Config((bus_num, _, _), _) => {
+                stat.st_mode = MODE_FILE | 0o400;
+                stat.st_size = u64::from(self.state.config_space_size(bus_num));
+            }
+        }
+        Ok(0)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut cursor = io::Cursor::new(buf);
+
+        let _ = match self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::TopLevel(_, _) => write!(cursor, "pci:"),
+            Handle::Tree(_, _) => write!(cursor, "pci:tree"),
+            &Handle::Function(address, _, _) => write!(cursor, "pci:{}/", format_address(address)),
+            &Handle::Entry(address, entry, _, _) => write!(cursor, "pci:{}/{}", format_address(address), entry),
+            &Handle::Config(address, _) => write!(cursor, "pci:{}/config", format_address(address)),
+        };
+
+        Ok(cursor.position() as usize)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::mem;
+
+use crate::pci::{PciBus, PciHeader, PciHeaderError, PciHeaderType, PciIter};
+use crate::scheme::format_address;
+use crate::{print_header, probe_function, Function, State};
+
+/// The dword containing the primary, secondary and subordinate bus numbers of a bridge, followed
+/// by its secondary latency timer.
+const BUS_NUMBERS_OFFSET: u16 = 0x18;
+
+/// The state of walking the bus hierarchy.
+struct Walk<'state> {
+    state: &'state State,
+    /// The buses that have been scanned so far.
+    visited: BTreeSet<u8>,
+    /// Bridges whose bus numbers weren't configured by firmware, along with the highest bus
+    /// number that their upstream bridge forwards to. They are only given bus numbers once every
+    /// configured bridge has been walked, so that the numbers don't collide.
+    unconfigured: Vec<((u8, u8, u8), u8)>,
+    /// The next bus number to assign to an unconfigured bridge.
+    next_bus: u16,
+}
+
+impl<'state> Walk<'state> {
+    /// Scan every device on a bus, and recursively the buses behind any bridges found. When
+    /// `assigning`, bridges without bus numbers are given the next free ones right away.
+    fn scan_bus(&mut self, bus_num: u8, parent: Option<(u8, u8, u8)>, limit: u8, assigning: bool) {
+        let state = self.state;
+
+        self.visited.insert(bus_num);
+
+        let bus = PciBus {
+            pci: state.preferred_cfg_access(),
+            num: bus_num,
+        };
+
+        'dev: for dev in bus.devs() {
+            for func in dev.funcs() {
+                let func_num = func.num;
+                let address = (bus_num, dev.num, func_num);
+
+                match PciHeader::from_reader(func) {
+                    Ok(header) => {
+                        print_header(bus_num, dev.num, func_num, &header);
+
+                        let mut function = probe_function(state, bus_num, dev.num, func_num, header);
+                        function.parent = parent;
+                        state.functions.lock().unwrap().insert(address, function);
+
+                        if let PciHeader::PciToPci { secondary_bus_num, subordinate_bus_num, .. } = header {
+                            self.walk_bridge(address, secondary_bus_num, subordinate_bus_num, limit, assigning);
+                        }
+                        if func_num == 0 && !header.header_type().contains(PciHeaderType::MULTIFUNCTION) {
+                            continue 'dev;
+                        }
+                    }
+                    Err(PciHeaderError::NoDevice) => if func_num == 0 {
+                        continue 'dev;
+                    }
+                    Err(PciHeaderError::UnknownHeaderType(id)) => {
+                        println!("pcid: unknown header type: {}", id);
+                    }
+                }
+            }
+        }
+    }
+    fn walk_bridge(&mut self, address: (u8, u8, u8), secondary: u8, subordinate: u8, limit: u8, assigning: bool) {
+        let (bus_num, _, _) = address;
+
+        if secondary > bus_num && subordinate >= secondary && subordinate <= limit && !self.visited.contains(&secondary) {
+            self.set_bus_range(address, secondary, subordinate);
+            self.scan_bus(secondary, Some(address), subordinate, assigning);
+        } else if assigning {
+            self.assign(address, limit);
+        } else {
+            self.unconfigured.push((address, limit));
+        }
+    }
+    /// Give a bridge the next free bus number as its secondary bus, and the bus numbers of the
+    /// bridges found behind it. The bus numbers have to be below the `limit` of its upstream
+    /// bridge: a bridge behind one that firmware configured without spare bus numbers is left
+    /// unconfigured, since widening the upstream bridges could take bus numbers already in use
+    /// behind their siblings.
+    fn assign(&mut self, address: (u8, u8, u8), limit: u8) {
+        let (bus_num, dev_num, func_num) = address;
+
+        if self.next_bus > u16::from(limit) {
+            println!("pcid: no bus numbers left for bridge {}", format_address(address));
+            return;
+        }
+        let secondary = self.next_bus as u8;
+        self.next_bus += 1;
+
+        let access = self.state.preferred_cfg_access();
+        let latency_timer = unsafe { access.read(bus_num, dev_num, func_num, BUS_NUMBERS_OFFSET) } & 0xFF00_0000;
+        let write_bus_numbers = |subordinate: u8| unsafe {
+            access.write(bus_num, dev_num, func_num, BUS_NUMBERS_OFFSET, latency_timer | (u32::from(subordinate) << 16) | (u32::from(secondary) << 8) | u32::from(bus_num));
+        };
+
+        // Forward configuration cycles for every bus number that may end up behind the bridge,
+        // while its children are scanned.
+        write_bus_numbers(limit);
+        self.scan_bus(secondary, Some(address), limit, true);
+
+        let subordinate = (self.next_bus - 1) as u8;
+        write_bus_numbers(subordinate);
+
+        println!("pcid: assigned buses {:>02X}-{:>02X} to bridge {}", secondary, subordinate, format_address(address));
+        self.set_bus_range(address, secondary, subordinate);
+    }
+    fn set_bus_range(&self, address: (u8, u8, u8), secondary: u8, subordinate: u8) {
+        if let Some(function) = self.state.functions.lock().unwrap().get_mut(&address) {
+            function.bus_range = Some((secondary, subordinate));
+        }
+    }
+}
+
+/// Enumerate every function, by walking the hierarchy from the host bridge through the
+/// secondary and subordinate bus numbers of each bridge. Bridges left unconfigured by firmware
+/// are assigned bus numbers above the highest one in use.
+pub fn enumerate(state: &State) {
+    let mut walk = Walk {
+        state,
+        visited: BTreeSet::new(),
+        unconfigured: Vec::new(),
+        next_bus: 0,
+    };
+
+    walk.scan_bus(0, None, 0xFF, false);
+
+    // Buses behind other host bridges can't be reached from bus 0.
+    for bus in PciIter::new(state.preferred_cfg_access()) {
+        if walk.visited.contains(&bus.num) {
+            continue;
+        }
+        if (0..32).any(|dev_num| unsafe { bus.read(dev_num, 0, 0) } != 0xFFFF_FFFF) {
+            walk.scan_bus(bus.num, None, 0xFF, false);
+        }
+    }
+
+    walk.next_bus = walk.visited.iter().next_back().map_or(0, |&max| u16::from(max) + 1);
+
+    for (address, limit) in mem::replace(&mut walk.unconfigured, Vec::new()) {
+        walk.assign(address, limit);
+    }
+}
+
+/// Format the hierarchy as a tree, with every function indented below the bridge it was found
+/// behind.
+pub fn format_tree(functions: &BTreeMap<(u8, u8, u8), Function>) -> String {
+    fn format_children(string: &mut String, functions: &BTreeMap<(u8, u8, u8), Function>, parent: Option<(u8, u8, u8)>, depth: usize) {
+        for (&address, function) in functions.iter().filter(|(_, function)| function.parent == parent) {
+            let header = &function.header;
+
+            write!(string, "{:indent$}{} {:>04X}:{:>04X} {:?}", "", format_address(address), header.vendor_id(), header.device_id(), header.class(), indent = depth * 2).unwrap();
+            if let Some((secondary, subordinate)) = function.bus_range {
+                write!(string, " [{:>02X}-{:>02X}]", secondary, subordinate).unwrap();
+            }
+            string.push('\n');
+
+            format_children(string, functions, Some(address), depth + 1);
+        }
+    }
+
+    let mut string = String::new();
+    format_children(&mut string, functions, None, 0);
+    string
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::pci::mock::{MockCfgAccess, QEMU_Q35_DUMP};
+    use crate::pci::CfgAccess;
+    use crate::State;
+    use super::{enumerate, format_tree};
+
+    #[test]
+    fn test_enumerate_q35() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+        mock.set_bar_size((1, 0, 0), 0, 0x4000);
+        mock.set_bar_size((0, 0x1F, 2), 5, 0x1000);
+
+        let config: Config = toml::from_str(r#"
+            [[drivers]]
+            name = "nvmed"
+            class = 1
+            subclass = 8
+            command = ["nvmed"]
+        "#).unwrap();
+
+        let state = State::new(Arc::new(mock), None, config);
+        enumerate(&state);
+
+        let functions = state.functions.lock().unwrap();
+        assert_eq!(functions.keys().copied().collect::<Vec<_>>(), vec![(0, 0, 0), (0, 1, 0), (0, 0x1F, 0), (0, 0x1F, 2), (1, 0, 0)]);
+
+        let nvme = &functions[&(1, 0, 0)];
+        assert_eq!(nvme.parent, Some((0, 1, 0)));
+        assert_eq!(nvme.bar_sizes[0], 0x4000);
+        assert_eq!(nvme.capabilities.iter().map(|&(offset, _)| offset).collect::<Vec<_>>(), vec![0x40, 0x80]);
+        assert_eq!(nvme.capabilities[0].1.as_msix().map(|msix| msix.table_size()), Some(65));
+        assert!(nvme.capabilities[1].1.as_pcie().map_or(false, |pcie| pcie.function_level_reset_capable()));
+
+        let ahci = &functions[&(0, 0x1F, 2)];
+        assert_eq!(ahci.parent, None);
+        assert_eq!(ahci.bar_sizes[4], 0x40);
+        assert_eq!(ahci.bar_sizes[5], 0x1000);
+        assert!(ahci.capabilities[0].1.as_msi().is_some());
+
+        assert_eq!(functions[&(0, 1, 0)].bus_range, Some((1, 1)));
+
+        assert_eq!(state.config.best_match(&nvme.header).and_then(|driver| driver.name.as_ref()).map(String::as_str), Some("nvmed"));
+        assert!(state.config.best_match(&ahci.header).is_none());
+
+        let tree = format_tree(&functions);
+        assert!(tree.contains("\n00.01.0 1B36:000C Bridge [01-01]\n  01.00.0 1B36:0010 Storage\n"), "{}", tree);
+    }
+
+    #[test]
+    fn test_assign_unconfigured_bridge() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+
+        // A bridge that firmware left without bus numbers.
+        let mut bridge = [0u8; 64];
+        bridge[0..4].copy_from_slice(&[0x36, 0x1B, 0x0C, 0x00]);
+        bridge[0x0A..0x0C].copy_from_slice(&[0x04, 0x06]);
+        bridge[0x0E] = 0x01;
+        bridge[0x1B] = 0x20;
+        mock.insert((0, 2, 0), &bridge);
+
+        let state = State::new(Arc::new(mock), None, Config::default());
+        enumerate(&state);
+
+        assert_eq!(state.functions.lock().unwrap()[&(0, 2, 0)].bus_range, Some((2, 2)));
+        // The secondary latency timer is kept.
+        assert_eq!(unsafe { state.pci.read(0, 2, 0, 0x18) }, 0x2002_0200);
+    }
+
+    #[test]
+    fn test_no_bus_numbers_left() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+
+        // An unconfigured bridge behind the root port at 00.01.0, which only forwards bus 1.
+        let mut bridge = [0u8; 64];
+        bridge[0..4].copy_from_slice(&[0x36, 0x1B, 0x0C, 0x00]);
+        bridge[0x0A..0x0C].copy_from_slice(&[0x04, 0x06]);
+        bridge[0x0E] = 0x01;
+        mock.insert((1, 1, 0), &bridge);
+
+        let state = State::new(Arc::new(mock), None, Config::default());
+        enumerate(&state);
+
+        // The root port isn't widened, so the bridge is left without bus numbers.
+        let functions = state.functions.lock().unwrap();
+        assert_eq!(functions[&(0, 1, 0)].bus_range, Some((1, 1)));
+        assert_eq!(functions[&(1, 1, 0)].bus_range, None);
+        assert_eq!(unsafe { state.pci.read(1, 1, 0, 0x18) }, 0);
+    }
+
+    #[test]
+    fn test_enumerate_multifunction() {
+        let mock = MockCfgAccess::from_lspci(QEMU_Q35_DUMP).unwrap();
+
+        // Bit 7 of the header type marks a multifunction device, whose functions may be sparse.
+        // Functions of a single function device are skipped, even if they would respond.
+        let mut device = [0u8; 64];
+        device[0..4].copy_from_slice(&[0x86, 0x80, 0x00, 0x10]);
+        device[0x0A..0x0C].copy_from_slice(&[0x80, 0x0C]);
+        for &(address, header_type) in [((0, 3, 0), 0x80), ((0, 3, 1), 0x80), ((0, 3, 7), 0x80), ((0, 4, 0), 0x00), ((0, 4, 1), 0x00)].iter() {
+            device[0x0E] = header_type;
+            mock.insert(address, &device);
+        }
+
+        let state = State::new(Arc::new(mock), None, Config::default());
+        enumerate(&state);
+
+        let functions = state.functions.lock().unwrap();
+        let found = functions.keys().copied().filter(|&(_, dev_num, _)| dev_num == 3 || dev_num == 4).collect::<Vec<_>>();
+        assert_eq!(found, vec![(0, 3, 0), (0, 3, 1), (0, 3, 7), (0, 4, 0)]);
+    }
+}
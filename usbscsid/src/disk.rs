@@ -0,0 +1,49 @@
+use block_io_wrapper::syscall::error::{Error, Result};
+use block_io_wrapper::syscall::error::{EINVAL, EIO};
+use block_io_wrapper::Disk;
+
+use crate::protocol::Protocol;
+use crate::scsi::Scsi;
+
+/// The logical unit behind a SCSI transport, served as a disk.
+pub struct ScsiDisk<'a> {
+    scsi: &'a mut Scsi,
+    protocol: &'a mut dyn Protocol,
+}
+
+impl<'a> ScsiDisk<'a> {
+    pub fn new(scsi: &'a mut Scsi, protocol: &'a mut dyn Protocol) -> Self {
+        Self { scsi, protocol }
+    }
+}
+
+impl<'a> Disk for ScsiDisk<'a> {
+    fn size(&mut self) -> u64 {
+        self.scsi.get_disk_size()
+    }
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<Option<usize>> {
+        if buffer.len() as u64 % u64::from(self.scsi.block_size) != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let bytes_read = self
+            .scsi
+            .read(self.protocol, block, buffer)
+            .map_err(|err| dbg!(err))
+            .or(Err(Error::new(EIO)))?;
+        Ok(Some(bytes_read as usize))
+    }
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<Option<usize>> {
+        if buffer.len() as u64 % u64::from(self.scsi.block_size) != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let bytes_written = self
+            .scsi
+            .write(self.protocol, block, buffer)
+            .map_err(|err| dbg!(err))
+            .or(Err(Error::new(EIO)))?;
+        Ok(Some(bytes_written as usize))
+    }
+    fn block_length(&mut self) -> Result<u32> {
+        Ok(self.scsi.block_size)
+    }
+}
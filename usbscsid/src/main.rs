@@ -1,17 +1,20 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::os::unix::io::{FromRawFd, RawFd};
 
-use syscall::{CloneFlags, Packet, SchemeMut};
+use block_io_wrapper::syscall::{Packet, SchemeBlockMut};
+use block_io_wrapper::DiskScheme;
+use syscall::CloneFlags;
 use xhcid_interface::{ConfigureEndpointsReq, DeviceReqData, XhciClientHandle};
 
 pub mod protocol;
 pub mod scsi;
 
-mod scheme;
+mod disk;
 
-use scheme::ScsiScheme;
+use disk::ScsiDisk;
 use scsi::Scsi;
 
 fn main() {
@@ -42,7 +45,7 @@ fn main() {
         return;
     }
 
-    let disk_scheme_name = format!(":disk/{}-{}_scsi", scheme, port);
+    let disk_scheme_name = format!("disk/{}-{}_scsi", scheme, port);
 
     // TODO: Use eventfds.
     let handle = XhciClientHandle::new(scheme, port);
@@ -85,8 +88,11 @@ fn main() {
 
     // TODO: Let all of the USB drivers syscall clone(2), and xhcid won't have to keep track of all
     // the drivers.
-    let socket_fd = syscall::open(disk_scheme_name, syscall::O_RDWR | syscall::O_CREAT)
-        .expect("usbscsid: failed to create disk scheme");
+    let socket_fd = syscall::open(
+        format!(":{}", disk_scheme_name),
+        syscall::O_RDWR | syscall::O_CREAT,
+    )
+    .expect("usbscsid: failed to create disk scheme");
     let mut socket_file = unsafe { File::from_raw_fd(socket_fd as RawFd) };
 
     //syscall::setrens(0, 0).expect("scsid: failed to enter null namespace");
@@ -96,7 +102,10 @@ fn main() {
     scsi.read(&mut *protocol, 0, &mut buffer).unwrap();
     println!("DISK CONTENT: {}", base64::encode(&buffer[..]));
 
-    let mut scsi_scheme = ScsiScheme::new(&mut scsi, &mut *protocol);
+    // TODO: Only one disk, right?
+    let mut disks = BTreeMap::new();
+    disks.insert(0, ScsiDisk::new(&mut scsi, &mut *protocol));
+    let mut disk_scheme = DiskScheme::new(disk_scheme_name, disks);
 
     // TODO: Use nonblocking and put all pending calls in a todo VecDeque. Use an eventfd as well.
     'scheme_loop: loop {
@@ -106,7 +115,10 @@ fn main() {
             Ok(_) => (),
             Err(err) => panic!("scsid: failed to read disk scheme: {}", err),
         }
-        scsi_scheme.handle(&mut packet);
+        // Every request completes synchronously, so there is always a response.
+        packet.a = disk_scheme
+            .handle(&packet)
+            .expect("usbscsid: request didn't complete");
         socket_file
             .write(&packet)
             .expect("scsid: failed to write packet");
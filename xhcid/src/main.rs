@@ -1,8 +1,7 @@
 #[macro_use]
 extern crate bitflags;
 
-use std::convert::TryInto;
-use std::fs::{self, File};
+use std::fs::File;
 use std::future::Future;
 use std::io::{self, Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
@@ -11,8 +10,8 @@ use std::ptr::NonNull;
 use std::sync::{Arc, Mutex};
 use std::env;
 
-use pcid_interface::{PcidServerHandle, PciFeature, PciFeatureInfo};
-use pcid_interface::msi::{MsiCapability, MsixCapability, MsixTableEntry};
+use pcid_interface::{InterruptKind, PcidServerHandle, PciFeature, PciFeatureInfo};
+use pcid_interface::msi::MsixTableEntry;
 
 use event::{Event, EventQueue};
 use log::info;
@@ -20,7 +19,6 @@ use syscall::data::Packet;
 use syscall::error::EWOULDBLOCK;
 use syscall::flag::{CloneFlags, PHYSMAP_NO_CACHE, PHYSMAP_WRITE};
 use syscall::scheme::Scheme;
-use syscall::io::Io;
 
 use crate::xhci::{InterruptMethod, Xhci};
 
@@ -32,49 +30,6 @@ pub mod driver_interface;
 mod usb;
 mod xhci;
 
-/// Read the local APIC id of the bootstrap processor.
-fn read_bsp_apic_id() -> io::Result<u32> {
-    let mut buffer = [0u8; 8];
-
-    let mut file = File::open("irq:bsp")?;
-    let bytes_read = file.read(&mut buffer)?;
-
-    Ok(if bytes_read == 8 {
-        u64::from_le_bytes(buffer) as u32
-    } else if bytes_read == 4 {
-        u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
-    } else {
-        panic!("`irq:` scheme responded with {} bytes, expected {}", bytes_read, std::mem::size_of::<usize>());
-    })
-}
-/// Allocate an interrupt vector, located at the BSP's IDT.
-fn allocate_interrupt_vector() -> io::Result<Option<(u8, File)>> {
-    let available_irqs = fs::read_dir("irq:")?;
-
-    for entry in available_irqs {
-        let entry = entry?;
-        let path = entry.path();
-
-        let file_name = match path.file_name() {
-            Some(f) => f,
-            None => continue,
-        };
-
-        let path_str = match file_name.to_str() {
-            Some(s) => s,
-            None => continue,
-        };
-
-        if let Ok(irq_number) = path_str.parse::<u8>() {
-            // if found, reserve the irq
-            let irq_handle = File::create(format!("irq:{}", irq_number))?;
-            let interrupt_vector = irq_number + 32;
-            return Ok(Some((interrupt_vector, irq_handle)));
-        }
-    }
-    Ok(None)
-}
-
 async fn handle_packet(hci: Arc<Xhci>, packet: Packet) -> Packet {
     todo!()
 }
@@ -108,9 +63,9 @@ fn main() {
     let bar = pci_config.func.bars[0];
     let irq = pci_config.func.legacy_interrupt_line;
 
-    let bar_ptr = match bar {
-        pcid_interface::PciBar::Memory(ptr) => ptr,
-        other => panic!("Expected memory bar, found {}", other),
+    let bar_ptr = match bar.memory_address() {
+        Some(ptr) => ptr as usize,
+        None => panic!("Expected memory bar, found {}", bar),
     };
 
     let address = unsafe {
@@ -121,36 +76,15 @@ fn main() {
     let all_pci_features = pcid_handle.fetch_all_features().expect("xhcid: failed to fetch pci features");
     info!("XHCI PCI FEATURES: {:?}", all_pci_features);
 
-    let (has_msi, mut msi_enabled) = all_pci_features.iter().map(|(feature, status)| (feature.is_msi(), status.is_enabled())).find(|&(f, _)| f).unwrap_or((false, false));
-    let (has_msix, mut msix_enabled) = all_pci_features.iter().map(|(feature, status)| (feature.is_msix(), status.is_enabled())).find(|&(f, _)| f).unwrap_or((false, false));
+    let has_msi = all_pci_features.iter().any(|(feature, _)| feature.is_msi());
+    let has_msix = all_pci_features.iter().any(|(feature, _)| feature.is_msix());
 
-    dbg!(has_msi, msi_enabled);
-    dbg!(has_msix, msix_enabled);
+    dbg!(has_msi, has_msix);
 
-    if has_msi && !msi_enabled && !has_msix {
-        pcid_handle.enable_feature(PciFeature::Msi).expect("xhcid: failed to enable MSI");
-        info!("Enabled MSI");
-        msi_enabled = true;
-    }
-    if has_msix && !msix_enabled {
-        pcid_handle.enable_feature(PciFeature::MsiX).expect("xhcid: failed to enable MSI-X");
-        info!("Enabled MSI-X");
-        msix_enabled = true;
-    }
-
-    let (mut irq_file, interrupt_method) = if msi_enabled && !msix_enabled {
-        let mut capability = match pcid_handle.feature_info(PciFeature::MsiX).expect("xhcid: failed to retrieve the MSI capability structure from pcid") {
-            PciFeatureInfo::Msi(s) => s,
-            PciFeatureInfo::MsiX(_) => panic!(),
-        };
-        // use one vector
-        capability.set_multi_message_enabled(0);
-
-        todo!("msi (msix is implemented though)")
-    } else if msix_enabled {
+    let (mut irq_file, interrupt_method) = if has_msix {
         let capability = match pcid_handle.feature_info(PciFeature::MsiX).expect("xhcid: failed to retrieve the MSI-X capability structure from pcid") {
-            PciFeatureInfo::Msi(_) => panic!(),
             PciFeatureInfo::MsiX(s) => s,
+            _ => panic!(),
         };
         let table_size = capability.table_size();
         let table_base = capability.table_base_pointer(pci_config.func.bars);
@@ -160,48 +94,32 @@ fn main() {
         let pba_base = capability.pba_base_pointer(pci_config.func.bars);
         dbg!(table_size, table_base, table_min_length, pba_base);
 
-        if !(bar_ptr..bar_ptr + 65536).contains(&(table_base as u32 + table_min_length as u32)) {
+        if !(bar_ptr..bar_ptr + 65536).contains(&(table_base + table_min_length as usize)) {
             todo!()
         }
-        if !(bar_ptr..bar_ptr + 65536).contains(&(pba_base as u32 + pba_min_length as u32)) {
+        if !(bar_ptr..bar_ptr + 65536).contains(&(pba_base + pba_min_length as usize)) {
             todo!()
         }
 
         let virt_table_base = ((table_base - bar_ptr as usize) + address) as *mut MsixTableEntry;
         let virt_pba_base = ((pba_base - bar_ptr as usize) + address) as *mut u64;
 
-        let mut info = xhci::MsixInfo {
+        let info = xhci::MsixInfo {
             virt_table_base: NonNull::new(virt_table_base).unwrap(),
             virt_pba_base: NonNull::new(virt_pba_base).unwrap(),
             capability,
         };
 
-        // Allocate one msi vector.
-
-        {
-            use pcid_interface::msi::x86_64::{DeliveryMode, self as x86_64_msix};
-
-            // primary interrupter
-            let k = 0;
-
-            assert_eq!(std::mem::size_of::<MsixTableEntry>(), 16);
-            let table_entry_pointer = info.table_entry_pointer(k);
-
-            let destination_id = read_bsp_apic_id().expect("xhcid: failed to read BSP apic id");
-            let rh = false;
-            let dm = false;
-            let addr = x86_64_msix::message_address(destination_id.try_into().expect("xhcid: BSP apic id couldn't fit u8"), rh, dm, 0b00);
-
-            let (vector, interrupt_handle) = allocate_interrupt_vector().expect("xhcid: failed to allocate interrupt vector").expect("xhcid: no interrupt vectors left");
-            let msg_data = x86_64_msix::message_data_edge_triggered(DeliveryMode::Fixed, vector);
+        // Allocate one vector, for the primary interrupter.
+        let interrupt_handle = pcid_handle.allocate_interrupts(1, InterruptKind::MsiX).expect("xhcid: failed to allocate MSI-X vector").pop();
+        info!("Enabled MSI-X");
 
-            table_entry_pointer.addr_lo.write(addr);
-            table_entry_pointer.addr_hi.write(0);
-            table_entry_pointer.msg_data.write(msg_data);
-            table_entry_pointer.vec_ctl.writef(MsixTableEntry::VEC_CTL_MASK_BIT, false);
+        (interrupt_handle, InterruptMethod::MsiX(Mutex::new(info)))
+    } else if has_msi {
+        let interrupt_handle = pcid_handle.allocate_interrupts(1, InterruptKind::Msi).expect("xhcid: failed to allocate MSI vector").pop();
+        info!("Enabled MSI");
 
-            (Some(interrupt_handle), InterruptMethod::MsiX(Mutex::new(info)))
-        }
+        (interrupt_handle, InterruptMethod::Msi)
     } else if pci_config.func.legacy_interrupt_pin.is_some() {
         // legacy INTx# interrupt pins.
         (Some(File::open(format!("irq:{}", irq)).expect("xhcid: failed to open legacy IRQ file")), InterruptMethod::Intx)
@@ -226,7 +144,7 @@ fn main() {
         File::from_raw_fd(socket_fd as RawFd)
     }));
 
-    let hci = Arc::new(Xhci::new(name, address, interrupt_method, pcid_handle).expect("xhcid: failed to allocate device"));
+    let hci = Arc::new(Xhci::new(name, address, interrupt_method, pcid_handle.into_async()).expect("xhcid: failed to allocate device"));
     xhci::start_irq_reactor(&hci, irq_file);
     futures::executor::block_on(hci.probe()).expect("xhcid: failed to probe");
 
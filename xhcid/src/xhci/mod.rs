@@ -20,7 +20,7 @@ use syscall::io::{Dma, Io};
 use crate::usb;
 
 use pcid_interface::msi::{MsixTableEntry, MsixCapability};
-use pcid_interface::{PcidServerHandle, PciFeature};
+use pcid_interface::{AsyncPcidServerHandle, PciFeature};
 
 mod capability;
 mod context;
@@ -190,7 +190,7 @@ pub struct Xhci {
     scheme_name: String,
 
     interrupt_method: InterruptMethod,
-    pcid_handle: Mutex<PcidServerHandle>,
+    pcid_handle: AsyncPcidServerHandle,
 
     irq_reactor: Mutex<Option<thread::JoinHandle<()>>>,
 
@@ -232,7 +232,7 @@ impl EndpointState {
 }
 
 impl Xhci {
-    pub fn new(scheme_name: String, address: usize, interrupt_method: InterruptMethod, pcid_handle: PcidServerHandle) -> Result<Xhci> {
+    pub fn new(scheme_name: String, address: usize, interrupt_method: InterruptMethod, pcid_handle: AsyncPcidServerHandle) -> Result<Xhci> {
         let cap = unsafe { &mut *(address as *mut CapabilityRegs) };
         debug!("CAP REGS BASE {:X}", address);
 
@@ -323,7 +323,7 @@ impl Xhci {
             scheme_name,
 
             interrupt_method,
-            pcid_handle: Mutex::new(pcid_handle),
+            pcid_handle,
 
             irq_reactor: Mutex::new(None),
             irq_reactor_sender,